@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::diff;
+use crate::error::Result;
+use crate::msg::{Action, Edge, EdgeId, GraphId, MutateKind, NodeId, Properties, Reply, Subgraph};
+use crate::store::Datastore;
+
+pub type ReplicaId = Uuid;
+
+// unique tag of an operation, ordered as a lamport timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub counter: u64,
+    pub replica: ReplicaId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CrdtOp {
+    AddNode {
+        node_id: NodeId,
+        dot: Dot,
+    },
+    // removes only the adds the replica had seen, so a concurrent add wins
+    RemoveNode {
+        node_id: NodeId,
+        observed: Vec<Dot>,
+    },
+    AddEdge {
+        edge: Edge,
+        dot: Dot,
+    },
+    RemoveEdge {
+        edge_id: EdgeId,
+        observed: Vec<Dot>,
+    },
+    // last writer wins per node or edge and key, None removes the property
+    SetProperty {
+        id: Uuid,
+        key: String,
+        value: Option<JsonValue>,
+        dot: Dot,
+    },
+}
+
+// add-wins sets of nodes and edges with a last-writer-wins register per property,
+// every operation commutes so replicas converge whatever the delivery order
+#[derive(Debug, Clone)]
+pub struct GraphCrdt {
+    replica: ReplicaId,
+    clock: u64,
+    nodes: HashMap<NodeId, HashSet<Dot>>,
+    edges: HashMap<EdgeId, (Edge, HashSet<Dot>)>,
+    removed: HashSet<Dot>,
+    properties: HashMap<(Uuid, String), (Dot, Option<JsonValue>)>,
+}
+
+impl GraphCrdt {
+    pub fn new(replica: ReplicaId) -> Self {
+        GraphCrdt {
+            replica,
+            clock: 0,
+            nodes: HashMap::new(),
+            edges: HashMap::new(),
+            removed: HashSet::new(),
+            properties: HashMap::new(),
+        }
+    }
+
+    fn next_dot(&mut self) -> Dot {
+        self.clock += 1;
+        Dot {
+            counter: self.clock,
+            replica: self.replica,
+        }
+    }
+
+    // the local operations below are applied right away and returned for broadcasting
+
+    pub fn add_node(&mut self, node_id: NodeId) -> CrdtOp {
+        let dot = self.next_dot();
+        self.local(CrdtOp::AddNode { node_id, dot })
+    }
+
+    pub fn remove_node(&mut self, node_id: NodeId) -> CrdtOp {
+        let observed = live_dots(self.nodes.get(&node_id), &self.removed);
+        self.local(CrdtOp::RemoveNode { node_id, observed })
+    }
+
+    pub fn add_edge(&mut self, edge: Edge) -> CrdtOp {
+        let dot = self.next_dot();
+        self.local(CrdtOp::AddEdge { edge, dot })
+    }
+
+    pub fn remove_edge(&mut self, edge_id: EdgeId) -> CrdtOp {
+        let observed = live_dots(
+            self.edges.get(&edge_id).map(|(_, dots)| dots),
+            &self.removed,
+        );
+        self.local(CrdtOp::RemoveEdge { edge_id, observed })
+    }
+
+    pub fn set_property(&mut self, id: Uuid, key: String, value: Option<JsonValue>) -> CrdtOp {
+        let dot = self.next_dot();
+        self.local(CrdtOp::SetProperty {
+            id,
+            key,
+            value,
+            dot,
+        })
+    }
+
+    fn local(&mut self, op: CrdtOp) -> CrdtOp {
+        self.apply(op.clone());
+        op
+    }
+
+    pub fn apply(&mut self, op: CrdtOp) {
+        match op {
+            CrdtOp::AddNode { node_id, dot } => {
+                self.observe(dot);
+                if !self.removed.contains(&dot) {
+                    self.nodes.entry(node_id).or_default().insert(dot);
+                }
+            }
+            CrdtOp::RemoveNode { node_id, observed } => {
+                if let Some(dots) = self.nodes.get_mut(&node_id) {
+                    for dot in observed.iter() {
+                        dots.remove(dot);
+                    }
+                }
+                self.removed.extend(observed);
+            }
+            CrdtOp::AddEdge { edge, dot } => {
+                self.observe(dot);
+                if !self.removed.contains(&dot) {
+                    self.edges
+                        .entry(edge.id)
+                        .or_insert_with(|| (edge, HashSet::new()))
+                        .1
+                        .insert(dot);
+                }
+            }
+            CrdtOp::RemoveEdge { edge_id, observed } => {
+                if let Some((_, dots)) = self.edges.get_mut(&edge_id) {
+                    for dot in observed.iter() {
+                        dots.remove(dot);
+                    }
+                }
+                self.removed.extend(observed);
+            }
+            CrdtOp::SetProperty {
+                id,
+                key,
+                value,
+                dot,
+            } => {
+                self.observe(dot);
+                let register = self.properties.entry((id, key)).or_insert((dot, None));
+                if dot >= register.0 {
+                    *register = (dot, value);
+                }
+            }
+        }
+    }
+
+    fn observe(&mut self, dot: Dot) {
+        self.clock = self.clock.max(dot.counter);
+    }
+
+    pub fn contains_node(&self, node_id: NodeId) -> bool {
+        self.nodes
+            .get(&node_id)
+            .is_some_and(|dots| !dots.is_empty())
+    }
+
+    // edges are only visible while both of their nodes are
+    pub fn to_subgraph(&self) -> Subgraph {
+        let mut nodes: Vec<(NodeId, Properties)> = self
+            .nodes
+            .iter()
+            .filter(|(_, dots)| !dots.is_empty())
+            .map(|(node_id, _)| (*node_id, self.properties_of(*node_id)))
+            .collect();
+        nodes.sort_by_key(|(node_id, _)| *node_id);
+
+        let mut edges: Vec<(Edge, Properties)> = self
+            .edges
+            .values()
+            .filter(|(edge, dots)| {
+                !dots.is_empty() && self.contains_node(edge.from) && self.contains_node(edge.to)
+            })
+            .map(|(edge, _)| (*edge, self.properties_of(edge.id)))
+            .collect();
+        edges.sort_by_key(|(edge, _)| edge.id);
+
+        Subgraph { nodes, edges }
+    }
+
+    fn properties_of(&self, id: Uuid) -> Properties {
+        self.properties
+            .iter()
+            .filter(|((element_id, _), _)| *element_id == id)
+            .filter_map(|((_, key), (_, value))| Some((key.clone(), value.clone()?)))
+            .collect()
+    }
+}
+
+fn live_dots(dots: Option<&HashSet<Dot>>, removed: &HashSet<Dot>) -> Vec<Dot> {
+    dots.into_iter()
+        .flatten()
+        .filter(|dot| !removed.contains(dot))
+        .copied()
+        .collect()
+}
+
+// brings the graph in the datastore to the state of the crdt as one undoable batch
+pub async fn apply_to_datastore<D: Datastore + ?Sized>(
+    crdt: &GraphCrdt,
+    store: &mut D,
+    graph_id: GraphId,
+) -> Result<Reply> {
+    let (_, current) = store.read_graph_snapshot(graph_id).await?;
+    let diff = diff::diff(&current, &crdt.to_subgraph());
+
+    if diff.is_empty() {
+        return Ok(Reply::Empty);
+    }
+
+    store
+        .execute(Action::Mutate(graph_id, MutateKind::Batch(diff.mutations)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::content_hash;
+    use serde_json::json;
+
+    // xorshift, enough to shuffle deliveries reproducibly
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn uuid(&mut self) -> Uuid {
+            Uuid::from_u128((self.next() as u128) << 64 | self.next() as u128)
+        }
+    }
+
+    fn random_op(replica: &mut GraphCrdt, node_ids: &[NodeId], rng: &mut Rng) -> CrdtOp {
+        let node_id = node_ids[rng.below(node_ids.len())];
+        let other_id = node_ids[rng.below(node_ids.len())];
+        // sorted, a map's iteration order would change from run to run
+        let mut edge_ids: Vec<EdgeId> = replica.edges.keys().copied().collect();
+        edge_ids.sort();
+        let existing_edge = edge_ids.get(rng.below(edge_ids.len().max(1))).copied();
+
+        match rng.below(6) {
+            0 => replica.add_node(node_id),
+            1 => replica.remove_node(node_id),
+            2 => replica.add_edge(Edge {
+                id: rng.uuid(),
+                from: node_id,
+                to: other_id,
+            }),
+            3 => match existing_edge {
+                Some(edge_id) => replica.remove_edge(edge_id),
+                None => replica.add_node(node_id),
+            },
+            4 => match existing_edge {
+                Some(edge_id) => {
+                    replica.set_property(edge_id, "weight".into(), Some(json!(rng.below(10))))
+                }
+                None => replica.set_property(node_id, "name".into(), None),
+            },
+            _ => {
+                let key = ["name", "color"][rng.below(2)];
+                replica.set_property(node_id, key.into(), Some(json!(rng.below(10))))
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_concurrent_ops_converge() {
+        for seed in 1..=200u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let node_ids: Vec<NodeId> = (0..4).map(|_| rng.uuid()).collect();
+            let mut replicas: Vec<GraphCrdt> = (0..3).map(|_| GraphCrdt::new(rng.uuid())).collect();
+
+            // each replica works offline for a while, then everything is exchanged
+            let mut ops = Vec::new();
+            for _ in 0..3 {
+                for (index, replica) in replicas.iter_mut().enumerate() {
+                    for _ in 0..rng.below(5) {
+                        ops.push((index, random_op(replica, &node_ids, &mut rng)));
+                    }
+                }
+
+                for (index, replica) in replicas.iter_mut().enumerate() {
+                    let mut incoming: Vec<&CrdtOp> = ops
+                        .iter()
+                        .filter(|(origin, _)| *origin != index)
+                        .map(|(_, op)| op)
+                        .collect();
+                    for i in (1..incoming.len()).rev() {
+                        incoming.swap(i, rng.below(i + 1));
+                    }
+                    for op in incoming {
+                        replica.apply(op.clone());
+                    }
+                }
+                ops.clear();
+            }
+
+            let hashes: Vec<String> = replicas
+                .iter()
+                .map(|replica| content_hash(&replica.to_subgraph()))
+                .collect();
+            assert!(
+                hashes.iter().all(|hash| *hash == hashes[0]),
+                "replicas diverged with seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_wins_over_concurrent_remove() {
+        let node_id = Uuid::from_u128(1);
+        let mut a = GraphCrdt::new(Uuid::from_u128(2));
+        let mut b = GraphCrdt::new(Uuid::from_u128(3));
+
+        let add = a.add_node(node_id);
+        b.apply(add);
+
+        let remove = a.remove_node(node_id);
+        let concurrent_add = b.add_node(node_id);
+        a.apply(concurrent_add);
+        b.apply(remove);
+
+        assert!(a.contains_node(node_id));
+        assert!(b.contains_node(node_id));
+    }
+}
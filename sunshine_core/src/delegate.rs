@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+
+use crate::error::Result;
+use crate::hash::HashCache;
+use crate::history::History;
+use crate::msg::{
+    Action, ActionId, CreateEdge, Edge, EdgeId, Graph, GraphId, Node, NodeId, Properties,
+    RecreateNode, Reply,
+};
+use crate::store::Datastore;
+use crate::timeline::Timeline;
+
+// a store wrapping another one, every Datastore method goes to the inner store
+// except execution, which the wrapper can take over in `forward`
+#[async_trait]
+pub trait Delegate: Send + Sync {
+    type Inner: Datastore;
+
+    fn inner(&self) -> &Self::Inner;
+
+    fn inner_mut(&mut self) -> &mut Self::Inner;
+
+    async fn forward(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        self.inner_mut().execute_with_id(action_id, msg).await
+    }
+}
+
+#[async_trait]
+impl<T: Delegate> Datastore for T {
+    fn undo_buf(&mut self) -> &mut Vec<Action> {
+        self.inner_mut().undo_buf()
+    }
+
+    fn redo_buf(&mut self) -> &mut Vec<Action> {
+        self.inner_mut().redo_buf()
+    }
+
+    fn hash_cache(&self) -> Option<&HashCache> {
+        self.inner().hash_cache()
+    }
+
+    fn undo_capacity(&self) -> Option<usize> {
+        self.inner().undo_capacity()
+    }
+
+    fn history(&self) -> &History {
+        self.inner().history()
+    }
+
+    fn history_buf(&mut self) -> &mut History {
+        self.inner_mut().history_buf()
+    }
+
+    fn timeline(&self) -> &Timeline {
+        self.inner().timeline()
+    }
+
+    fn timeline_buf(&mut self) -> &mut Timeline {
+        self.inner_mut().timeline_buf()
+    }
+
+    async fn execute_with_id(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        self.forward(action_id, msg).await
+    }
+
+    async fn save_history(&mut self) -> Result<()> {
+        self.inner_mut().save_history().await
+    }
+
+    async fn update_state_id(&self, graph_id: GraphId) -> Result<u64> {
+        self.inner().update_state_id(graph_id).await
+    }
+
+    async fn set_state_id(&self, graph_id: GraphId, state_id: u64) -> Result<()> {
+        self.inner().set_state_id(graph_id, state_id).await
+    }
+
+    async fn read_state_id(&self, graph_id: GraphId) -> Result<u64> {
+        self.inner().read_state_id(graph_id).await
+    }
+
+    async fn read_graph_hash(&self, graph_id: GraphId) -> Result<String> {
+        self.inner().read_graph_hash(graph_id).await
+    }
+
+    async fn create_graph_with_id(
+        &self,
+        graph_id: GraphId,
+        properties: Properties,
+    ) -> Result<(Action, GraphId)> {
+        self.inner()
+            .create_graph_with_id(graph_id, properties)
+            .await
+    }
+
+    async fn list_graphs(&self) -> Result<Vec<(NodeId, Properties)>> {
+        self.inner().list_graphs().await
+    }
+
+    async fn read_graph(&self, graph_id: GraphId) -> Result<Graph> {
+        self.inner().read_graph(graph_id).await
+    }
+
+    async fn create_node_with_id(
+        &self,
+        node_id: NodeId,
+        args: (GraphId, Properties),
+    ) -> Result<Action> {
+        self.inner().create_node_with_id(node_id, args).await
+    }
+
+    async fn read_node(&self, node_id: NodeId) -> Result<Node> {
+        self.inner().read_node(node_id).await
+    }
+
+    async fn update_node(&self, args: (NodeId, Properties), graph_id: GraphId) -> Result<Action> {
+        self.inner().update_node(args, graph_id).await
+    }
+
+    async fn recreate_node(
+        &self,
+        recreate_node: RecreateNode,
+        graph_id: GraphId,
+    ) -> Result<Action> {
+        self.inner().recreate_node(recreate_node, graph_id).await
+    }
+
+    async fn recreate_edge(&self, edge: Edge, properties: Properties) -> Result<()> {
+        self.inner().recreate_edge(edge, properties).await
+    }
+
+    async fn delete_node(&self, node_id: NodeId, graph_id: GraphId) -> Result<Action> {
+        self.inner().delete_node(node_id, graph_id).await
+    }
+
+    async fn create_edge(&self, msg: CreateEdge, graph_id: GraphId) -> Result<(Action, EdgeId)> {
+        self.inner().create_edge(msg, graph_id).await
+    }
+
+    async fn read_edge_properties(&self, msg: Edge) -> Result<Properties> {
+        self.inner().read_edge_properties(msg).await
+    }
+
+    async fn update_edge(&self, args: (Edge, Properties), graph_id: GraphId) -> Result<Action> {
+        self.inner().update_edge(args, graph_id).await
+    }
+
+    async fn delete_edge(&self, edge: Edge, graph_id: GraphId) -> Result<Action> {
+        self.inner().delete_edge(edge, graph_id).await
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::msg::{Edge, EdgeId, MutateKind, NodeId, Properties, Subgraph};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Diff {
+    // applied in order, these turn the first subgraph into the second
+    pub mutations: Vec<MutateKind>,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Change {
+    NodeAdded(NodeId),
+    NodeChanged(NodeId, Vec<String>), // changed property keys
+    NodeRemoved(NodeId),
+    EdgeAdded(Edge),
+    EdgeChanged(Edge, Vec<String>),
+    EdgeRemoved(Edge),
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+}
+
+// nodes are created first and deleted last, so edges always have both ends,
+// edges of deleted nodes are left to the node deletion
+pub fn diff(from: &Subgraph, to: &Subgraph) -> Diff {
+    let from_nodes: HashMap<NodeId, &Properties> =
+        from.nodes.iter().map(|(id, props)| (*id, props)).collect();
+    let to_nodes: HashMap<NodeId, &Properties> =
+        to.nodes.iter().map(|(id, props)| (*id, props)).collect();
+    let from_edges: HashMap<EdgeId, &(Edge, Properties)> =
+        from.edges.iter().map(|edge| (edge.0.id, edge)).collect();
+    let to_edges: HashMap<EdgeId, &(Edge, Properties)> =
+        to.edges.iter().map(|edge| (edge.0.id, edge)).collect();
+
+    let mut diff = Diff::default();
+
+    for (node_id, props) in to.nodes.iter() {
+        match from_nodes.get(node_id) {
+            None => {
+                diff.mutations
+                    .push(MutateKind::CreateNodeWithId((*node_id, props.clone())));
+                diff.changes.push(Change::NodeAdded(*node_id));
+            }
+            Some(from_props) if *from_props != props => {
+                diff.mutations
+                    .push(MutateKind::UpdateNode((*node_id, props.clone())));
+                diff.changes.push(Change::NodeChanged(
+                    *node_id,
+                    changed_keys(from_props, props),
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (edge, _) in from.edges.iter() {
+        let removed = match to_edges.get(&edge.id) {
+            Some((to_edge, _)) => !same_ends(edge, to_edge),
+            None => true,
+        };
+        let node_removed = !to_nodes.contains_key(&edge.from) || !to_nodes.contains_key(&edge.to);
+
+        if removed && !node_removed {
+            diff.mutations.push(MutateKind::DeleteEdge(*edge));
+        }
+        if removed {
+            diff.changes.push(Change::EdgeRemoved(*edge));
+        }
+    }
+
+    for (edge, props) in to.edges.iter() {
+        match from_edges.get(&edge.id) {
+            Some((from_edge, from_props)) if same_ends(edge, from_edge) => {
+                if from_props != props {
+                    diff.mutations
+                        .push(MutateKind::UpdateEdge((*edge, props.clone())));
+                    diff.changes
+                        .push(Change::EdgeChanged(*edge, changed_keys(from_props, props)));
+                }
+            }
+            _ => {
+                diff.mutations
+                    .push(MutateKind::RecreateEdge((*edge, props.clone())));
+                diff.changes.push(Change::EdgeAdded(*edge));
+            }
+        }
+    }
+
+    for (node_id, _) in from.nodes.iter() {
+        if !to_nodes.contains_key(node_id) {
+            diff.mutations.push(MutateKind::DeleteNode(*node_id));
+            diff.changes.push(Change::NodeRemoved(*node_id));
+        }
+    }
+
+    diff
+}
+
+fn same_ends(a: &Edge, b: &Edge) -> bool {
+    a.from == b.from && a.to == b.to
+}
+
+fn changed_keys(from: &Properties, to: &Properties) -> Vec<String> {
+    let mut keys: Vec<String> = from
+        .keys()
+        .chain(to.keys())
+        .filter(|key| from.get(*key) != to.get(*key))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::NodeAdded(node_id) => write!(f, "+ node {}", node_id),
+            Change::NodeChanged(node_id, keys) => {
+                write!(f, "~ node {} ({})", node_id, keys.join(", "))
+            }
+            Change::NodeRemoved(node_id) => write!(f, "- node {}", node_id),
+            Change::EdgeAdded(edge) => {
+                write!(f, "+ edge {} {} -> {}", edge.id, edge.from, edge.to)
+            }
+            Change::EdgeChanged(edge, keys) => write!(
+                f,
+                "~ edge {} {} -> {} ({})",
+                edge.id,
+                edge.from,
+                edge.to,
+                keys.join(", ")
+            ),
+            Change::EdgeRemoved(edge) => {
+                write!(f, "- edge {} {} -> {}", edge.id, edge.from, edge.to)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count =
+            |matches: fn(&Change) -> bool| self.changes.iter().filter(|c| matches(c)).count();
+
+        writeln!(
+            f,
+            "nodes: {} added, {} changed, {} removed; edges: {} added, {} changed, {} removed",
+            count(|c| matches!(c, Change::NodeAdded(_))),
+            count(|c| matches!(c, Change::NodeChanged(..))),
+            count(|c| matches!(c, Change::NodeRemoved(_))),
+            count(|c| matches!(c, Change::EdgeAdded(_))),
+            count(|c| matches!(c, Change::EdgeChanged(..))),
+            count(|c| matches!(c, Change::EdgeRemoved(_))),
+        )?;
+
+        for change in self.changes.iter() {
+            writeln!(f, "{}", change)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::content_hash;
+    use crate::test_utils::props;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn edge(from: NodeId, to: NodeId) -> Edge {
+        Edge {
+            id: Uuid::new_v4(),
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let (a, b, c, d) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        let (ab, bc, ca) = (edge(a, b), edge(b, c), edge(c, a));
+
+        let from = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "a" }))),
+                (b, props(json!({ "name": "b" }))),
+                (c, props(json!({ "name": "c" }))),
+            ],
+            edges: vec![
+                (ab, props(json!({ "weight": 1 }))),
+                (bc, Properties::new()),
+                (ca, Properties::new()),
+            ],
+        };
+
+        let to = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "a", "age": 1 }))),
+                (b, props(json!({ "name": "b" }))),
+                (d, props(json!({ "name": "d" }))),
+            ],
+            edges: vec![
+                (ab, props(json!({ "weight": 2 }))),
+                (edge(b, d), Properties::new()),
+            ],
+        };
+
+        let diff = diff(&from, &to);
+
+        // update a, create d, update ab, create bd, delete c (with bc and ca)
+        assert_eq!(diff.mutations.len(), 5);
+        assert!(matches!(diff.mutations[1], MutateKind::CreateNodeWithId((id, _)) if id == d));
+        assert!(matches!(diff.mutations[4], MutateKind::DeleteNode(id) if id == c));
+        assert!(diff.to_string().starts_with(
+            "nodes: 1 added, 1 changed, 1 removed; edges: 1 added, 1 changed, 2 removed"
+        ));
+
+        let mut applied = from.clone();
+        for mutation in diff.mutations {
+            applied.apply(mutation).unwrap();
+        }
+        assert_eq!(content_hash(&applied), content_hash(&to));
+
+        assert!(super::diff(&to, &to).is_empty());
+    }
+}
@@ -52,12 +52,48 @@ pub enum Error {
     HttpClientError(reqwest::Error),
     #[error("error, dgraph error: {0}.")]
     DGraphError(String),
+    #[error("error, the dgraph transaction conflicted with another one.")]
+    TransactionConflict,
+    #[error("error, the reverse of a mutation is not a mutation: {0:?}.")]
+    InvalidReverse(Box<crate::msg::Action>),
+    #[error("error, rolling back after `{0}` failed: {1:?}.")]
+    RollbackFailed(Box<Error>, Vec<Error>),
+    #[error("error, the store answered with an unexpected reply.")]
+    UnexpectedReply,
     #[error("error, operation isn't implemented.")]
     Unimplemented,
     #[error("error, graph not found.")]
     GraphNotFound,
     #[error("error, node not found.")]
     NodeNotFound,
+    #[error("error, edge not found.")]
+    EdgeNotFound,
+    #[error("error, node already exists: {0}.")]
+    NodeAlreadyExists(uuid::Uuid),
+    #[error("error, template parameter is missing: {0}.")]
+    MissingTemplateParameter(String),
+    #[error("error, version not found.")]
+    VersionNotFound,
+    #[error("error, version already exists: {0}.")]
+    VersionAlreadyExists(String),
+    #[error("error, state {0} of the graph is not available.")]
+    StateNotFound(u64),
+    #[error("error, {0} merge conflicts are not resolved.")]
+    UnresolvedConflicts(usize),
+    #[error("error, could not access the offline queue: {0}.")]
+    OfflineQueue(std::io::Error),
+    #[error("error, sunshine server error: {0}.")]
+    Server(String),
+    #[error("error, the remote is unavailable: {0}.")]
+    RemoteUnavailable(String),
+    #[error("error, depends on the refused action {0}/{1}.")]
+    DependsOnRejected(uuid::Uuid, u64),
+    #[error("error, action {0}/{1} is older than the history kept.")]
+    ActionForgotten(uuid::Uuid, u64),
+    #[error("error, could not access the file: {0}.")]
+    File(std::io::Error),
+    #[error("error, invalid configuration: {0}.")]
+    Config(String),
 }
 
 impl From<uuid::Error> for Error {
@@ -0,0 +1,132 @@
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::msg::{GraphId, Subgraph};
+
+// the hash of the latest state of each graph that was hashed, a state id always
+// names the same content so a hit spares reading the edges again
+#[derive(Debug, Default)]
+pub struct HashCache {
+    hashes: Mutex<HashMap<GraphId, (u64, String)>>,
+}
+
+impl HashCache {
+    pub fn get(&self, graph_id: GraphId, state_id: u64) -> Option<String> {
+        match self.hashes.lock().unwrap().get(&graph_id) {
+            Some((cached_state_id, hash)) if *cached_state_id == state_id => Some(hash.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, graph_id: GraphId, state_id: u64, hash: String) {
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(graph_id, (state_id, hash));
+    }
+
+    // a graph created again under the same id starts its state ids over
+    pub fn remove(&self, graph_id: GraphId) {
+        self.hashes.lock().unwrap().remove(&graph_id);
+    }
+}
+
+// sha256 over a canonical form of the nodes, edges and their properties:
+// nodes and edges are sorted by id and object keys are sorted,
+// so the hash doesn't depend on insertion order or on the backend
+pub fn content_hash(subgraph: &Subgraph) -> String {
+    let mut nodes: Vec<_> = subgraph.nodes.iter().collect();
+    nodes.sort_by_key(|(node_id, _)| *node_id);
+
+    let mut edges: Vec<_> = subgraph.edges.iter().collect();
+    edges.sort_by_key(|(edge, _)| edge.id);
+
+    let mut canonical = String::new();
+
+    for (node_id, properties) in nodes {
+        canonical.push_str(&format!("n{}", node_id));
+        write_canonical(&mut canonical, &JsonValue::Object(properties.clone()));
+    }
+
+    for (edge, properties) in edges {
+        canonical.push_str(&format!("e{}:{}:{}", edge.id, edge.from, edge.to));
+        write_canonical(&mut canonical, &JsonValue::Object(properties.clone()));
+    }
+
+    Sha256::digest(canonical.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn write_canonical(out: &mut String, value: &JsonValue) {
+    match value {
+        JsonValue::Object(map) => {
+            let mut keys: Vec<_> = map.keys().collect();
+            keys.sort();
+
+            out.push('{');
+            for key in keys {
+                out.push_str(&JsonValue::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(out, &map[key]);
+                out.push(',');
+            }
+            out.push('}');
+        }
+        JsonValue::Array(values) => {
+            out.push('[');
+            for value in values {
+                write_canonical(out, value);
+                out.push(',');
+            }
+            out.push(']');
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{Edge, Properties};
+    use crate::test_utils::props;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_content_hash_ignores_order() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let edge = Edge {
+            id: Uuid::new_v4(),
+            from: a,
+            to: b,
+        };
+
+        let mut first = props(json!({ "name": "a" }));
+        first.insert("age".into(), json!(3));
+        let mut second = props(json!({ "age": 3 }));
+        second.insert("name".into(), json!("a"));
+
+        let subgraph = Subgraph {
+            nodes: vec![(a, first), (b, Properties::new())],
+            edges: vec![(edge, Properties::new())],
+        };
+        let reordered = Subgraph {
+            nodes: vec![(b, Properties::new()), (a, second)],
+            edges: vec![(edge, Properties::new())],
+        };
+        assert_eq!(content_hash(&subgraph), content_hash(&reordered));
+
+        let changed = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "b", "age": 3 }))),
+                (b, Properties::new()),
+            ],
+            edges: vec![(edge, Properties::new())],
+        };
+        assert_ne!(content_hash(&subgraph), content_hash(&changed));
+    }
+}
@@ -0,0 +1,351 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::msg::{Action, ActionId, Reply};
+
+// entries kept by default, the oldest quarter is dropped once there are more
+pub const HISTORY_CAPACITY: usize = 10_000;
+
+// the executed mutations with their id and reply, ids from other clients are kept as they came,
+// queries aren't recorded, positions count the dropped entries so they stay the same
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredHistory")]
+pub struct History {
+    client_id: Uuid,
+    next_seq: u64,
+    capacity: usize,
+    // number of entries dropped from the front
+    offset: usize,
+    entries: Vec<(ActionId, Action)>,
+    // the reply of each entry, a retried action gets the one it had the first time
+    replies: Vec<Reply>,
+    // the highest seq of each client among the dropped entries
+    dropped: HashMap<Uuid, u64>,
+    // the position of each applied id in the entries, rebuilt when loading
+    #[serde(skip_serializing)]
+    applied: HashMap<ActionId, usize>,
+    // the positions kept when the store last saved the history
+    #[serde(skip_serializing)]
+    saved: Range<usize>,
+}
+
+// stores that append to a saved history instead of writing it whole keep a head,
+// rewritten after each action, and one entry per action, removed once dropped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryHead {
+    client_id: Uuid,
+    next_seq: u64,
+    capacity: usize,
+    offset: usize,
+    dropped: HashMap<Uuid, u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub position: usize,
+    pub action_id: ActionId,
+    pub action: Action,
+    pub reply: Reply,
+}
+
+#[derive(Deserialize)]
+struct StoredHistory {
+    client_id: Uuid,
+    next_seq: u64,
+    // missing from histories saved before they were bounded
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+    #[serde(default)]
+    offset: usize,
+    entries: Vec<(ActionId, Action)>,
+    replies: Vec<Reply>,
+    #[serde(default)]
+    dropped: HashMap<Uuid, u64>,
+}
+
+fn default_capacity() -> usize {
+    HISTORY_CAPACITY
+}
+
+impl From<StoredHistory> for History {
+    fn from(stored: StoredHistory) -> Self {
+        let applied = stored
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(position, (action_id, _))| (*action_id, stored.offset + position))
+            .collect();
+
+        let saved = stored.offset..stored.offset + stored.entries.len();
+        History {
+            client_id: stored.client_id,
+            next_seq: stored.next_seq,
+            capacity: stored.capacity,
+            offset: stored.offset,
+            entries: stored.entries,
+            replies: stored.replies,
+            dropped: stored.dropped,
+            applied,
+            saved,
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(Uuid::new_v4())
+    }
+}
+
+impl History {
+    pub fn new(client_id: Uuid) -> Self {
+        History {
+            client_id,
+            next_seq: 0,
+            capacity: HISTORY_CAPACITY,
+            offset: 0,
+            entries: Vec::new(),
+            replies: Vec::new(),
+            dropped: HashMap::new(),
+            applied: HashMap::new(),
+            saved: 0..0,
+        }
+    }
+
+    // a history saved as a head and its entries, those already dropped are left out
+    pub fn from_parts(head: HistoryHead, mut entries: Vec<HistoryEntry>) -> Self {
+        entries.retain(|entry| entry.position >= head.offset);
+        entries.sort_by_key(|entry| entry.position);
+        let (entries, replies) = entries
+            .into_iter()
+            .map(|entry| ((entry.action_id, entry.action), entry.reply))
+            .unzip();
+
+        History::from(StoredHistory {
+            client_id: head.client_id,
+            next_seq: head.next_seq,
+            capacity: head.capacity,
+            offset: head.offset,
+            entries,
+            replies,
+            dropped: head.dropped,
+        })
+    }
+
+    pub fn head(&self) -> HistoryHead {
+        HistoryHead {
+            client_id: self.client_id,
+            next_seq: self.next_seq,
+            capacity: self.capacity,
+            offset: self.offset,
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    // the entries pushed since the last save, and the positions dropped since
+    pub fn unsaved(&self) -> (Vec<HistoryEntry>, Range<usize>) {
+        let start = self.saved.end.max(self.offset);
+        let entries = (start..self.len())
+            .map(|position| {
+                let (action_id, action) = self.entries[position - self.offset].clone();
+                HistoryEntry {
+                    position,
+                    action_id,
+                    action,
+                    reply: self.replies[position - self.offset].clone(),
+                }
+            })
+            .collect();
+
+        (entries, self.saved.start..self.offset.min(self.saved.end))
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = self.offset..self.len();
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn client_id(&self) -> Uuid {
+        self.client_id
+    }
+
+    pub fn next_id(&mut self) -> ActionId {
+        self.next_seq += 1;
+        ActionId {
+            client_id: self.client_id,
+            seq: self.next_seq,
+        }
+    }
+
+    pub fn contains(&self, action_id: ActionId) -> bool {
+        self.applied.contains_key(&action_id)
+    }
+
+    pub fn reply(&self, action_id: ActionId) -> Option<&Reply> {
+        self.applied
+            .get(&action_id)
+            .map(|position| &self.replies[position - self.offset])
+    }
+
+    // the reply of an applied id, an id as old as the dropped entries of its client
+    // may have been applied or not, so it's refused rather than run again
+    pub fn seen(&self, action_id: ActionId) -> Result<Option<&Reply>> {
+        match self.reply(action_id) {
+            Some(reply) => Ok(Some(reply)),
+            None if self
+                .dropped
+                .get(&action_id.client_id)
+                .is_some_and(|seq| action_id.seq <= *seq) =>
+            {
+                Err(Error::ActionForgotten(action_id.client_id, action_id.seq))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // the highest seq applied from the client, which tells a client what to resend
+    pub fn last_seq(&self, client_id: Uuid) -> Option<u64> {
+        self.applied
+            .keys()
+            .filter(|action_id| action_id.client_id == client_id)
+            .map(|action_id| action_id.seq)
+            .chain(self.dropped.get(&client_id).copied())
+            .max()
+    }
+
+    pub fn push(&mut self, action_id: ActionId, action: Action, reply: Reply) {
+        self.applied.insert(action_id, self.len());
+        self.entries.push((action_id, action));
+        self.replies.push(reply);
+
+        if self.entries.len() > self.capacity {
+            let count = self.entries.len() - self.capacity * 3 / 4;
+            for (action_id, _) in self.entries.drain(..count) {
+                self.applied.remove(&action_id);
+                let seq = self.dropped.entry(action_id.client_id).or_default();
+                *seq = action_id.seq.max(*seq);
+            }
+            self.replies.drain(..count);
+            self.offset += count;
+        }
+    }
+
+    // forgets the entries past the position `len`, e.g. of actions that were rolled back
+    pub fn truncate(&mut self, len: usize) {
+        let len = len.saturating_sub(self.offset);
+        if len < self.entries.len() {
+            for (action_id, _) in self.entries.drain(len..) {
+                self.applied.remove(&action_id);
+            }
+            self.replies.truncate(len);
+        }
+        self.saved.end = self.saved.end.min(self.len());
+    }
+
+    // the entries kept, the first one is at position `start`
+    pub fn entries(&self) -> &[(ActionId, Action)] {
+        &self.entries
+    }
+
+    // the entries from the position on, those dropped already are left out
+    pub fn since(&self, position: usize) -> &[(ActionId, Action)] {
+        let start = position.saturating_sub(self.offset).min(self.entries.len());
+        &self.entries[start..]
+    }
+
+    pub fn start(&self) -> usize {
+        self.offset
+    }
+
+    // the position of the next entry, dropped entries included
+    pub fn len(&self) -> usize {
+        self.offset + self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::default().with_capacity(8);
+        let ids: Vec<ActionId> = (0..9).map(|_| history.next_id()).collect();
+        for action_id in ids.iter() {
+            history.push(*action_id, Action::Undo, Reply::Empty);
+        }
+
+        // a quarter of the capacity is left free, positions don't move
+        assert_eq!(history.entries().len(), 6);
+        assert_eq!(history.len(), 9);
+        assert_eq!(history.start(), 3);
+        assert_eq!(history.since(7)[0].0, ids[7]);
+        assert_eq!(history.since(0).len(), 6);
+
+        assert!(matches!(history.seen(ids[8]), Ok(Some(Reply::Empty))));
+        assert!(matches!(
+            history.seen(ids[2]),
+            Err(Error::ActionForgotten(..))
+        ));
+        assert_eq!(history.last_seq(history.client_id()), Some(9));
+
+        history.truncate(7);
+        assert_eq!(history.len(), 7);
+        assert!(!history.contains(ids[8]));
+        assert_eq!(history.last_seq(history.client_id()), Some(7));
+
+        let loaded: History =
+            serde_json::from_str(&serde_json::to_string(&history).unwrap()).unwrap();
+        assert_eq!(loaded.len(), 7);
+        assert!(matches!(loaded.reply(ids[6]), Some(Reply::Empty)));
+        assert!(loaded.seen(ids[0]).is_err());
+    }
+
+    #[test]
+    fn test_saved_in_parts() {
+        let mut history = History::default().with_capacity(4);
+        let ids: Vec<ActionId> = (0..5).map(|_| history.next_id()).collect();
+        for action_id in ids[..3].iter() {
+            history.push(*action_id, Action::Undo, Reply::Empty);
+        }
+
+        // only what was pushed since the last save is written
+        let (mut saved, dropped) = history.unsaved();
+        assert_eq!(saved.len(), 3);
+        assert!(dropped.is_empty());
+        history.mark_saved();
+        history.push(ids[3], Action::Redo, Reply::Empty);
+        let (entries, _) = history.unsaved();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].position, 3);
+        saved.extend(entries);
+        history.mark_saved();
+
+        // the fifth entry drops the first two, which the store removes
+        history.push(ids[4], Action::Undo, Reply::Empty);
+        let (entries, dropped) = history.unsaved();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(dropped, 0..2);
+        saved.extend(entries);
+        history.mark_saved();
+
+        let loaded = History::from_parts(history.head(), saved);
+        assert_eq!(loaded.start(), 2);
+        assert_eq!(loaded.len(), 5);
+        assert!(matches!(loaded.since(3)[0].1, Action::Redo));
+        assert!(loaded.contains(ids[4]));
+        assert!(loaded.seen(ids[0]).is_err());
+        assert!(loaded.unsaved().0.is_empty());
+    }
+}
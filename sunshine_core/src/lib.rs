@@ -1,4 +1,15 @@
+pub mod crdt;
+pub mod delegate;
+pub mod diff;
 pub mod error;
+pub mod hash;
+pub mod history;
+pub mod merge;
 pub mod msg;
 pub mod properties;
 pub mod store;
+pub mod template;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod timeline;
+pub mod version;
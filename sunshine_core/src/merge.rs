@@ -0,0 +1,519 @@
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::diff;
+use crate::error::{Error, Result};
+use crate::msg::{Action, Edge, EdgeId, GraphId, MutateKind, NodeId, Properties, Reply, Subgraph};
+use crate::store::Datastore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Element {
+    Node(NodeId),
+    Edge(EdgeId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conflict {
+    // both sides changed the property to different values, None means removed
+    Property {
+        element: Element,
+        key: String,
+        base: Option<JsonValue>,
+        ours: Option<JsonValue>,
+        theirs: Option<JsonValue>,
+    },
+    // one side deleted what the other side modified
+    DeleteModify {
+        element: Element,
+        deleted_by: Side,
+    },
+    // one side created or modified an edge to a node the other side deleted
+    EdgeToDeletedNode {
+        edge: Edge,
+        node_id: NodeId,
+        deleted_by: Side,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Merge {
+    // conflicts are resolved with our side until `resolve` says otherwise
+    pub merged: Subgraph,
+    pub conflicts: Vec<Conflict>,
+    ours: Subgraph,
+    theirs: Subgraph,
+}
+
+// diff::diff(ours, &merge.merged) gives the mutations to apply to our copy
+pub fn merge(base: &Subgraph, ours: &Subgraph, theirs: &Subgraph) -> Merge {
+    let mut conflicts = Vec::new();
+
+    let base_nodes = node_map(base);
+    let our_nodes = node_map(ours);
+    let their_nodes = node_map(theirs);
+
+    let mut nodes = Vec::new();
+    for node_id in ordered_ids(
+        [base, ours, theirs]
+            .iter()
+            .flat_map(|subgraph| subgraph.nodes.iter().map(|(id, _)| *id)),
+    ) {
+        let merged = merge_element(
+            Element::Node(node_id),
+            base_nodes.get(&node_id).copied(),
+            our_nodes.get(&node_id).copied(),
+            their_nodes.get(&node_id).copied(),
+            &mut conflicts,
+        );
+        if let Some(properties) = merged {
+            nodes.push((node_id, properties));
+        }
+    }
+
+    let node_ids: HashSet<NodeId> = nodes.iter().map(|(id, _)| *id).collect();
+
+    let base_edges = edge_map(base);
+    let our_edges = edge_map(ours);
+    let their_edges = edge_map(theirs);
+
+    let mut edges = Vec::new();
+    for edge_id in ordered_ids(
+        [base, ours, theirs]
+            .iter()
+            .flat_map(|subgraph| subgraph.edges.iter().map(|(edge, _)| edge.id)),
+    ) {
+        let base_edge = base_edges.get(&edge_id).copied();
+        let our_edge = our_edges.get(&edge_id).copied();
+        let their_edge = their_edges.get(&edge_id).copied();
+
+        let merged = merge_element(
+            Element::Edge(edge_id),
+            base_edge.map(|(_, props)| props),
+            our_edge.map(|(_, props)| props),
+            their_edge.map(|(_, props)| props),
+            &mut conflicts,
+        );
+        let properties = match merged {
+            Some(properties) => properties,
+            None => continue,
+        };
+
+        let edge = our_edge.or(their_edge).or(base_edge).unwrap().0;
+
+        match [edge.from, edge.to]
+            .into_iter()
+            .find(|node_id| !node_ids.contains(node_id))
+        {
+            None => edges.push((edge, properties)),
+            Some(node_id) => {
+                // an untouched edge simply goes away with its node
+                let changed_by = |side: Option<&(Edge, Properties)>| match (base_edge, side) {
+                    (_, None) => false,
+                    (None, Some(_)) => true,
+                    (Some((_, base_props)), Some((_, props))) => base_props != props,
+                };
+                let deleted_by = if !our_nodes.contains_key(&node_id) {
+                    Side::Ours
+                } else {
+                    Side::Theirs
+                };
+                let changed = match deleted_by {
+                    Side::Ours => changed_by(their_edge),
+                    Side::Theirs => changed_by(our_edge),
+                };
+                if changed {
+                    conflicts.push(Conflict::EdgeToDeletedNode {
+                        edge,
+                        node_id,
+                        deleted_by,
+                    });
+                }
+            }
+        }
+    }
+
+    Merge {
+        merged: Subgraph { nodes, edges },
+        conflicts,
+        ours: ours.clone(),
+        theirs: theirs.clone(),
+    }
+}
+
+impl Merge {
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty()
+    }
+
+    // takes the given side for a conflict and drops it from the list
+    pub fn resolve(&mut self, conflict: &Conflict, side: Side) {
+        let source = match side {
+            Side::Ours => &self.ours,
+            Side::Theirs => &self.theirs,
+        };
+
+        match conflict {
+            Conflict::Property {
+                element,
+                key,
+                ours,
+                theirs,
+                ..
+            } => {
+                let value = match side {
+                    Side::Ours => ours.clone(),
+                    Side::Theirs => theirs.clone(),
+                };
+                if let Some(properties) = properties_mut(&mut self.merged, *element) {
+                    match value {
+                        Some(value) => properties.insert(key.clone(), value),
+                        None => properties.remove(key),
+                    };
+                }
+            }
+            Conflict::DeleteModify { element, .. } => {
+                remove_element(&mut self.merged, *element);
+                match element {
+                    Element::Node(node_id) => {
+                        if let Some(node) = source.nodes.iter().find(|(id, _)| id == node_id) {
+                            self.merged.nodes.push(node.clone());
+                        }
+                        // the edges went away with the node on the deleting side, the kept
+                        // node gets back those of the side keeping it
+                        let restored: Vec<(Edge, Properties)> = source
+                            .edges
+                            .iter()
+                            .filter(|(edge, _)| edge.from == *node_id || edge.to == *node_id)
+                            .filter(|(edge, _)| {
+                                self.merged.contains_node(edge.from)
+                                    && self.merged.contains_node(edge.to)
+                                    && !self.merged.edges.iter().any(|(e, _)| e.id == edge.id)
+                            })
+                            .cloned()
+                            .collect();
+                        self.merged.edges.extend(restored);
+                    }
+                    Element::Edge(edge_id) => {
+                        if let Some(edge) = source.edges.iter().find(|(e, _)| e.id == *edge_id) {
+                            self.merged.edges.push(edge.clone());
+                        }
+                    }
+                }
+            }
+            Conflict::EdgeToDeletedNode {
+                edge,
+                node_id,
+                deleted_by,
+            } => {
+                // keeping the edge means bringing back the node it points to
+                if side != *deleted_by {
+                    let restore_from = match deleted_by {
+                        Side::Ours => &self.theirs,
+                        Side::Theirs => &self.ours,
+                    };
+                    if let Some(node) = restore_from.nodes.iter().find(|(id, _)| id == node_id) {
+                        if !self.merged.contains_node(*node_id) {
+                            self.merged.nodes.push(node.clone());
+                        }
+                    }
+                    if let Some(kept) = restore_from.edges.iter().find(|(e, _)| e.id == edge.id) {
+                        self.merged.edges.push(kept.clone());
+                    }
+                }
+            }
+        }
+
+        self.conflicts.retain(|other| other != conflict);
+    }
+}
+
+// merges `theirs` into the graph in the datastore, the graph at `base_state_id`
+// being the state both sides started from
+pub async fn merge_with_datastore<D: Datastore + ?Sized>(
+    store: &D,
+    graph_id: GraphId,
+    base_state_id: u64,
+    theirs: &Subgraph,
+) -> Result<Merge> {
+    let base = store.read_snapshot_at(graph_id, base_state_id).await?;
+    let (_, ours) = store.read_graph_snapshot(graph_id).await?;
+
+    Ok(merge(&base, &ours, theirs))
+}
+
+// brings the graph in the datastore to the merged state as one undoable batch,
+// the conflicts have to be resolved first
+pub async fn apply_to_datastore<D: Datastore + ?Sized>(
+    merge: &Merge,
+    store: &mut D,
+    graph_id: GraphId,
+) -> Result<Reply> {
+    if merge.has_conflicts() {
+        return Err(Error::UnresolvedConflicts(merge.conflicts.len()));
+    }
+
+    let (_, current) = store.read_graph_snapshot(graph_id).await?;
+    let diff = diff::diff(&current, &merge.merged);
+
+    if diff.is_empty() {
+        return Ok(Reply::Empty);
+    }
+
+    store
+        .execute(Action::Mutate(graph_id, MutateKind::Batch(diff.mutations)))
+        .await
+}
+
+// three-way merge of one node or edge, None when it ends up deleted
+fn merge_element(
+    element: Element,
+    base: Option<&Properties>,
+    ours: Option<&Properties>,
+    theirs: Option<&Properties>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<Properties> {
+    match (base, ours, theirs) {
+        (_, Some(ours), Some(theirs)) => Some(merge_properties(
+            element,
+            base.unwrap_or(&Properties::new()),
+            ours,
+            theirs,
+            conflicts,
+        )),
+        (None, Some(added), None) | (None, None, Some(added)) => Some(added.clone()),
+        (Some(base), Some(kept), None) | (Some(base), None, Some(kept)) => {
+            if kept != base {
+                let deleted_by = if ours.is_none() {
+                    Side::Ours
+                } else {
+                    Side::Theirs
+                };
+                conflicts.push(Conflict::DeleteModify {
+                    element,
+                    deleted_by,
+                });
+                // our side wins until resolved
+                ours.cloned()
+            } else {
+                None
+            }
+        }
+        (_, None, None) => None,
+    }
+}
+
+fn merge_properties(
+    element: Element,
+    base: &Properties,
+    ours: &Properties,
+    theirs: &Properties,
+    conflicts: &mut Vec<Conflict>,
+) -> Properties {
+    let keys: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let mut merged = Properties::new();
+    for key in keys {
+        let (base_value, our_value, their_value) = (base.get(key), ours.get(key), theirs.get(key));
+
+        let value = if our_value == their_value || their_value == base_value {
+            our_value
+        } else if our_value == base_value {
+            their_value
+        } else {
+            conflicts.push(Conflict::Property {
+                element,
+                key: key.clone(),
+                base: base_value.cloned(),
+                ours: our_value.cloned(),
+                theirs: their_value.cloned(),
+            });
+            our_value
+        };
+
+        if let Some(value) = value {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    merged
+}
+
+fn node_map(subgraph: &Subgraph) -> HashMap<NodeId, &Properties> {
+    subgraph
+        .nodes
+        .iter()
+        .map(|(id, props)| (*id, props))
+        .collect()
+}
+
+fn edge_map(subgraph: &Subgraph) -> HashMap<EdgeId, &(Edge, Properties)> {
+    subgraph
+        .edges
+        .iter()
+        .map(|edge| (edge.0.id, edge))
+        .collect()
+}
+
+// keeps the order of first appearance so the result is stable
+fn ordered_ids<I: Iterator<Item = uuid::Uuid>>(ids: I) -> Vec<uuid::Uuid> {
+    let mut seen = HashSet::new();
+    ids.filter(|id| seen.insert(*id)).collect()
+}
+
+fn properties_mut(subgraph: &mut Subgraph, element: Element) -> Option<&mut Properties> {
+    match element {
+        Element::Node(node_id) => subgraph
+            .nodes
+            .iter_mut()
+            .find(|(id, _)| *id == node_id)
+            .map(|(_, props)| props),
+        Element::Edge(edge_id) => subgraph
+            .edges
+            .iter_mut()
+            .find(|(edge, _)| edge.id == edge_id)
+            .map(|(_, props)| props),
+    }
+}
+
+fn remove_element(subgraph: &mut Subgraph, element: Element) {
+    match element {
+        Element::Node(node_id) => {
+            subgraph.nodes.retain(|(id, _)| *id != node_id);
+            subgraph
+                .edges
+                .retain(|(edge, _)| edge.from != node_id && edge.to != node_id);
+        }
+        Element::Edge(edge_id) => subgraph.edges.retain(|(edge, _)| edge.id != edge_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::props;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn node(subgraph: &Subgraph, node_id: NodeId) -> Option<&Properties> {
+        subgraph
+            .nodes
+            .iter()
+            .find(|(id, _)| *id == node_id)
+            .map(|(_, props)| props)
+    }
+
+    #[test]
+    fn test_merge_without_conflicts() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let base = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "a", "x": 1 }))),
+                (b, props(json!({ "name": "b" }))),
+            ],
+            edges: Vec::new(),
+        };
+        let mut ours = base.clone();
+        ours.nodes[0].1 = props(json!({ "name": "a", "x": 2 }));
+        ours.nodes.push((c, props(json!({ "name": "c" }))));
+
+        let mut theirs = base.clone();
+        theirs.nodes[0].1 = props(json!({ "name": "A", "x": 1 }));
+        theirs.nodes.retain(|(id, _)| *id != b);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert!(!merge.has_conflicts());
+        assert_eq!(
+            node(&merge.merged, a),
+            Some(&props(json!({ "name": "A", "x": 2 })))
+        );
+        assert!(node(&merge.merged, b).is_none());
+        assert!(node(&merge.merged, c).is_some());
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut base = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "a" }))),
+                (b, props(json!({ "name": "b" }))),
+                (c, props(json!({ "name": "c" }))),
+            ],
+            edges: Vec::new(),
+        };
+
+        let edge = Edge {
+            id: Uuid::new_v4(),
+            from: a,
+            to: c,
+        };
+        let untouched = Edge {
+            id: Uuid::new_v4(),
+            from: b,
+            to: a,
+        };
+        base.edges.push((untouched, props(json!({ "weight": 1 }))));
+
+        let mut ours = base.clone();
+        ours.nodes[0].1 = props(json!({ "name": "ours" }));
+        ours.nodes.retain(|(id, _)| *id != b);
+        ours.edges.clear();
+        ours.edges.push((edge, Properties::new()));
+
+        let mut theirs = base.clone();
+        theirs.nodes[0].1 = props(json!({ "name": "theirs" }));
+        theirs.nodes[1].1 = props(json!({ "name": "changed" }));
+        theirs.nodes.retain(|(id, _)| *id != c);
+
+        let mut merge = merge(&base, &ours, &theirs);
+
+        assert_eq!(merge.conflicts.len(), 3);
+        assert!(merge.conflicts.contains(&Conflict::Property {
+            element: Element::Node(a),
+            key: "name".into(),
+            base: Some(json!("a")),
+            ours: Some(json!("ours")),
+            theirs: Some(json!("theirs")),
+        }));
+        assert!(merge.conflicts.contains(&Conflict::DeleteModify {
+            element: Element::Node(b),
+            deleted_by: Side::Ours,
+        }));
+        assert!(merge.conflicts.contains(&Conflict::EdgeToDeletedNode {
+            edge,
+            node_id: c,
+            deleted_by: Side::Theirs,
+        }));
+        assert!(merge.merged.edges.is_empty());
+
+        for conflict in merge.conflicts.clone() {
+            merge.resolve(&conflict, Side::Theirs);
+        }
+        assert!(!merge.has_conflicts());
+        assert_eq!(
+            node(&merge.merged, a),
+            Some(&props(json!({ "name": "theirs" })))
+        );
+        assert_eq!(
+            node(&merge.merged, b),
+            Some(&props(json!({ "name": "changed" })))
+        );
+        assert!(node(&merge.merged, c).is_none());
+        // the node deleted on our side comes back with its edge
+        assert_eq!(
+            merge.merged.edges,
+            vec![(untouched, props(json!({ "weight": 1 })))]
+        );
+    }
+}
@@ -1,28 +1,66 @@
 use indradb::{EdgeKey, Type};
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use uuid::Uuid;
 
+use crate::diff::Diff;
+use crate::error::Error;
+use crate::hash;
+
 // this map can't contain Objects
 pub type Properties = serde_json::Map<String, JsonValue>;
 
-#[derive(Clone, Debug)]
+// origin of an action, unique across clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ActionId {
+    pub client_id: Uuid,
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Action {
     Mutate(GraphId, MutateKind),
     Query(QueryKind),
     CreateGraph(Properties),
     CreateGraphWithId(GraphId, Properties),
     DeleteGraph(GraphId),
+    RecreateGraph(RecreateGraph),
+    CreateTemplate(String, Subgraph),
+    CreateTemplateWithId(GraphId, String, Subgraph),
+    CreateVersion(GraphId, String),
+    CreateVersionWithId(GraphId, GraphId, String), // version id, graph id, name
     Undo,
     Redo,
 }
 
+impl Action {
+    // for logs, without ids or properties
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Mutate(_, kind) => kind.name(),
+            Action::Query(_) => "Query",
+            Action::CreateGraph(_) => "CreateGraph",
+            Action::CreateGraphWithId(..) => "CreateGraphWithId",
+            Action::DeleteGraph(_) => "DeleteGraph",
+            Action::RecreateGraph(_) => "RecreateGraph",
+            Action::CreateTemplate(..) => "CreateTemplate",
+            Action::CreateTemplateWithId(..) => "CreateTemplateWithId",
+            Action::CreateVersion(..) => "CreateVersion",
+            Action::CreateVersionWithId(..) => "CreateVersionWithId",
+            Action::Undo => "Undo",
+            Action::Redo => "Redo",
+        }
+    }
+}
+
 // #[derive(Clone, Debug)]
 // pub struct MutateState {
 //     pub kind: MutateStateKind,
 //     pub graph_id: GraphId,
 // }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum MutateKind {
     CreateNode(Properties),
     CreateNodeWithId((NodeId, Properties)),
@@ -32,20 +70,61 @@ pub enum MutateKind {
     CreateEdge(CreateEdge),
     UpdateEdge((Edge, Properties)),
     DeleteEdge(Edge),
+    RecreateEdge((Edge, Properties)),
+    PasteSubgraph(Subgraph),
+    InstantiateTemplate((GraphId, Properties)), // template id, parameters
+    RestoreVersion(String),
+    Batch(Vec<MutateKind>),
 }
 
-#[derive(Clone, Debug)]
+impl MutateKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MutateKind::CreateNode(_) => "CreateNode",
+            MutateKind::CreateNodeWithId(_) => "CreateNodeWithId",
+            MutateKind::RecreateNode(_) => "RecreateNode",
+            MutateKind::UpdateNode(_) => "UpdateNode",
+            MutateKind::DeleteNode(_) => "DeleteNode",
+            MutateKind::CreateEdge(_) => "CreateEdge",
+            MutateKind::UpdateEdge(_) => "UpdateEdge",
+            MutateKind::DeleteEdge(_) => "DeleteEdge",
+            MutateKind::RecreateEdge(_) => "RecreateEdge",
+            MutateKind::PasteSubgraph(_) => "PasteSubgraph",
+            MutateKind::InstantiateTemplate(_) => "InstantiateTemplate",
+            MutateKind::RestoreVersion(_) => "RestoreVersion",
+            MutateKind::Batch(_) => "Batch",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum QueryKind {
     ListGraphs,       // graph node list
     ReadNode(NodeId), //node properties and edges
     ReadEdgeProperties(Edge),
-    ReadGraph(GraphId), //list of nodes[edges]
+    ReadGraph(GraphId),        //list of nodes[edges]
+    CopySubgraph(Vec<NodeId>), // selected nodes and the edges among them
+    ListTemplates,
+    ListVersions(GraphId),
+    ReadGraphVersion((GraphId, String)),
+    ReadGraphAt {
+        graph_id: GraphId,
+        state_id: u64,
+    },
+    ReadGraphHash(GraphId),
+    LastApplied(Uuid), // client id
+    DiffGraphAt {
+        graph_id: GraphId,
+        from_state_id: u64,
+        to_state_id: u64,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Graph {
     pub nodes: Vec<Node>,
     pub state_id: u64,
+    pub hash: Option<String>, // content hash, see hash::content_hash
 }
 
 pub type GraphId = Uuid;
@@ -53,14 +132,24 @@ pub type NodeId = Uuid;
 
 pub type EdgeId = Uuid;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RecreateNode {
     pub node_id: NodeId,
     pub properties: Properties,
     pub edges: Vec<(Edge, Properties)>,
 }
 
-#[derive(Debug, Clone, Default)]
+// reverse of DeleteGraph, the graph comes back at the state id it was deleted at
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecreateGraph {
+    pub graph_id: GraphId,
+    pub properties: Properties,
+    pub subgraph: Subgraph,
+    pub state_id: u64,
+    pub versions: Vec<(GraphId, Properties)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Node {
     pub node_id: NodeId,
     pub properties: Properties,
@@ -74,14 +163,161 @@ pub struct Node {
 //     name: String,
 // }
 
-#[derive(Debug, Clone, Copy, Default)]
+// nodes and the edges among them, detached from any graph
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Subgraph {
+    pub nodes: Vec<(NodeId, Properties)>,
+    pub edges: Vec<(Edge, Properties)>,
+}
+
+impl Subgraph {
+    pub fn into_graph(self, state_id: u64) -> Graph {
+        let hash = Some(hash::content_hash(&self));
+
+        let mut nodes: Vec<Node> = self
+            .nodes
+            .into_iter()
+            .map(|(node_id, properties)| Node {
+                node_id,
+                properties,
+                ..Node::default()
+            })
+            .collect();
+
+        for (edge, _) in self.edges {
+            for node in nodes.iter_mut() {
+                if node.node_id == edge.from {
+                    node.outbound_edges.push(edge);
+                }
+                if node.node_id == edge.to {
+                    node.inbound_edges.push(edge);
+                }
+            }
+        }
+
+        Graph {
+            nodes,
+            state_id,
+            hash,
+        }
+    }
+
+    // applies a mutation with known ids in memory,
+    // edges to nodes outside of the subgraph (e.g. the graph root) are ignored
+    pub fn apply(&mut self, kind: MutateKind) -> Result<(), Error> {
+        match kind {
+            MutateKind::CreateNodeWithId((node_id, properties)) => {
+                self.nodes.push((node_id, properties))
+            }
+            MutateKind::RecreateNode(recreate_node) => {
+                self.nodes
+                    .push((recreate_node.node_id, recreate_node.properties));
+                for edge in recreate_node.edges {
+                    self.apply(MutateKind::RecreateEdge(edge))?;
+                }
+            }
+            MutateKind::UpdateNode((node_id, properties)) => {
+                let (_, props) = self
+                    .nodes
+                    .iter_mut()
+                    .find(|(id, _)| *id == node_id)
+                    .ok_or(Error::NodeNotFound)?;
+                *props = properties;
+            }
+            MutateKind::DeleteNode(node_id) => {
+                self.nodes.retain(|(id, _)| *id != node_id);
+                self.edges
+                    .retain(|(edge, _)| edge.from != node_id && edge.to != node_id);
+            }
+            MutateKind::CreateEdge(create_edge) => {
+                let edge = Edge {
+                    id: indradb::util::generate_uuid_v1(),
+                    from: create_edge.from,
+                    to: create_edge.to,
+                };
+                self.apply(MutateKind::RecreateEdge((edge, create_edge.properties)))?;
+            }
+            MutateKind::RecreateEdge((edge, properties)) => {
+                if self.contains_node(edge.from) && self.contains_node(edge.to) {
+                    self.edges.push((edge, properties));
+                }
+            }
+            MutateKind::UpdateEdge((edge, properties)) => {
+                if let Some((_, props)) = self.edges.iter_mut().find(|(e, _)| e.id == edge.id) {
+                    *props = properties;
+                }
+            }
+            MutateKind::DeleteEdge(edge) => self.edges.retain(|(e, _)| e.id != edge.id),
+            MutateKind::Batch(kinds) => {
+                for kind in kinds {
+                    self.apply(kind)?;
+                }
+            }
+            MutateKind::CreateNode(_)
+            | MutateKind::PasteSubgraph(_)
+            | MutateKind::InstantiateTemplate(_)
+            | MutateKind::RestoreVersion(_) => return Err(Error::Unimplemented),
+        }
+
+        Ok(())
+    }
+
+    // the same content under fresh node and edge ids, edges leaving the subgraph are dropped,
+    // the old node id -> new node id mapping comes along
+    pub fn with_fresh_ids(self) -> (Subgraph, HashMap<NodeId, NodeId>) {
+        let ids: HashMap<NodeId, NodeId> = self
+            .nodes
+            .iter()
+            .map(|(node_id, _)| (*node_id, indradb::util::generate_uuid_v1()))
+            .collect();
+        let mut edge_ids: HashMap<EdgeId, EdgeId> = HashMap::new();
+
+        let nodes = self
+            .nodes
+            .into_iter()
+            .map(|(node_id, properties)| (ids[&node_id], properties))
+            .collect();
+
+        let edges = self
+            .edges
+            .into_iter()
+            .filter_map(|(edge, properties)| {
+                let edge = Edge {
+                    id: *edge_ids
+                        .entry(edge.id)
+                        .or_insert_with(indradb::util::generate_uuid_v1),
+                    from: *ids.get(&edge.from)?,
+                    to: *ids.get(&edge.to)?,
+                };
+                Some((edge, properties))
+            })
+            .collect();
+
+        (Subgraph { nodes, edges }, ids)
+    }
+
+    // the mutations creating the content in a graph, nodes first
+    pub fn into_mutations(self) -> Vec<MutateKind> {
+        self.nodes
+            .into_iter()
+            .map(MutateKind::CreateNodeWithId)
+            .chain(self.edges.into_iter().map(MutateKind::RecreateEdge))
+            .collect()
+    }
+
+    pub fn contains_node(&self, node_id: NodeId) -> bool {
+        self.nodes.iter().any(|(id, _)| *id == node_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Edge {
     pub id: EdgeId, // EdgeType
     pub from: NodeId,
     pub to: NodeId,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CreateEdge {
     pub from: NodeId,
     pub to: NodeId,
@@ -110,7 +346,7 @@ impl From<Edge> for EdgeKey {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Reply {
     Id(Uuid),
     NodeList(Vec<(NodeId, Properties)>),
@@ -118,6 +354,12 @@ pub enum Reply {
     Edge(Edge),
     Graph(Graph),
     Properties(Properties),
+    Subgraph(Subgraph),
+    Hash(String),
+    Diff(Diff),
+    IdMap(HashMap<Uuid, Uuid>), // old id -> new id
+    Batch(Vec<Reply>),          // one reply per mutation
+    Seq(Option<u64>),
     Empty,
 }
 
@@ -143,6 +385,27 @@ impl Reply {
         }
     }
 
+    pub fn into_properties(self) -> Option<Properties> {
+        match self {
+            Reply::Properties(properties) => Some(properties),
+            _ => None,
+        }
+    }
+
+    pub fn into_subgraph(self) -> Option<Subgraph> {
+        match self {
+            Reply::Subgraph(subgraph) => Some(subgraph),
+            _ => None,
+        }
+    }
+
+    pub fn into_id_map(self) -> Option<HashMap<Uuid, Uuid>> {
+        match self {
+            Reply::IdMap(ids) => Some(ids),
+            _ => None,
+        }
+    }
+
     pub fn as_id(&self) -> Option<Uuid> {
         match self {
             Reply::Id(id) => Some(*id),
@@ -1,12 +1,20 @@
 use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
 pub use crate::error::{Error, Result};
 
+use crate::diff::{self, Diff};
+use crate::hash::{self, HashCache};
+use crate::history::History;
 use crate::msg::{
-    Action, CreateEdge, Edge, EdgeId, Graph, GraphId, MutateKind, Node, NodeId, Properties,
-    QueryKind, RecreateNode, Reply,
+    Action, ActionId, CreateEdge, Edge, EdgeId, Graph, GraphId, MutateKind, Node, NodeId,
+    Properties, QueryKind, RecreateGraph, RecreateNode, Reply, Subgraph,
 };
+use crate::template::{self, TEMPLATE_NAME_PROPERTY};
+use crate::timeline::Timeline;
+use crate::version;
 
 #[derive(Debug)]
 pub enum Operation {
@@ -21,13 +29,57 @@ pub trait Datastore: Send + Sync {
 
     fn redo_buf(&mut self) -> &mut Vec<Action>;
 
-    fn history_buf(&mut self) -> &mut Vec<Action>;
+    fn history(&self) -> &History;
+
+    fn history_buf(&mut self) -> &mut History;
+
+    fn timeline(&self) -> &Timeline;
+
+    fn timeline_buf(&mut self) -> &mut Timeline;
+
+    // stores without one compute the hash on every read
+    fn hash_cache(&self) -> Option<&HashCache> {
+        None
+    }
+
+    // the oldest reverse actions of undo and redo are dropped past this, unbounded by default
+    fn undo_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    // stores keeping the history next to the data save it after every action
+    // and load it when opened, so ids and the applied set survive a restart
+    async fn save_history(&mut self) -> Result<()> {
+        Ok(())
+    }
 
     async fn execute(&mut self, msg: Action) -> Result<Reply> {
-        self.execute_impl(msg, Operation::Other).await
+        let action_id = self.history_buf().next_id();
+        self.execute_with_id(action_id, msg).await
     }
 
-    async fn execute_impl(&mut self, msg: Action, operation: Operation) -> Result<Reply> {
+    // executes an action coming from elsewhere, an id that was already applied is skipped
+    // and answered with its first reply, so retries and replays are safe,
+    // queries aren't recorded and run again
+    async fn execute_with_id(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        if let Action::Query(_) = msg {
+            return self.execute_impl(action_id, msg, Operation::Other).await;
+        }
+        if let Some(reply) = self.history().seen(action_id)? {
+            return Ok(reply.clone());
+        }
+
+        let reply = self.execute_impl(action_id, msg, Operation::Other).await?;
+        self.save_history().await?;
+        Ok(reply)
+    }
+
+    async fn execute_impl(
+        &mut self,
+        action_id: ActionId,
+        msg: Action,
+        operation: Operation,
+    ) -> Result<Reply> {
         let (reverse_msg, reply) = match msg.clone() {
             Action::CreateGraph(properties) => self
                 .create_graph(properties)
@@ -37,21 +89,50 @@ pub trait Datastore: Send + Sync {
                 .create_graph_with_id(uuid, properties)
                 .await
                 .map(|(reverse_msg, node)| (Some(reverse_msg), Reply::Id(node)))?,
-            Action::Mutate(uuid, mutate_state) => self
-                .execute_mutate_state((uuid, mutate_state))
-                .await
-                .map(|(reverse_msg, reply)| (Some(reverse_msg), reply))?,
+            Action::Mutate(uuid, mutate_state) => {
+                let (reverse_msg, reply, state_id) =
+                    self.execute_mutate_state((uuid, mutate_state)).await?;
+                self.record_state(uuid, state_id, &reverse_msg).await?;
+                (Some(reverse_msg), reply)
+            }
             Action::Query(read_only) => (None, self.execute_read_only(read_only).await?),
-            Action::DeleteGraph(_) => todo!(),
+            Action::CreateTemplate(name, subgraph) => self
+                .create_template(name, subgraph)
+                .await
+                .map(|(reverse_msg, template_id)| (Some(reverse_msg), Reply::Id(template_id)))?,
+            Action::CreateTemplateWithId(template_id, name, subgraph) => self
+                .create_template_with_id(template_id, name, subgraph)
+                .await
+                .map(|reverse_msg| (Some(reverse_msg), Reply::Id(template_id)))?,
+            Action::CreateVersion(graph_id, name) => self
+                .create_version(graph_id, name)
+                .await
+                .map(|(reverse_msg, version_id)| (Some(reverse_msg), Reply::Id(version_id)))?,
+            Action::CreateVersionWithId(version_id, graph_id, name) => self
+                .create_version_with_id(version_id, graph_id, name)
+                .await
+                .map(|reverse_msg| (Some(reverse_msg), Reply::Id(version_id)))?,
+            Action::DeleteGraph(graph_id) => self
+                .delete_graph(graph_id)
+                .await
+                .map(|reverse_msg| (Some(reverse_msg), Reply::Empty))?,
+            Action::RecreateGraph(recreate_graph) => {
+                let graph_id = recreate_graph.graph_id;
+                self.recreate_graph(recreate_graph)
+                    .await
+                    .map(|reverse_msg| (Some(reverse_msg), Reply::Id(graph_id)))?
+            }
             Action::Undo => {
                 let reverse_msg = self.undo_buf().pop().ok_or(Error::UndoBufferEmpty)?;
-                self.execute_impl(reverse_msg, Operation::Undo)
+                let reverse_id = self.history_buf().next_id();
+                self.execute_impl(reverse_id, reverse_msg, Operation::Undo)
                     .await
                     .map(|reply| (None, reply))?
             }
             Action::Redo => {
                 let reverse_msg = self.redo_buf().pop().ok_or(Error::RedoBufferEmpty)?;
-                self.execute_impl(reverse_msg, Operation::Redo)
+                let reverse_id = self.history_buf().next_id();
+                self.execute_impl(reverse_id, reverse_msg, Operation::Redo)
                     .await
                     .map(|reply| (None, reply))?
             }
@@ -66,17 +147,131 @@ pub trait Datastore: Send + Sync {
                 Operation::Redo => self.undo_buf().push(reverse_msg),
                 Operation::Undo => self.redo_buf().push(reverse_msg),
             }
+
+            if let Some(capacity) = self.undo_capacity() {
+                let undo_buf = self.undo_buf();
+                undo_buf.drain(..undo_buf.len().saturating_sub(capacity));
+                let redo_buf = self.redo_buf();
+                redo_buf.drain(..redo_buf.len().saturating_sub(capacity));
+            }
+        }
+
+        if let Action::Query(_) = msg {
+            return Ok(reply);
         }
 
-        self.history_buf().push(msg);
+        let resolved_msg = self.resolve_action(msg, &reply).await?;
+        log::debug!(
+            "{}/{} {}",
+            action_id.client_id,
+            action_id.seq,
+            resolved_msg.name()
+        );
+        self.history_buf()
+            .push(action_id, resolved_msg, reply.clone());
 
         Ok(reply)
     }
 
-    async fn execute_mutate_state(&self, msg: (Uuid, MutateKind)) -> Result<(Action, Reply)> {
+    // fills the generated ids of an executed action in from its reply,
+    // so replaying the history on another store recreates the same ids
+    async fn resolve_action(&self, msg: Action, reply: &Reply) -> Result<Action> {
+        let resolved_msg = match (msg, reply) {
+            (Action::CreateGraph(properties), Reply::Id(graph_id)) => {
+                Action::CreateGraphWithId(*graph_id, properties)
+            }
+            (Action::Mutate(graph_id, kind), reply) => {
+                Action::Mutate(graph_id, self.resolve_kind(kind, reply).await?)
+            }
+            // the content was created under fresh ids, the template holds the ones used
+            (Action::CreateTemplate(name, _), Reply::Id(template_id)) => {
+                let (_, subgraph) = self.read_graph_snapshot(*template_id).await?;
+                Action::CreateTemplateWithId(*template_id, name, subgraph)
+            }
+            (Action::CreateVersion(graph_id, name), Reply::Id(version_id)) => {
+                Action::CreateVersionWithId(*version_id, graph_id, name)
+            }
+            (msg, _) => msg,
+        };
+
+        Ok(resolved_msg)
+    }
+
+    async fn resolve_kind(&self, kind: MutateKind, reply: &Reply) -> Result<MutateKind> {
+        let resolved_kind = match (kind, reply) {
+            (MutateKind::CreateNode(properties), Reply::Id(node_id)) => {
+                MutateKind::CreateNodeWithId((*node_id, properties))
+            }
+            (MutateKind::CreateEdge(create_edge), Reply::Id(edge_id)) => {
+                let edge = Edge {
+                    id: *edge_id,
+                    from: create_edge.from,
+                    to: create_edge.to,
+                };
+                MutateKind::RecreateEdge((edge, create_edge.properties))
+            }
+            (
+                MutateKind::PasteSubgraph(_) | MutateKind::InstantiateTemplate(_),
+                Reply::IdMap(ids),
+            ) => {
+                let pasted = self.copy_subgraph(ids.values().copied().collect()).await?;
+                let kinds = pasted
+                    .nodes
+                    .into_iter()
+                    .map(MutateKind::CreateNodeWithId)
+                    .chain(pasted.edges.into_iter().map(MutateKind::RecreateEdge))
+                    .collect();
+                MutateKind::Batch(kinds)
+            }
+            (MutateKind::Batch(kinds), Reply::Batch(replies)) => {
+                let mut resolved_kinds = Vec::with_capacity(kinds.len());
+                for (kind, reply) in kinds.into_iter().zip(replies) {
+                    resolved_kinds.push(self.resolve_kind(kind, reply).await?);
+                }
+                MutateKind::Batch(resolved_kinds)
+            }
+            (kind, _) => kind,
+        };
+
+        Ok(resolved_kind)
+    }
+
+    // returns the new state id of the graph along with the reverse and the reply
+    async fn execute_mutate_state(&self, msg: (Uuid, MutateKind)) -> Result<(Action, Reply, u64)> {
         // let MutateState { kind, graph_id } = msg;
         let (graph_id, kind) = msg;
 
+        let (undo_msg, reply) = self.execute_mutate_kind((graph_id, kind)).await?;
+
+        let state_id = self.update_state_id(graph_id).await?;
+
+        Ok((undo_msg, reply, state_id))
+    }
+
+    async fn record_state(
+        &mut self,
+        graph_id: GraphId,
+        state_id: u64,
+        reverse_msg: &Action,
+    ) -> Result<()> {
+        if let Action::Mutate(_, reverse_kind) = reverse_msg {
+            self.timeline_buf()
+                .record(graph_id, state_id, reverse_kind.clone());
+        }
+
+        if Timeline::needs_checkpoint(state_id) {
+            let (_, snapshot) = self.read_graph_snapshot(graph_id).await?;
+            self.timeline_buf().checkpoint(graph_id, state_id, snapshot);
+        }
+
+        Ok(())
+    }
+
+    // applies a mutation without touching the state id of the graph
+    async fn execute_mutate_kind(
+        &self,
+        (graph_id, kind): (GraphId, MutateKind),
+    ) -> Result<(Action, Reply)> {
         let (undo_msg, reply) = match kind {
             MutateKind::CreateNode(properties) => self
                 .create_node((graph_id, properties))
@@ -112,25 +307,153 @@ pub trait Datastore: Send + Sync {
                 .delete_edge(edge, graph_id)
                 .await
                 .map(|undo_msg| (undo_msg, Reply::Empty))?,
+            MutateKind::RecreateEdge((edge, properties)) => {
+                self.recreate_edge(edge, properties).await.map(|()| {
+                    (
+                        Action::Mutate(graph_id, MutateKind::DeleteEdge(edge)),
+                        Reply::Empty,
+                    )
+                })?
+            }
+            MutateKind::PasteSubgraph(subgraph) => self
+                .paste_subgraph(subgraph, graph_id)
+                .await
+                .map(|(undo_msg, ids)| (undo_msg, Reply::IdMap(ids)))?,
+            MutateKind::InstantiateTemplate((template_id, params)) => self
+                .instantiate_template(template_id, params, graph_id)
+                .await
+                .map(|(undo_msg, ids)| (undo_msg, Reply::IdMap(ids)))?,
+            MutateKind::RestoreVersion(name) => self
+                .restore_version(name, graph_id)
+                .await
+                .map(|undo_msg| (undo_msg, Reply::Empty))?,
+            MutateKind::Batch(kinds) => self
+                .execute_batch_with_replies(kinds, graph_id)
+                .await
+                .map(|(undo_msg, replies)| (undo_msg, Reply::Batch(replies)))?,
         };
 
-        self.update_state_id(graph_id).await?;
-
         Ok((undo_msg, reply))
     }
 
+    // applies all mutations or none of them, the reverse is a single batch
+    async fn execute_batch(&self, kinds: Vec<MutateKind>, graph_id: GraphId) -> Result<Action> {
+        self.execute_batch_with_replies(kinds, graph_id)
+            .await
+            .map(|(reverse_msg, _)| reverse_msg)
+    }
+
+    async fn execute_batch_with_replies(
+        &self,
+        kinds: Vec<MutateKind>,
+        graph_id: GraphId,
+    ) -> Result<(Action, Vec<Reply>)> {
+        let mut reverse_kinds = Vec::with_capacity(kinds.len());
+        let mut replies = Vec::with_capacity(kinds.len());
+
+        for kind in kinds {
+            let result = match self.execute_mutate_kind((graph_id, kind)).await {
+                Ok((Action::Mutate(_, reverse_kind), reply)) => Ok((reverse_kind, reply)),
+                Ok((reverse_msg, _)) => Err(Error::InvalidReverse(Box::new(reverse_msg))),
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok((reverse_kind, reply)) => {
+                    reverse_kinds.push(reverse_kind);
+                    replies.push(reply);
+                }
+                Err(err) => {
+                    let mut failures = Vec::new();
+                    for reverse_kind in reverse_kinds.into_iter().rev() {
+                        if let Err(failure) =
+                            self.execute_mutate_kind((graph_id, reverse_kind)).await
+                        {
+                            failures.push(failure);
+                        }
+                    }
+
+                    return Err(rollback_error(err, failures));
+                }
+            }
+        }
+
+        reverse_kinds.reverse();
+
+        Ok((
+            Action::Mutate(graph_id, MutateKind::Batch(reverse_kinds)),
+            replies,
+        ))
+    }
+
     async fn execute_read_only(&self, msg: QueryKind) -> Result<Reply> {
         match msg {
             QueryKind::ReadEdgeProperties(msg) => {
                 self.read_edge_properties(msg).await.map(Reply::Properties)
             }
             QueryKind::ReadNode(msg) => self.read_node(msg).await.map(Reply::Node),
-            QueryKind::ReadGraph(read_graph) => self.read_graph(read_graph).await.map(Reply::Graph),
-            QueryKind::ListGraphs => self.list_graphs().await.map(Reply::NodeList),
+            QueryKind::ReadGraph(read_graph) => self
+                .read_graph_with_hash(read_graph)
+                .await
+                .map(Reply::Graph),
+            QueryKind::ListGraphs => self
+                .list_graphs()
+                .await
+                .map(|graphs| {
+                    filter_graphs(graphs, |props| {
+                        !template::is_template(props) && !version::is_version(props)
+                    })
+                })
+                .map(Reply::NodeList),
+            QueryKind::CopySubgraph(node_ids) => {
+                self.copy_subgraph(node_ids).await.map(Reply::Subgraph)
+            }
+            QueryKind::ListTemplates => self
+                .list_graphs()
+                .await
+                .map(|graphs| filter_graphs(graphs, template::is_template))
+                .map(Reply::NodeList),
+            QueryKind::ListVersions(graph_id) => self
+                .list_versions(graph_id)
+                .await
+                .map(|versions| {
+                    versions
+                        .into_iter()
+                        .map(|(id, props)| (id, version::without_snapshot(props)))
+                        .collect()
+                })
+                .map(Reply::NodeList),
+            QueryKind::ReadGraphVersion((graph_id, name)) => self
+                .read_version(graph_id, &name)
+                .await
+                .map(|(state_id, snapshot)| Reply::Graph(snapshot.into_graph(state_id))),
+            QueryKind::ReadGraphHash(graph_id) => {
+                self.read_graph_hash(graph_id).await.map(Reply::Hash)
+            }
+            QueryKind::LastApplied(client_id) => Ok(Reply::Seq(self.history().last_seq(client_id))),
+            QueryKind::ReadGraphAt { graph_id, state_id } => self
+                .read_graph_at(graph_id, state_id)
+                .await
+                .map(Reply::Graph),
+            QueryKind::DiffGraphAt {
+                graph_id,
+                from_state_id,
+                to_state_id,
+            } => self
+                .diff_graph_at(graph_id, from_state_id, to_state_id)
+                .await
+                .map(Reply::Diff),
         }
     }
 
-    async fn update_state_id(&self, graph_id: GraphId) -> Result<()>;
+    // increments the state id of the graph and returns the new value
+    async fn update_state_id(&self, graph_id: GraphId) -> Result<u64>;
+
+    async fn set_state_id(&self, graph_id: GraphId, state_id: u64) -> Result<()>;
+
+    async fn read_state_id(&self, graph_id: GraphId) -> Result<u64> {
+        self.read_graph(graph_id).await.map(|graph| graph.state_id)
+    }
 
     async fn create_graph(&self, properties: Properties) -> Result<(Action, GraphId)> {
         self.create_graph_with_id(indradb::util::generate_uuid_v1(), properties)
@@ -143,6 +466,114 @@ pub trait Datastore: Send + Sync {
         properties: Properties,
     ) -> Result<(Action, GraphId)>;
 
+    // deletes the graph with its nodes and versions, all of it or nothing,
+    // the timeline of the graph is kept so its past states read the same once undone
+    async fn delete_graph(&self, graph_id: GraphId) -> Result<Action> {
+        let properties = self.read_node(graph_id).await?.properties;
+        let (state_id, subgraph) = self.read_graph_snapshot(graph_id).await?;
+        let versions = self.list_versions(graph_id).await?;
+
+        let delete_nodes = subgraph
+            .nodes
+            .iter()
+            .map(|(node_id, _)| MutateKind::DeleteNode(*node_id))
+            .collect();
+        let restore_nodes = self.execute_batch(delete_nodes, graph_id).await?;
+
+        // versions are bare roots, the graph root goes last
+        let roots = versions
+            .iter()
+            .map(|(version_id, _)| *version_id)
+            .chain(std::iter::once(graph_id));
+        let mut deleted = Vec::new();
+        for root_id in roots {
+            if let Err(error) = self.delete_node(root_id, root_id).await {
+                let mut failures = Vec::new();
+                for (version_id, properties) in
+                    versions.iter().filter(|(id, _)| deleted.contains(id))
+                {
+                    if let Err(failure) = self
+                        .create_graph_with_id(*version_id, properties.clone())
+                        .await
+                    {
+                        failures.push(failure);
+                    }
+                }
+                if let Action::Mutate(_, restore_nodes) = restore_nodes {
+                    if let Err(failure) = self.execute_mutate_kind((graph_id, restore_nodes)).await
+                    {
+                        failures.push(failure);
+                    }
+                }
+
+                return Err(rollback_error(error, failures));
+            }
+            deleted.push(root_id);
+        }
+        if let Some(cache) = self.hash_cache() {
+            cache.remove(graph_id);
+        }
+
+        Ok(Action::RecreateGraph(RecreateGraph {
+            graph_id,
+            properties,
+            subgraph,
+            state_id,
+            versions,
+        }))
+    }
+
+    async fn recreate_graph(&self, recreate_graph: RecreateGraph) -> Result<Action> {
+        let RecreateGraph {
+            graph_id,
+            properties,
+            subgraph,
+            state_id,
+            versions,
+        } = recreate_graph;
+
+        self.create_graph_with_content(graph_id, properties, subgraph, state_id)
+            .await?;
+
+        for (version_id, properties) in versions {
+            if let Err(error) = self.create_graph_with_id(version_id, properties).await {
+                // takes the versions created so far along
+                let failures = self.delete_graph(graph_id).await.err();
+                return Err(rollback_error(error, failures.into_iter().collect()));
+            }
+        }
+
+        Ok(Action::DeleteGraph(graph_id))
+    }
+
+    // creates a graph already holding the nodes and edges, at the given state id,
+    // nothing is left behind when it fails
+    async fn create_graph_with_content(
+        &self,
+        graph_id: GraphId,
+        properties: Properties,
+        subgraph: Subgraph,
+        state_id: u64,
+    ) -> Result<()> {
+        self.create_graph_with_id(graph_id, properties).await?;
+
+        let result = match self.set_state_id(graph_id, state_id).await {
+            // the batch takes its own nodes back when it fails
+            Ok(()) => self
+                .execute_batch(subgraph.into_mutations(), graph_id)
+                .await
+                .map(|_| ()),
+            Err(error) => Err(error),
+        };
+
+        if let Err(error) = result {
+            let failures = self.delete_node(graph_id, graph_id).await.err();
+            return Err(rollback_error(error, failures.into_iter().collect()));
+        }
+
+        Ok(())
+    }
+
     async fn list_graphs(&self) -> Result<Vec<(NodeId, Properties)>>;
 
     async fn read_graph(&self, graph_id: GraphId) -> Result<Graph>;
@@ -180,4 +611,246 @@ pub trait Datastore: Send + Sync {
     async fn update_edge(&self, args: (Edge, Properties), graph_id: GraphId) -> Result<Action>;
 
     async fn delete_edge(&self, edge: Edge, graph_id: GraphId) -> Result<Action>;
+
+    // edges leaving the selection are not copied
+    async fn copy_subgraph(&self, node_ids: Vec<NodeId>) -> Result<Subgraph> {
+        let selected: HashSet<NodeId> = node_ids.iter().copied().collect();
+
+        let nodes =
+            futures::future::try_join_all(node_ids.into_iter().map(|id| self.read_node(id)))
+                .await?;
+
+        let edges = nodes
+            .iter()
+            .flat_map(|node| node.outbound_edges.iter().copied())
+            .filter(|edge| selected.contains(&edge.to))
+            .map(|edge| async move {
+                self.read_edge_properties(edge)
+                    .await
+                    .map(|props| (edge, props))
+            });
+
+        let edges = futures::future::try_join_all(edges).await?;
+
+        let nodes = nodes
+            .into_iter()
+            .map(|node| (node.node_id, node.properties))
+            .collect();
+
+        Ok(Subgraph { nodes, edges })
+    }
+
+    // creates the nodes and edges with fresh ids, returns the old id -> new id mapping
+    async fn paste_subgraph(
+        &self,
+        subgraph: Subgraph,
+        graph_id: GraphId,
+    ) -> Result<(Action, HashMap<NodeId, NodeId>)> {
+        let (subgraph, ids) = subgraph.with_fresh_ids();
+
+        let undo_msg = self
+            .execute_batch(subgraph.into_mutations(), graph_id)
+            .await?;
+
+        Ok((undo_msg, ids))
+    }
+
+    // stores the subgraph under fresh ids as a new graph marked as a template
+    async fn create_template(&self, name: String, subgraph: Subgraph) -> Result<(Action, GraphId)> {
+        let template_id = indradb::util::generate_uuid_v1();
+        let (subgraph, _) = subgraph.with_fresh_ids();
+
+        self.create_template_with_id(template_id, name, subgraph)
+            .await
+            .map(|reverse_msg| (reverse_msg, template_id))
+    }
+
+    // the template is created along with its content, which counts as its first state
+    async fn create_template_with_id(
+        &self,
+        template_id: GraphId,
+        name: String,
+        subgraph: Subgraph,
+    ) -> Result<Action> {
+        let mut properties = Properties::new();
+        properties.insert(TEMPLATE_NAME_PROPERTY.into(), JsonValue::String(name));
+
+        self.create_graph_with_content(template_id, properties, subgraph, 1)
+            .await?;
+
+        Ok(Action::DeleteGraph(template_id))
+    }
+
+    // pastes the template nodes and edges with placeholders replaced by params
+    async fn instantiate_template(
+        &self,
+        template_id: GraphId,
+        params: Properties,
+        graph_id: GraphId,
+    ) -> Result<(Action, HashMap<NodeId, NodeId>)> {
+        let node_ids = self
+            .read_graph(template_id)
+            .await?
+            .nodes
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+
+        let Subgraph { nodes, edges } = self.copy_subgraph(node_ids).await?;
+
+        let subgraph = Subgraph {
+            nodes: nodes
+                .into_iter()
+                .map(|(node_id, props)| Ok((node_id, template::substitute(props, &params)?)))
+                .collect::<Result<_>>()?,
+            edges: edges
+                .into_iter()
+                .map(|(edge, props)| Ok((edge, template::substitute(props, &params)?)))
+                .collect::<Result<_>>()?,
+        };
+
+        self.paste_subgraph(subgraph, graph_id).await
+    }
+
+    async fn read_graph_with_hash(&self, graph_id: GraphId) -> Result<Graph> {
+        let mut graph = self.read_graph(graph_id).await?;
+        graph.hash = Some(self.hash_graph(graph_id, &graph).await?);
+
+        Ok(graph)
+    }
+
+    // the edges are only read when the state wasn't hashed before
+    async fn hash_graph(&self, graph_id: GraphId, graph: &Graph) -> Result<String> {
+        if let Some(hash) = self
+            .hash_cache()
+            .and_then(|cache| cache.get(graph_id, graph.state_id))
+        {
+            return Ok(hash);
+        }
+
+        let node_ids = graph.nodes.iter().map(|node| node.node_id).collect();
+        let hash = hash::content_hash(&self.copy_subgraph(node_ids).await?);
+        if let Some(cache) = self.hash_cache() {
+            cache.insert(graph_id, graph.state_id, hash.clone());
+        }
+
+        Ok(hash)
+    }
+
+    async fn read_graph_hash(&self, graph_id: GraphId) -> Result<String> {
+        let graph = self.read_graph(graph_id).await?;
+        self.hash_graph(graph_id, &graph).await
+    }
+
+    // every node of the graph with the edges among them
+    async fn read_graph_snapshot(&self, graph_id: GraphId) -> Result<(u64, Subgraph)> {
+        let graph = self.read_graph(graph_id).await?;
+        let node_ids = graph.nodes.iter().map(|node| node.node_id).collect();
+
+        Ok((graph.state_id, self.copy_subgraph(node_ids).await?))
+    }
+
+    async fn read_graph_at(&self, graph_id: GraphId, state_id: u64) -> Result<Graph> {
+        self.read_snapshot_at(graph_id, state_id)
+            .await
+            .map(|snapshot| snapshot.into_graph(state_id))
+    }
+
+    // starts from the closest checkpoint, or the current state, and replays reverses
+    async fn read_snapshot_at(&self, graph_id: GraphId, state_id: u64) -> Result<Subgraph> {
+        let start = match self.timeline().checkpoint_after(graph_id, state_id) {
+            Some((checkpoint_id, snapshot)) => (checkpoint_id, snapshot.clone()),
+            None => self.read_graph_snapshot(graph_id).await?,
+        };
+
+        self.timeline().rewind(graph_id, start, state_id)
+    }
+
+    async fn diff_graph_at(
+        &self,
+        graph_id: GraphId,
+        from_state_id: u64,
+        to_state_id: u64,
+    ) -> Result<Diff> {
+        let from = self.read_snapshot_at(graph_id, from_state_id).await?;
+        let to = self.read_snapshot_at(graph_id, to_state_id).await?;
+
+        Ok(diff::diff(&from, &to))
+    }
+
+    async fn list_versions(&self, graph_id: GraphId) -> Result<Vec<(GraphId, Properties)>> {
+        Ok(filter_graphs(self.list_graphs().await?, |props| {
+            version::is_version_of(props, graph_id)
+        }))
+    }
+
+    async fn read_version(&self, graph_id: GraphId, name: &str) -> Result<(u64, Subgraph)> {
+        let versions = self.list_versions(graph_id).await?;
+        let (_, properties) = versions
+            .iter()
+            .find(|(_, props)| version::version_name(props) == Some(name))
+            .ok_or(Error::VersionNotFound)?;
+
+        version::read_snapshot(properties)
+    }
+
+    async fn create_version(&self, graph_id: GraphId, name: String) -> Result<(Action, GraphId)> {
+        let version_id = indradb::util::generate_uuid_v1();
+
+        self.create_version_with_id(version_id, graph_id, name)
+            .await
+            .map(|reverse_msg| (reverse_msg, version_id))
+    }
+
+    // stores a snapshot of the graph under the name, the snapshot lives in the
+    // properties of the version root so its creation is a single write
+    async fn create_version_with_id(
+        &self,
+        version_id: GraphId,
+        graph_id: GraphId,
+        name: String,
+    ) -> Result<Action> {
+        let versions = self.list_versions(graph_id).await?;
+        if versions
+            .iter()
+            .any(|(_, props)| version::version_name(props) == Some(name.as_str()))
+        {
+            return Err(Error::VersionAlreadyExists(name));
+        }
+
+        let (state_id, snapshot) = self.read_graph_snapshot(graph_id).await?;
+        let properties = version::version_properties(graph_id, name, state_id, &snapshot)?;
+
+        self.create_graph_with_id(version_id, properties)
+            .await
+            .map(|(reverse_msg, _)| reverse_msg)
+    }
+
+    // applies the difference between the graph and the version as one batch
+    async fn restore_version(&self, name: String, graph_id: GraphId) -> Result<Action> {
+        let (_, snapshot) = self.read_version(graph_id, &name).await?;
+        let (_, current) = self.read_graph_snapshot(graph_id).await?;
+
+        self.execute_batch(diff::diff(&current, &snapshot).mutations, graph_id)
+            .await
+    }
+}
+
+// the error that stopped an operation, along with whatever failed while undoing its steps
+fn rollback_error(error: Error, failures: Vec<Error>) -> Error {
+    if failures.is_empty() {
+        error
+    } else {
+        Error::RollbackFailed(Box::new(error), failures)
+    }
+}
+
+fn filter_graphs<F: Fn(&Properties) -> bool>(
+    graphs: Vec<(NodeId, Properties)>,
+    keep: F,
+) -> Vec<(NodeId, Properties)> {
+    graphs
+        .into_iter()
+        .filter(|(_, props)| keep(props))
+        .collect()
 }
@@ -0,0 +1,109 @@
+use serde_json::Value as JsonValue;
+
+use crate::error::{Error, Result};
+use crate::msg::Properties;
+
+// templates are graphs whose root carries this property
+pub const TEMPLATE_NAME_PROPERTY: &str = "_template_name";
+
+const PLACEHOLDER_START: &str = "{{";
+const PLACEHOLDER_END: &str = "}}";
+
+pub fn is_template(properties: &Properties) -> bool {
+    properties.contains_key(TEMPLATE_NAME_PROPERTY)
+}
+
+// replaces "{{name}}" placeholders in string values with the matching parameter,
+// a value that is only a placeholder takes the parameter as is (numbers stay numbers)
+pub fn substitute(properties: Properties, params: &Properties) -> Result<Properties> {
+    properties
+        .into_iter()
+        .map(|(key, value)| Ok((key, substitute_value(value, params)?)))
+        .collect()
+}
+
+fn substitute_value(value: JsonValue, params: &Properties) -> Result<JsonValue> {
+    match value {
+        JsonValue::String(text) => substitute_text(text, params),
+        JsonValue::Array(values) => values
+            .into_iter()
+            .map(|value| substitute_value(value, params))
+            .collect::<Result<Vec<_>>>()
+            .map(JsonValue::Array),
+        value => Ok(value),
+    }
+}
+
+fn substitute_text(text: String, params: &Properties) -> Result<JsonValue> {
+    let mut out = String::new();
+    let mut rest = text.as_str();
+
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        let after_start = &rest[start + PLACEHOLDER_START.len()..];
+        let end = match after_start.find(PLACEHOLDER_END) {
+            Some(end) => end,
+            None => break,
+        };
+
+        let name = after_start[..end].trim();
+        let param = params
+            .get(name)
+            .ok_or_else(|| Error::MissingTemplateParameter(name.into()))?;
+
+        let is_whole_text = start == 0 && end + PLACEHOLDER_END.len() == after_start.len();
+        if is_whole_text {
+            return Ok(param.clone());
+        }
+
+        out.push_str(&rest[..start]);
+        match param {
+            JsonValue::String(param) => out.push_str(param),
+            param => out.push_str(&param.to_string()),
+        }
+        rest = &after_start[end + PLACEHOLDER_END.len()..];
+    }
+
+    out.push_str(rest);
+
+    Ok(JsonValue::String(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::props;
+    use serde_json::json;
+
+    #[test]
+    fn test_substitute() {
+        let params = props(json!({ "name": "alice", "age": 30 }));
+        let properties = props(json!({
+            "title": "hello {{name}}, you are {{ age }}",
+            "age": "{{age}}",
+            "tags": ["{{name}}", "fixed"],
+            "count": 2,
+            "unclosed": "{{name",
+        }));
+
+        assert_eq!(
+            substitute(properties, &params).unwrap(),
+            props(json!({
+                "title": "hello alice, you are 30",
+                "age": 30,
+                "tags": ["alice", "fixed"],
+                "count": 2,
+                "unclosed": "{{name",
+            }))
+        );
+    }
+
+    #[test]
+    fn test_substitute_missing_parameter() {
+        let properties = props(json!({ "title": "{{missing}}" }));
+
+        assert!(matches!(
+            substitute(properties, &Properties::new()),
+            Err(Error::MissingTemplateParameter(name)) if name == "missing"
+        ));
+    }
+}
@@ -0,0 +1,11 @@
+use serde_json::Value as JsonValue;
+
+use crate::msg::Properties;
+
+// properties from a json object literal, e.g. props(json!({ "name": "a" }))
+pub fn props(value: JsonValue) -> Properties {
+    match value {
+        JsonValue::Object(props) => props,
+        _ => panic!("properties must be a json object, got {}", value),
+    }
+}
@@ -0,0 +1,379 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::error::{Error, Result};
+use crate::msg::{GraphId, MutateKind, Subgraph};
+
+// a snapshot of the graph is kept every this many states
+pub const CHECKPOINT_INTERVAL: u64 = 32;
+
+// past states kept per graph by default
+pub const RETAINED_STATES: u64 = 1024;
+
+// per graph record of the reverse of the latest mutations, used to rebuild past states,
+// it keeps the last `window` states of each graph, anything older is dropped as the graph
+// moves on and reading it gives StateNotFound, stores that persist it save what changed
+#[derive(Debug)]
+pub struct Timeline {
+    graphs: HashMap<GraphId, GraphTimeline>,
+    window: u64,
+    // what was replaced since `begin`, so a store whose transaction failed can put it back
+    journal: Option<Vec<Change>>,
+    // the states recorded, checkpointed or dropped since the last save
+    changed: BTreeSet<(GraphId, u64)>,
+}
+
+// what a state of a graph holds, a store saves one per state and removes it once both are gone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub graph_id: GraphId,
+    pub state_id: u64,
+    pub reverse: Option<MutateKind>,
+    pub checkpoint: Option<Subgraph>,
+}
+
+#[derive(Debug)]
+enum Change {
+    Reverse(GraphId, u64, Option<MutateKind>),
+    Checkpoint(GraphId, u64, Option<Subgraph>),
+}
+
+#[derive(Debug, Default)]
+struct GraphTimeline {
+    // reverse of the mutation that moved the graph to the state id
+    reverse: BTreeMap<u64, MutateKind>,
+    checkpoints: BTreeMap<u64, Subgraph>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::with_window(RETAINED_STATES)
+    }
+}
+
+impl Timeline {
+    pub fn with_window(window: u64) -> Self {
+        Timeline {
+            graphs: HashMap::new(),
+            window,
+            journal: None,
+            changed: BTreeSet::new(),
+        }
+    }
+
+    // the entries a store saved, as they were
+    pub fn load(&mut self, entries: Vec<TimelineEntry>) {
+        for entry in entries {
+            let timeline = self.graphs.entry(entry.graph_id).or_default();
+            if let Some(reverse) = entry.reverse {
+                timeline.reverse.insert(entry.state_id, reverse);
+            }
+            if let Some(snapshot) = entry.checkpoint {
+                timeline.checkpoints.insert(entry.state_id, snapshot);
+            }
+        }
+    }
+
+    // the states changed since the last save, an entry with neither part is to be removed
+    pub fn unsaved(&self) -> Vec<TimelineEntry> {
+        self.changed
+            .iter()
+            .map(|(graph_id, state_id)| {
+                let timeline = self.graphs.get(graph_id);
+                TimelineEntry {
+                    graph_id: *graph_id,
+                    state_id: *state_id,
+                    reverse: timeline.and_then(|t| t.reverse.get(state_id).cloned()),
+                    checkpoint: timeline.and_then(|t| t.checkpoints.get(state_id).cloned()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.changed.clear();
+    }
+
+    pub fn record(&mut self, graph_id: GraphId, state_id: u64, reverse: MutateKind) {
+        let replaced = self
+            .graphs
+            .entry(graph_id)
+            .or_default()
+            .reverse
+            .insert(state_id, reverse);
+        self.journal(Change::Reverse(graph_id, state_id, replaced));
+        self.trim(graph_id, state_id);
+    }
+
+    // the oldest state still readable is `window` states back
+    fn trim(&mut self, graph_id: GraphId, state_id: u64) {
+        let oldest = state_id.saturating_sub(self.window);
+        let timeline = self.graphs.entry(graph_id).or_default();
+
+        let kept = timeline.reverse.split_off(&(oldest + 1));
+        let dropped_reverses = std::mem::replace(&mut timeline.reverse, kept);
+        let kept = timeline.checkpoints.split_off(&oldest);
+        let dropped_checkpoints = std::mem::replace(&mut timeline.checkpoints, kept);
+
+        for (dropped_id, reverse) in dropped_reverses {
+            self.journal(Change::Reverse(graph_id, dropped_id, Some(reverse)));
+        }
+        for (dropped_id, snapshot) in dropped_checkpoints {
+            self.journal(Change::Checkpoint(graph_id, dropped_id, Some(snapshot)));
+        }
+    }
+
+    pub fn needs_checkpoint(state_id: u64) -> bool {
+        state_id % CHECKPOINT_INTERVAL == 0
+    }
+
+    pub fn checkpoint(&mut self, graph_id: GraphId, state_id: u64, snapshot: Subgraph) {
+        let replaced = self
+            .graphs
+            .entry(graph_id)
+            .or_default()
+            .checkpoints
+            .insert(state_id, snapshot);
+        self.journal(Change::Checkpoint(graph_id, state_id, replaced));
+    }
+
+    // starts keeping what is replaced, for `rollback`
+    pub fn begin(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    // puts back everything recorded since `begin`
+    pub fn rollback(&mut self) {
+        for change in self.journal.take().unwrap_or_default().into_iter().rev() {
+            match change {
+                Change::Reverse(graph_id, state_id, replaced) => {
+                    let reverse = &mut self.graphs.entry(graph_id).or_default().reverse;
+                    restore(reverse, state_id, replaced);
+                }
+                Change::Checkpoint(graph_id, state_id, replaced) => {
+                    let checkpoints = &mut self.graphs.entry(graph_id).or_default().checkpoints;
+                    restore(checkpoints, state_id, replaced);
+                }
+            }
+        }
+    }
+
+    // every change goes through here, it's kept for the next save whether journaled or not
+    fn journal(&mut self, change: Change) {
+        let (Change::Reverse(graph_id, state_id, _) | Change::Checkpoint(graph_id, state_id, _)) =
+            &change;
+        self.changed.insert((*graph_id, *state_id));
+        if let Some(journal) = &mut self.journal {
+            journal.push(change);
+        }
+    }
+
+    // the closest checkpoint at or after the state id
+    pub fn checkpoint_after(&self, graph_id: GraphId, state_id: u64) -> Option<(u64, &Subgraph)> {
+        self.graphs
+            .get(&graph_id)?
+            .checkpoints
+            .range(state_id..)
+            .next()
+            .map(|(checkpoint_id, snapshot)| (*checkpoint_id, snapshot))
+    }
+
+    // walks back from a snapshot taken at `from_state_id` to `state_id`
+    pub fn rewind(
+        &self,
+        graph_id: GraphId,
+        (from_state_id, mut snapshot): (u64, Subgraph),
+        state_id: u64,
+    ) -> Result<Subgraph> {
+        if state_id > from_state_id {
+            return Err(Error::StateNotFound(state_id));
+        }
+
+        let timeline = self
+            .graphs
+            .get(&graph_id)
+            .ok_or(Error::StateNotFound(state_id))?;
+
+        for current in (state_id + 1..=from_state_id).rev() {
+            let reverse = timeline
+                .reverse
+                .get(&current)
+                .ok_or(Error::StateNotFound(state_id))?;
+            snapshot.apply(reverse.clone())?;
+        }
+
+        Ok(snapshot)
+    }
+}
+
+fn restore<T>(map: &mut BTreeMap<u64, T>, state_id: u64, replaced: Option<T>) {
+    match replaced {
+        Some(value) => map.insert(state_id, value),
+        None => map.remove(&state_id),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{Edge, Properties};
+    use crate::test_utils::props;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_rewind() {
+        let graph_id = Uuid::new_v4();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let edge = Edge {
+            id: Uuid::new_v4(),
+            from: a,
+            to: b,
+        };
+
+        // 1: create a, 2: create b, 3: create edge, 4: update a
+        let mut timeline = Timeline::default();
+        timeline.record(graph_id, 1, MutateKind::DeleteNode(a));
+        timeline.record(graph_id, 2, MutateKind::DeleteNode(b));
+        timeline.record(graph_id, 3, MutateKind::DeleteEdge(edge));
+        timeline.record(
+            graph_id,
+            4,
+            MutateKind::UpdateNode((a, props(json!({ "name": "a" })))),
+        );
+
+        let current = Subgraph {
+            nodes: vec![
+                (a, props(json!({ "name": "updated" }))),
+                (b, props(json!({ "name": "b" }))),
+            ],
+            edges: vec![(edge, Properties::new())],
+        };
+
+        let at_3 = timeline.rewind(graph_id, (4, current.clone()), 3).unwrap();
+        assert_eq!(at_3.nodes[0].1, props(json!({ "name": "a" })));
+        assert_eq!(at_3.edges.len(), 1);
+
+        let at_1 = timeline.rewind(graph_id, (4, current.clone()), 1).unwrap();
+        assert_eq!(at_1.nodes.len(), 1);
+        assert!(at_1.edges.is_empty());
+
+        let at_2 = timeline.rewind(graph_id, (4, current.clone()), 2).unwrap();
+        timeline.checkpoint(graph_id, 2, at_2);
+        assert_eq!(timeline.checkpoint_after(graph_id, 1).unwrap().0, 2);
+        assert!(timeline.checkpoint_after(graph_id, 3).is_none());
+
+        assert!(matches!(
+            timeline.rewind(graph_id, (4, current.clone()), 5),
+            Err(Error::StateNotFound(5))
+        ));
+
+        // what was recorded in a failed transaction is put back
+        timeline.begin();
+        timeline.record(graph_id, 4, MutateKind::DeleteNode(a));
+        timeline.record(graph_id, 5, MutateKind::DeleteNode(b));
+        timeline.checkpoint(graph_id, 5, current.clone());
+        timeline.rollback();
+        assert!(timeline.checkpoint_after(graph_id, 3).is_none());
+        let at_3 = timeline.rewind(graph_id, (4, current), 3).unwrap();
+        assert_eq!(at_3.nodes[0].1, props(json!({ "name": "a" })));
+        assert!(timeline.rewind(graph_id, (5, at_3), 4).is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let graph_id = Uuid::new_v4();
+        let a = Uuid::new_v4();
+        let named = |count: u64| props(json!({ "count": count }));
+
+        // state n sets the count of a to n
+        let mut timeline = Timeline::with_window(40);
+        for state_id in 1..=100 {
+            timeline.record(
+                graph_id,
+                state_id,
+                MutateKind::UpdateNode((a, named(state_id - 1))),
+            );
+            if Timeline::needs_checkpoint(state_id) {
+                let snapshot = Subgraph {
+                    nodes: vec![(a, named(state_id))],
+                    edges: Vec::new(),
+                };
+                timeline.checkpoint(graph_id, state_id, snapshot);
+            }
+        }
+        let current = Subgraph {
+            nodes: vec![(a, named(100))],
+            edges: Vec::new(),
+        };
+
+        let at_60 = timeline
+            .rewind(graph_id, (100, current.clone()), 60)
+            .unwrap();
+        assert_eq!(at_60.nodes[0].1, named(60));
+        assert!(matches!(
+            timeline.rewind(graph_id, (100, current.clone()), 59),
+            Err(Error::StateNotFound(59))
+        ));
+        assert!(timeline.checkpoint_after(graph_id, 0).unwrap().0 >= 60);
+
+        // what a failed transaction trimmed comes back with the rollback
+        timeline.begin();
+        timeline.record(graph_id, 101, MutateKind::UpdateNode((a, named(100))));
+        assert!(timeline
+            .rewind(graph_id, (100, current.clone()), 60)
+            .is_err());
+        timeline.rollback();
+        assert!(timeline.rewind(graph_id, (100, current), 60).is_ok());
+    }
+
+    #[test]
+    fn test_saved_entries() {
+        let graph_id = Uuid::new_v4();
+        let a = Uuid::new_v4();
+        let named = |count: u64| props(json!({ "count": count }));
+
+        let mut timeline = Timeline::with_window(2);
+        let mut saved = HashMap::new();
+        let mut save = |timeline: &mut Timeline| {
+            for entry in timeline.unsaved() {
+                let key = (entry.graph_id, entry.state_id);
+                match (&entry.reverse, &entry.checkpoint) {
+                    (None, None) => saved.remove(&key),
+                    _ => saved.insert(key, entry),
+                };
+            }
+            timeline.mark_saved();
+            saved.values().cloned().collect::<Vec<_>>()
+        };
+
+        timeline.record(graph_id, 1, MutateKind::UpdateNode((a, named(0))));
+        timeline.record(graph_id, 2, MutateKind::UpdateNode((a, named(1))));
+        assert_eq!(timeline.unsaved().len(), 2);
+        save(&mut timeline);
+        assert!(timeline.unsaved().is_empty());
+
+        // the third state drops the first, which is saved as removed
+        timeline.record(graph_id, 3, MutateKind::UpdateNode((a, named(2))));
+        let unsaved = timeline.unsaved();
+        assert_eq!(unsaved.len(), 2);
+        assert!(unsaved[0].reverse.is_none() && unsaved[0].checkpoint.is_none());
+        let entries = save(&mut timeline);
+        assert_eq!(entries.len(), 2);
+
+        let mut loaded = Timeline::with_window(2);
+        loaded.load(entries);
+        let current = Subgraph {
+            nodes: vec![(a, named(3))],
+            edges: Vec::new(),
+        };
+        let at_1 = loaded.rewind(graph_id, (3, current.clone()), 1).unwrap();
+        assert_eq!(at_1.nodes[0].1, named(1));
+        assert!(loaded.rewind(graph_id, (3, current), 0).is_err());
+        assert!(loaded.unsaved().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use serde_json::Value as JsonValue;
+
+use crate::error::{Error, Result};
+use crate::msg::{GraphId, Properties, Subgraph};
+
+// versions are graphs whose root carries these properties
+pub const VERSION_OF_PROPERTY: &str = "_version_of";
+pub const VERSION_NAME_PROPERTY: &str = "_version_name";
+pub const VERSION_STATE_ID_PROPERTY: &str = "_version_state_id";
+pub const VERSION_SNAPSHOT_PROPERTY: &str = "_version_snapshot";
+
+pub fn is_version(properties: &Properties) -> bool {
+    properties.contains_key(VERSION_OF_PROPERTY)
+}
+
+pub fn is_version_of(properties: &Properties, graph_id: GraphId) -> bool {
+    properties
+        .get(VERSION_OF_PROPERTY)
+        .and_then(JsonValue::as_str)
+        == Some(graph_id.to_string().as_str())
+}
+
+pub fn version_name(properties: &Properties) -> Option<&str> {
+    properties
+        .get(VERSION_NAME_PROPERTY)
+        .and_then(JsonValue::as_str)
+}
+
+// the snapshot is kept as a json string, properties can't contain objects
+pub fn version_properties(
+    graph_id: GraphId,
+    name: String,
+    state_id: u64,
+    snapshot: &Subgraph,
+) -> Result<Properties> {
+    let snapshot = serde_json::to_string(snapshot).map_err(Error::JsonError)?;
+
+    let mut properties = Properties::new();
+    properties.insert(VERSION_OF_PROPERTY.into(), graph_id.to_string().into());
+    properties.insert(VERSION_NAME_PROPERTY.into(), name.into());
+    properties.insert(VERSION_STATE_ID_PROPERTY.into(), state_id.into());
+    properties.insert(VERSION_SNAPSHOT_PROPERTY.into(), snapshot.into());
+
+    Ok(properties)
+}
+
+pub fn read_snapshot(properties: &Properties) -> Result<(u64, Subgraph)> {
+    let state_id = properties
+        .get(VERSION_STATE_ID_PROPERTY)
+        .and_then(JsonValue::as_u64)
+        .ok_or(Error::VersionNotFound)?;
+
+    let snapshot = properties
+        .get(VERSION_SNAPSHOT_PROPERTY)
+        .and_then(JsonValue::as_str)
+        .ok_or(Error::VersionNotFound)?;
+    let snapshot = serde_json::from_str(snapshot).map_err(Error::JsonError)?;
+
+    Ok((state_id, snapshot))
+}
+
+// drops the snapshot so version listings stay small
+pub fn without_snapshot(mut properties: Properties) -> Properties {
+    properties.remove(VERSION_SNAPSHOT_PROPERTY);
+    properties
+}
@@ -0,0 +1,304 @@
+use serde::Serialize;
+use serde_json::{Map, Value as JsonValue};
+
+// a DQL query whose values are never spliced into the text as they are, /query gets them
+// as variables, each block a variable named after it, and /mutate, which takes no
+// variables, as escaped string literals
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    name: String,
+    func: Func,
+    filter: Option<String>,
+    selection: String,
+}
+
+#[derive(Debug, Clone)]
+enum Func {
+    Eq { predicate: String, value: String },
+    Raw(String),
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    // the nodes whose `predicate` equals `value`
+    pub fn eq(
+        mut self,
+        name: &str,
+        predicate: &str,
+        value: impl ToString,
+        selection: &str,
+    ) -> Query {
+        self.blocks.push(Block {
+            name: name.into(),
+            func: Func::Eq {
+                predicate: predicate.into(),
+                value: value.to_string(),
+            },
+            filter: None,
+            selection: normalize(selection),
+        });
+        self
+    }
+
+    // for root functions without outside values, e.g. has(predicate)
+    pub fn func(mut self, name: &str, func: &str, selection: &str) -> Query {
+        self.blocks.push(Block {
+            name: name.into(),
+            func: Func::Raw(func.into()),
+            filter: None,
+            selection: normalize(selection),
+        });
+        self
+    }
+
+    // narrows the last block, like `func` for filters without outside values
+    pub fn filter(mut self, filter: &str) -> Query {
+        if let Some(block) = self.blocks.last_mut() {
+            block.filter = Some(filter.into());
+        }
+        self
+    }
+
+    pub fn text(&self) -> String {
+        let blocks = self.blocks(|name, _| format!("${}", name));
+        let declarations = self
+            .vars()
+            .map(|(var, _)| format!("{}: string", var))
+            .collect::<Vec<_>>();
+        if declarations.is_empty() {
+            return format!("{{ {} }}", blocks);
+        }
+
+        format!("query q({}) {{ {} }}", declarations.join(", "), blocks)
+    }
+
+    // the text with the values in place, as json string literals, which DQL reads the same
+    pub fn literal_text(&self) -> String {
+        let blocks = self.blocks(|_, value| JsonValue::String(value.into()).to_string());
+        format!("{{ {} }}", blocks)
+    }
+
+    pub fn variables(&self) -> Map<String, JsonValue> {
+        self.vars()
+            .map(|(var, value)| (var, JsonValue::String(value.into())))
+            .collect()
+    }
+
+    fn vars(&self) -> impl Iterator<Item = (String, &str)> {
+        self.blocks.iter().filter_map(|block| match &block.func {
+            Func::Eq { value, .. } => Some((format!("${}", block.name), value.as_str())),
+            Func::Raw(_) => None,
+        })
+    }
+
+    // `value` renders the value of an eq block from the block name and the value
+    fn blocks<F: Fn(&str, &str) -> String>(&self, value: F) -> String {
+        self.blocks
+            .iter()
+            .map(|block| {
+                let func = match &block.func {
+                    Func::Eq {
+                        predicate,
+                        value: block_value,
+                    } => format!("eq({}, {})", predicate, value(&block.name, block_value)),
+                    Func::Raw(func) => func.clone(),
+                };
+                let filter = match &block.filter {
+                    Some(filter) => format!(" @filter({})", filter),
+                    None => String::new(),
+                };
+                format!(
+                    "{}(func: {}){} {{ {} }}",
+                    block.name, func, filter, block.selection
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// the body of /query
+impl Serialize for Query {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        QueryBody {
+            query: self.text(),
+            variables: self.variables(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct QueryBody {
+    query: String,
+    variables: Map<String, JsonValue>,
+}
+
+// a schema query, it stands on its own instead of being a block of a query
+#[derive(Debug, Clone)]
+pub struct Schema {
+    predicates: Vec<String>,
+    selection: String,
+}
+
+impl Schema {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(
+        predicates: I,
+        selection: &str,
+    ) -> Schema {
+        Schema {
+            predicates: predicates.into_iter().map(Into::into).collect(),
+            selection: normalize(selection),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        format!(
+            "schema(pred: [{}]) {{ {} }}",
+            self.predicates.join(", "),
+            self.selection
+        )
+    }
+}
+
+impl Serialize for Schema {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        QueryBody {
+            query: self.text(),
+            variables: Map::new(),
+        }
+        .serialize(serializer)
+    }
+}
+
+// the body of /mutate, the mutation only runs when `cond` holds
+#[derive(Debug, Clone, Serialize)]
+pub struct Upsert {
+    query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cond: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delete: Option<JsonValue>,
+}
+
+impl Upsert {
+    pub fn new(query: &Query) -> Upsert {
+        Upsert {
+            query: query.literal_text(),
+            cond: None,
+            set: None,
+            delete: None,
+        }
+    }
+
+    pub fn cond(mut self, cond: &str) -> Upsert {
+        self.cond = Some(format!("@if({})", cond));
+        self
+    }
+
+    pub fn set(mut self, set: JsonValue) -> Upsert {
+        self.set = Some(set);
+        self
+    }
+
+    pub fn delete(mut self, delete: JsonValue) -> Upsert {
+        self.delete = Some(delete);
+        self
+    }
+}
+
+// one line with single spaces, so the generated text doesn't depend on indentation
+fn normalize(selection: &str) -> String {
+    selection.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query_text() {
+        let query = Query::new()
+            .eq("from", "indra_id", "a\") { uid } }", "f as uid")
+            .eq(
+                "to",
+                "indra_id",
+                "b",
+                "
+                t as uid
+                link {
+                    indra_id
+                }",
+            );
+        assert_eq!(
+            query.text(),
+            "query q($from: string, $to: string) { \
+             from(func: eq(indra_id, $from)) { f as uid } \
+             to(func: eq(indra_id, $to)) { t as uid link { indra_id } } }"
+        );
+        assert_eq!(
+            serde_json::to_value(&query).unwrap(),
+            json!({
+                "query": query.text(),
+                "variables": { "$from": "a\") { uid } }", "$to": "b" },
+            })
+        );
+
+        // /mutate gets the values escaped, a quote doesn't end the literal
+        assert_eq!(
+            query.literal_text(),
+            "{ from(func: eq(indra_id, \"a\\\") { uid } }\")) { f as uid } \
+             to(func: eq(indra_id, \"b\")) { t as uid link { indra_id } } }"
+        );
+
+        let query = Query::new().func("q", "has(schema_version)", "uid schema_version");
+        assert_eq!(
+            query.text(),
+            "{ q(func: has(schema_version)) { uid schema_version } }"
+        );
+
+        let query = Query::new()
+            .eq("d", "history_of", "c", "d as uid")
+            .filter("lt(history_position, 3)");
+        assert_eq!(
+            query.literal_text(),
+            "{ d(func: eq(history_of, \"c\")) @filter(lt(history_position, 3)) { d as uid } }"
+        );
+
+        let schema = Schema::new(["indra_id", "link"], "type\n    reverse");
+        assert_eq!(
+            serde_json::to_value(&schema).unwrap(),
+            json!({
+                "query": "schema(pred: [indra_id, link]) { type reverse }",
+                "variables": {},
+            })
+        );
+    }
+
+    #[test]
+    fn test_upsert() {
+        let query = Query::new().eq("q", "edge_id", "e", "e as uid");
+        let upsert = Upsert::new(&query)
+            .cond("eq(len(e), 1)")
+            .set(json!({ "uid": "uid(e)", "properties": "{}" }));
+        assert_eq!(
+            serde_json::to_value(&upsert).unwrap(),
+            json!({
+                "query": "{ q(func: eq(edge_id, \"e\")) { e as uid } }",
+                "cond": "@if(eq(len(e), 1))",
+                "set": { "uid": "uid(e)", "properties": "{}" },
+            })
+        );
+    }
+}
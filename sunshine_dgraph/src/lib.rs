@@ -1,3 +1,7 @@
+mod dql;
+#[cfg(test)]
+mod mock;
 mod queries;
 mod response;
+mod schema;
 pub mod store;
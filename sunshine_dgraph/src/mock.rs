@@ -0,0 +1,140 @@
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use serde_json::{json, Value as JsonValue};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// a stand-in for a dgraph server, it answers with scripted responses in order
+// and records what it was sent, so a test spells out the conversation it expects
+#[derive(Debug, Clone, Default)]
+pub struct MockDgraph {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    responses: VecDeque<(String, JsonValue)>,
+    requests: Vec<Request>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub path: String, // with the query string
+    pub access_token: Option<String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn json(&self) -> JsonValue {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+// the values at the json `pointer` of the requests that have one, in the order they were sent,
+// so a test doesn't depend on how many other requests came in between
+pub fn values_at(requests: &[Request], pointer: &str) -> Vec<JsonValue> {
+    requests
+        .iter()
+        .filter_map(|request| serde_json::from_str::<JsonValue>(&request.body).ok())
+        .filter_map(|body| body.pointer(pointer).cloned())
+        .collect()
+}
+
+impl MockDgraph {
+    // returns the base url to give the store
+    pub fn spawn() -> (MockDgraph, String) {
+        let mock = MockDgraph::default();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().fallback(handle).with_state(mock.clone());
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        (mock, format!("http://{}", addr))
+    }
+
+    // `data` is what dgraph puts under "data", the rest of the envelope is filled in
+    pub fn respond(&self, path: &str, data: JsonValue) -> &Self {
+        let response = json!({
+            "data": data,
+            "extensions": {
+                "server_latency": {
+                    "parsing_ns": 0,
+                    "processing_ns": 0,
+                    "encoding_ns": 0,
+                    "assign_timestamp_ns": 0,
+                    "total_ns": 0,
+                },
+                "txn": { "start_ts": 1, "hash": "" },
+            },
+        });
+        self.respond_raw(path, response)
+    }
+
+    pub fn respond_raw(&self, path: &str, response: JsonValue) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.responses.push_back((path.into(), response));
+        self
+    }
+
+    // an upsert answer, `queries` holds what the query blocks matched
+    pub fn respond_upsert(&self, queries: JsonValue) -> &Self {
+        self.respond(
+            "/mutate",
+            json!({ "code": "Success", "message": "Done", "queries": queries }),
+        )
+    }
+
+    pub fn respond_query(&self, data: JsonValue) -> &Self {
+        self.respond("/query", data)
+    }
+
+    // the requests received since the last call
+    pub fn take_requests(&self) -> Vec<Request> {
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            state.responses.is_empty(),
+            "unused responses: {:?}",
+            state.responses
+        );
+        std::mem::take(&mut state.requests)
+    }
+}
+
+async fn handle(
+    State(mock): State<MockDgraph>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let mut state = mock.state.lock().unwrap();
+    state.requests.push(Request {
+        path: uri.to_string(),
+        access_token: headers
+            .get("X-Dgraph-AccessToken")
+            .and_then(|token| token.to_str().ok())
+            .map(String::from),
+        body,
+    });
+
+    // /mutate reads no variables, the query would run with them undefined
+    let has_variables = serde_json::from_str::<JsonValue>(&state.requests.last().unwrap().body)
+        .is_ok_and(|body| body.get("variables").is_some());
+    if uri.path() == "/mutate" && has_variables {
+        let errors = json!({ "errors": [{ "message": "variables are not read by /mutate" }] });
+        return (StatusCode::OK, Json(errors)).into_response();
+    }
+
+    match state.responses.pop_front() {
+        Some((path, response)) if path == uri.path() => Json(response).into_response(),
+        expected => {
+            let message = format!("unexpected request {}, expected {:?}", uri, expected);
+            let errors = json!({ "errors": [{ "message": message }] });
+            (StatusCode::OK, Json(errors)).into_response()
+        }
+    }
+}
@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use sunshine_core::msg::Properties;
 
 #[derive(Serialize, Debug)]
 pub struct Mutate<T: Serialize> {
@@ -11,225 +10,43 @@ pub struct MutateCreateGraph {
     pub indra_id: String,
     pub state_id: i32,
     pub is_graph_root: bool,
-    #[serde(flatten)]
-    pub properties: Properties,
+    pub properties: String, // json object
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MutateCreateNode {
     pub indra_id: String,
-    #[serde(flatten)]
-    pub properties: Properties,
+    pub properties: String, // json object
 }
 
-// pub struct Upsert {
-//     pub query: Query
-//     pub set:,
-// }
-
-// pub struct Query {
-//     pub query: String,
-// }
-
-// pub struct MutateNode{
-//     pub uid
-// }
-
-// read_node
-// """
-// find(func: uid("{node_id}"))  {
-//                     uid
-//                     link{
-//                       uid
-//                     }
-//                     }
-// """
-
-// createNode
-
-//       """{
-//                 "set":{
-//                   "uid":"$parentUid",
-//                   "link":{
-//                   "name":"$newNodeName",
-//                   "node_type:
-
-//                   }
-//                 }
-//               }
-//               """;
-
-// CreateNode no parent
-
-//         /*
-//         {
-//                 "set":{
-//                   "indra_id":"{}",
-//                   "state_id":"0"
-//                 }
-//               }
-//                */
-// Upsert Example with math function
-// upsert {{
-//                     query {{
-//                         q(func: eq(indra_id,"{}")) {{
-//                         u as uid
-//                         s as state_id
-//                         n as math(s+1)
-//                         indra_id
-//                         }}
-//                     }}
-
-//                     mutation {{
-//                         set {{
-//                             uid(u) <state_id> val(n).
-//                         }}
-//                     }}
-//                 }}
-
-// Query
-
-// let query_by_uid = |uid: &str| {
-//     format!(
-//         r#"{{
-//             find(func: uid({}))  @recurse{{
-//                 uid
-//                 name
-//                 display
-//                 inlineDisplay
-//                 validation
-//                 action
-//                 link
-//                 options
-//                 selectionMode
-//             }}
-//         }}"#,
-//         uid
-//     )
-// };
-
-// class GraphQueries {
-//   static String cheetahNodeQuery() => """{
-//       find(func: uid("0x2"))  @recurse{
-//       uid
-//       name
-//       display
-//       inlineDisplay
-//       link
-//       }
-//     }""";
-
-//   /// TODO Document what the purpose of this query is. "entries" is too general.
-//   static String entries(String uid) => """{
-//             find(func: uid("$uid"))  @recurse{
-//               uid
-//               name
-//               display
-//               inlineDisplay
-//               validation
-//               action
-//               link
-//               options
-//               selectionMode
-//               }
-//             }
-//               """;
-
-//   static String userModel() => """{
-//                         find(func: uid("0x2"))  @recurse{
-//                           uid
-//                         name
-//                         display
-//                         inlineDisplay
-//                         action
-//                         link
-//                         }
-//                       }
-//                         """;
-
-//   static String rootNodes(String rootNodeUid) => """{
-//                   find(func: uid("$rootNodeUid"))  @recurse{
-//                     uid
-//                     name
-//                     display
-//                     inlineDisplay
-//                     validation
-//                     action
-//                     link
-//                     }
-//                   }
-//                     """;
-
-//   static String mutateNode(String parentUid, String newNodeName) => """{
-//                 "set":{
-//                   "uid":"0x2711",
-//                   "link":{
-//                   "name":"$newNodeName"
-//                   }
-//                 }
-//               }
-//               """;
-
-//   /// TODO This name is too general. Add what if what is absent?
-//   static String addIfAbsent(String value) => """{
-//                   find(func: eq(name, "$value"))  @recurse{
-//                     uid
-//                     name
-//                     display
-//                     inlineDisplay
-//                     validation
-//                     action
-//                     link
-//                     }
-//                   }
-//                     """;
-
-//   static String fetchUserData(String uid) => """{
-//                   find(func: eq(firebaseUid, "${uid}"))  {
-//                     uid
-//                     name
-//                     link
-//                     }
-//                   }
-//                     """;
-
-//   static String fetchMyListings(String userUid) => """{
-//                     find(func: uid("0xe")){
-//                         uid
-//                         link @facets(eq,"$userUid"){
-//                         uid
-//                         }
-//                       }
-//                     }
-//                     """;
+// an edge is a node of its own between the nodes it connects,
+// node -edges-> edge -to-> node, so parallel edges keep their ids
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutateCreateEdge {
+    pub edge_id: String,
+    pub properties: String, // json object
+    pub to: MutateUid,
+}
 
-//   // Node 0xe is a node with name listing.
-//   // Connect listing node to a new node with
-//   static String createListing(String userUid) => """{
-//                 "set":{
-//                   "uid":"0xe",
-//                   "link":[{
-//                   "link|userId":"$userUid"
-//                   }]
-//                 }
-//               }
-//               """;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutateUid {
+    pub uid: String,
+}
 
-//   static String addEdge({
-//     required String fromNode,
-//     required String toNode,
-//     required String facetId,
-//     required String facetValue,
-//   }) {
-//     return """{
-//                 "set":{
-//                   "uid":"$fromNode",
-//                   "link":[{
-//                   "uid":"$toNode",
-//                   "link|$facetId":"$facetValue"
-//                   }]
-//                 }
-//               }
-//              """;
-//   }
-// }
+// the edges of a node in both directions with their properties, for read_node,
+// read_graph and delete_node
+pub const NODE_EDGES: &str = "
+    edges {
+        edge_id
+        properties
+        to {
+            indra_id
+        }
+    }
+    in_edges: ~to {
+        edge_id
+        properties
+        from: ~edges {
+            indra_id
+        }
+    }";
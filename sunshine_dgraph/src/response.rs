@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use sunshine_core::msg::Properties;
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -11,6 +10,16 @@ pub struct QueryRoot {
     pub extensions: Extensions,
 }
 
+impl QueryRoot {
+    pub fn nodes(&self, query: &str) -> &[Node] {
+        self.data.get(query).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn first(&self, query: &str) -> Option<&Node> {
+        self.nodes(query).first()
+    }
+}
+
 /* Upsert Reponse Example
 {
   "data": {
@@ -76,10 +85,17 @@ pub struct UpsertRoot {
 pub struct UpsertData {
     pub code: String,
     pub message: String,
-    pub queries: HashMap<String, Vec<Node>>,
+    pub queries: Option<HashMap<String, Vec<Node>>>, // null without a query
     pub uids: Option<HashMap<String, String>>,
 }
 
+impl UpsertData {
+    // the nodes matched before the mutation was applied
+    pub fn first(&self, query: &str) -> Option<&Node> {
+        self.queries.as_ref()?.get(query)?.first()
+    }
+}
+
 /*
 {
   "data": {
@@ -122,13 +138,123 @@ pub struct MutateData {
     pub uids: HashMap<String, String>,
 }
 
+// only the predicates a query asks for are there, edge nodes use the same
+// struct with edge_id, to and from
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Node {
     pub uid: String,
     pub indra_id: String,
-    #[serde(flatten)]
-    pub properties: Properties,
+    pub state_id: Option<u64>,
+    pub properties: Option<String>, // json object
     pub link: Option<Vec<Node>>,
+    pub edges: Option<Vec<Node>>,
+    pub in_edges: Option<Vec<Node>>, // alias of ~to
+    pub edge_id: Option<String>,
+    pub to: Option<Vec<Node>>,
+    pub from: Option<Vec<Node>>, // alias of ~edges
+    pub schema_version: Option<usize>,
+    pub history: Option<String>,       // json of a HistoryHead
+    pub history_entry: Option<String>, // json of a HistoryEntry
+}
+
+/// Login
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginRoot {
+    pub data: LoginData,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginData {
+    #[serde(rename = "accessJWT")]
+    pub access_jwt: String,
+    #[serde(rename = "refreshJWT")]
+    pub refresh_jwt: String,
+}
+
+/// Alter, commit and abort
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlterRoot {
+    pub data: AlterData,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlterData {
+    pub code: String,
+    pub message: String,
+}
+
+/* Schema Query Response Example
+{
+  "data": {
+    "schema": [
+      {
+        "predicate": "indra_id",
+        "type": "string",
+        "index": true,
+        "tokenizer": ["exact"]
+      }
+    ]
+  }
+}
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaRoot {
+    pub data: SchemaData,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchemaData {
+    pub schema: Vec<PredicateSchema>,
+}
+
+// dgraph leaves out the flags that are false
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PredicateSchema {
+    pub predicate: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub index: bool,
+    pub tokenizer: Vec<String>,
+    pub reverse: bool,
+}
+
+// what dgraph answers instead of data when a request fails
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorRoot {
+    pub errors: Vec<DgraphError>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DgraphError {
+    pub message: String,
+    pub extensions: ErrorExtensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorExtensions {
+    pub code: Option<String>, // e.g. ErrorInvalidRequest
+}
+
+impl DgraphError {
+    // dgraph reports a conflicting commit with the invalid request code,
+    // only the message tells it apart
+    pub fn is_aborted(&self) -> bool {
+        self.message.starts_with("Transaction has been aborted")
+    }
+}
+
+impl std::fmt::Display for DgraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.extensions.code {
+            Some(code) => write!(f, "{}: {}", code, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -146,33 +272,18 @@ pub struct ServerLatency {
     pub total_ns: i64,
 }
 
+// keys and preds come with mutations, commit needs them back
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Txn {
     pub start_ts: i64,
     pub hash: String,
+    pub keys: Vec<String>,
+    pub preds: Vec<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Metrics {
     #[serde(flatten)]
     pub properties: HashMap<String, Value>,
-    // pub num_uids: NumUids,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct NumUids {
-    #[serde(flatten)]
-    pub properties: HashMap<String, Value>,
-    // pub field: Option<i64>,
-    // #[serde(rename = "_total")]
-    // pub total: i64,
-    // pub action: Option<i64>,
-    // pub display: i64,
-    // pub inline_display: i64,
-    // pub link: i64,
-    // pub name: i64,
-    // pub options: i64,
-    // pub selection_mode: i64,
-    // pub uid: i64,
-    // pub validation: i64,
 }
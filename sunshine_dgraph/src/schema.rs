@@ -0,0 +1,240 @@
+use sunshine_core::error::{Error, Result};
+
+use crate::dql::{Query, Schema, Upsert};
+use crate::response::{AlterRoot, QueryRoot, SchemaRoot, UpsertRoot};
+use crate::store::{Store, MUTATE, QUERY};
+
+const ALTER: &str = "/alter";
+
+// each entry takes the schema one version further, applied ones must stay as they are
+const MIGRATIONS: &[&str] = &[
+    // nodes and graph roots
+    "
+    schema_version: int .
+    indra_id: string @index(exact) @upsert .
+    is_graph_root: bool @index(bool) .
+    state_id: int .
+    properties: string .
+    link: [uid] @reverse .
+    ",
+    // edges as nodes of their own
+    "
+    edge_id: string @index(exact) @upsert .
+    edges: [uid] @reverse .
+    to: [uid] @reverse .
+    ",
+    // the history of each client, a head node keyed by the client id and a node per entry
+    "
+    history_client: string @index(exact) @upsert .
+    history: string .
+    history_of: string @index(exact) .
+    history_position: int @index(int) .
+    history_entry: string .
+    ",
+];
+
+// every predicate the migrations declare, as the schema query reports it:
+// predicate, type, index tokenizer, @reverse
+// `link` ties a graph root to its nodes, `edges` and `to` tie an edge node to the nodes
+// it connects, delete_node finds what points at a node through their reverse
+const REQUIRED: &[(&str, &str, Option<&str>, bool)] = &[
+    ("schema_version", "int", None, false),
+    ("indra_id", "string", Some("exact"), false),
+    ("is_graph_root", "bool", Some("bool"), false),
+    ("state_id", "int", None, false),
+    ("properties", "string", None, false),
+    ("link", "uid", None, true),
+    ("edge_id", "string", Some("exact"), false),
+    ("edges", "uid", None, true),
+    ("to", "uid", None, true),
+    ("history_client", "string", Some("exact"), false),
+    ("history", "string", None, false),
+    ("history_of", "string", Some("exact"), false),
+    ("history_position", "int", Some("int"), false),
+    ("history_entry", "string", None, false),
+];
+
+impl Store {
+    // brings the schema up to date and checks it, returns the schema version
+    pub async fn migrate(&self) -> Result<usize> {
+        let version = self.schema_version().await?;
+        if version > MIGRATIONS.len() {
+            return Err(Error::DGraphError(format!(
+                "schema version {} is newer than this client",
+                version
+            )));
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            log::info!("applying dgraph schema version {}", i + 1);
+            let _: AlterRoot = self.dql_req(ALTER, *migration).await?;
+            self.set_schema_version(i + 1).await?;
+        }
+
+        self.verify_schema().await?;
+
+        Ok(MIGRATIONS.len())
+    }
+
+    // a fresh database has no version node and counts as version 0
+    async fn schema_version(&self) -> Result<usize> {
+        let query = Query::new().func("q", "has(schema_version)", "uid schema_version");
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
+
+        Ok(res
+            .first("q")
+            .and_then(|node| node.schema_version)
+            .unwrap_or(0))
+    }
+
+    async fn set_schema_version(&self, version: usize) -> Result<()> {
+        let query = Query::new().func("q", "has(schema_version)", "v as uid");
+        let upsert = Upsert::new(&query).set(serde_json::json!({
+            "uid": "uid(v)",
+            "schema_version": version,
+        }));
+        let _: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        Ok(())
+    }
+
+    async fn verify_schema(&self) -> Result<()> {
+        let schema = Schema::new(
+            REQUIRED.iter().map(|(predicate, _, _, _)| *predicate),
+            "
+            type
+            index
+            tokenizer
+            reverse",
+        );
+        let res: SchemaRoot = self.json_req(QUERY, &schema).await?;
+
+        for (predicate, kind, tokenizer, reverse) in REQUIRED {
+            let schema = res
+                .data
+                .schema
+                .iter()
+                .find(|schema| schema.predicate == *predicate)
+                .ok_or_else(|| Error::DGraphError(format!("predicate {} is missing", predicate)))?;
+
+            if schema.kind != *kind {
+                return Err(Error::DGraphError(format!(
+                    "predicate {} has type {} instead of {}",
+                    predicate, schema.kind, kind
+                )));
+            }
+
+            if let Some(tokenizer) = tokenizer {
+                if !schema.tokenizer.iter().any(|t| t == tokenizer) {
+                    return Err(Error::DGraphError(format!(
+                        "predicate {} needs an {} index",
+                        predicate, tokenizer
+                    )));
+                }
+            }
+            if *reverse && !schema.reverse {
+                return Err(Error::DGraphError(format!(
+                    "predicate {} needs @reverse",
+                    predicate
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{values_at, MockDgraph};
+    use crate::store::Config;
+    use serde_json::json;
+
+    fn schema(reverse_link: bool) -> serde_json::Value {
+        json!({ "schema": [
+            { "predicate": "schema_version", "type": "int" },
+            { "predicate": "indra_id", "type": "string", "index": true, "tokenizer": ["exact"] },
+            { "predicate": "is_graph_root", "type": "bool", "index": true, "tokenizer": ["bool"] },
+            { "predicate": "state_id", "type": "int" },
+            { "predicate": "properties", "type": "string" },
+            { "predicate": "link", "type": "uid", "reverse": reverse_link },
+            { "predicate": "edge_id", "type": "string", "index": true, "tokenizer": ["exact"] },
+            { "predicate": "edges", "type": "uid", "reverse": true },
+            { "predicate": "to", "type": "uid", "reverse": true },
+            { "predicate": "history_client", "type": "string", "index": true, "tokenizer": ["exact"] },
+            { "predicate": "history", "type": "string" },
+            { "predicate": "history_of", "type": "string", "index": true, "tokenizer": ["exact"] },
+            { "predicate": "history_position", "type": "int", "index": true, "tokenizer": ["int"] },
+            { "predicate": "history_entry", "type": "string" },
+        ] })
+    }
+
+    // the check covers exactly what the migrations declare
+    #[test]
+    fn test_required_matches_migrations() {
+        let mut declared: Vec<(&str, &str, Option<&str>, bool)> = MIGRATIONS
+            .iter()
+            .flat_map(|migration| migration.lines())
+            .filter_map(|line| line.trim().split_once(':'))
+            .map(|(predicate, rest)| {
+                let mut words = rest.split_whitespace();
+                let kind = words.next().unwrap().trim_matches(|c| c == '[' || c == ']');
+                let directives: Vec<&str> = words.collect();
+                let tokenizer = directives.iter().find_map(|directive| {
+                    directive
+                        .strip_prefix("@index(")
+                        .and_then(|rest| rest.strip_suffix(')'))
+                });
+                (predicate, kind, tokenizer, directives.contains(&"@reverse"))
+            })
+            .collect();
+        let mut required = REQUIRED.to_vec();
+        declared.sort();
+        required.sort();
+        assert_eq!(declared, required);
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let (mock, url) = MockDgraph::spawn();
+        let store = Store::new(&Config::new(url, "token"));
+        let done = json!({ "code": "Success", "message": "Done" });
+
+        // a fresh database gets every migration
+        mock.respond_query(json!({ "q": [] }))
+            .respond(ALTER, done.clone())
+            .respond_upsert(json!({ "q": [] }))
+            .respond(ALTER, done.clone())
+            .respond_upsert(json!({ "q": [{ "uid": "0x1" }] }))
+            .respond(ALTER, done)
+            .respond_upsert(json!({ "q": [{ "uid": "0x1" }] }))
+            .respond_query(schema(true));
+        assert_eq!(store.migrate().await.unwrap(), MIGRATIONS.len());
+
+        let requests = mock.take_requests();
+        let alters: Vec<&str> = requests
+            .iter()
+            .filter(|request| request.path == ALTER)
+            .map(|request| request.body.as_str())
+            .collect();
+        assert_eq!(alters, MIGRATIONS);
+        assert_eq!(
+            values_at(&requests, "/set/schema_version"),
+            vec![json!(1), json!(2), json!(3)]
+        );
+        let checked = values_at(&requests, "/query").pop().unwrap();
+        assert!(checked
+            .as_str()
+            .unwrap()
+            .starts_with("schema(pred: [schema_version, indra_id, "));
+
+        // an up to date one is only checked
+        mock.respond_query(json!({ "q": [{ "uid": "0x1", "schema_version": 3 }] }))
+            .respond_query(schema(false));
+        assert!(matches!(store.migrate().await, Err(Error::DGraphError(_))));
+
+        mock.respond_query(json!({ "q": [{ "uid": "0x1", "schema_version": 4 }] }));
+        assert!(matches!(store.migrate().await, Err(Error::DGraphError(_))));
+    }
+}
@@ -1,46 +1,35 @@
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use serde_json::Value as JsonValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
 use std::str::FromStr;
-use sunshine_core::store::Datastore;
+use std::sync::Mutex;
+use std::time::Duration;
+use sunshine_core::hash::HashCache;
+use sunshine_core::history::History;
+use sunshine_core::store::{Datastore, Operation};
+use sunshine_core::timeline::Timeline;
 use uuid::Uuid;
 
+use crate::dql::{Query, Upsert};
 use crate::queries::*;
-use crate::response::{Node as DNode, QueryRoot, UpsertRoot};
+use crate::response::{
+    AlterRoot, ErrorRoot, LoginData, LoginRoot, MutateRoot, Node as DNode, QueryRoot, Txn,
+    UpsertRoot,
+};
 
 use sunshine_core::error::*;
 use sunshine_core::msg::*;
 
-// #[tokio::main]
-// pub async fn query() -> std::result::Result<DNode, Box<dyn std::error::Error>> {
-//     let client = reqwest::Client::new();
+pub(crate) const MUTATE: &str = "/mutate";
+pub(crate) const QUERY: &str = "/query";
+const COMMIT: &str = "/commit";
+const LOGIN: &str = "/login";
 
-//     let url = "https://quiet-leaf.us-west-2.aws.cloud.dgraph.io/query?=";
-//     let uid = "0x170f16be";
-
-//     let res = client
-//         .post(url)
-//         .body(query_by_uid(uid))
-//         .header(
-//             "x-auth-token",
-//             "NmY2YWQ1YzlkNjg4NjUwMzc0MDJmMjk4ZTg3Yzk5Yzc=",
-//         )
-//         .header("Content-Type", "application/graphql+-")
-//         .send()
-//         .await?;
-
-//     let t: Root = res.json().await?;
-
-//     let root_node: &DNode = &t.data.find.first().unwrap();
-
-//     dbg!(t.clone());
-
-//     Ok(root_node.clone())
-// }
-
-const MUTATE: &str = "/mutate?commitNow=true";
-const QUERY: &str = "/query";
+// runs of an action whose transaction keeps conflicting
+const MAX_ATTEMPTS: u32 = 3;
+// the wait before the second run, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
 
 #[async_trait]
 impl Datastore for Store {
@@ -52,40 +41,163 @@ impl Datastore for Store {
         &mut self.redo
     }
 
-    fn history_buf(&mut self) -> &mut Vec<Action> {
+    fn undo_capacity(&self) -> Option<usize> {
+        self.undo_capacity
+    }
+
+    fn history(&self) -> &History {
+        &self.history
+    }
+
+    fn history_buf(&mut self) -> &mut History {
         &mut self.history
     }
 
-    async fn update_state_id(&self, graph_id: GraphId) -> Result<()> {
-        let res: UpsertRoot = self
-            .json_req(
-                MUTATE,
-                &serde_json::json!({
-                    "query": format!(r#"{{
-                q(func: eq(indra_id,"{}")) {{
-                u as uid
-                s as state_id
-                n as math(s+1)
-                indra_id
-                }}
-            }}"#, graph_id),
-                    "set": {
-                        "uid": "uid(u)",
-                        "state_id": "val(n)",
-                    },
-                }),
-            )
-            .await?;
+    fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
 
-        if res.data.queries.get("q").unwrap().len() < 1 {
-            return Err(Error::GraphNotFound);
+    fn hash_cache(&self) -> Option<&HashCache> {
+        Some(&self.hash_cache)
+    }
+
+    fn timeline_buf(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+
+    // every action runs in a transaction of its own, the local buffers and the timeline
+    // are put back when it fails and a conflicting one is run again after a while
+    async fn execute_with_id(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        if !matches!(msg, Action::Query(_)) {
+            if let Some(reply) = self.history.seen(action_id)? {
+                return Ok(reply.clone());
+            }
+        }
+
+        let mut attempt = 1;
+        loop {
+            let (undo, redo, history_len) =
+                (self.undo.clone(), self.redo.clone(), self.history.len());
+            self.timeline.begin();
+            *self.txn.get_mut().unwrap() = Some(Transaction::default());
+
+            // the history is saved in the transaction of the action it records
+            let result = match self
+                .execute_impl(action_id, msg.clone(), Operation::Other)
+                .await
+            {
+                Ok(reply) if matches!(msg, Action::Query(_)) => Ok(reply),
+                Ok(reply) => self.save_history().await.map(|_| reply),
+                Err(error) => Err(error),
+            };
+            let result = match result {
+                Ok(reply) => self.finish_txn(false).await.map(|_| reply),
+                Err(error) => {
+                    if let Err(abort_error) = self.finish_txn(true).await {
+                        log::warn!("could not abort the transaction: {}", abort_error);
+                    }
+                    Err(error)
+                }
+            };
+
+            let error = match result {
+                Ok(reply) => {
+                    self.timeline.commit();
+                    return Ok(reply);
+                }
+                Err(error) => error,
+            };
+            self.undo = undo;
+            self.redo = redo;
+            self.history.truncate(history_len);
+            self.timeline.rollback();
+
+            match error {
+                Error::TransactionConflict if attempt < MAX_ATTEMPTS => {
+                    log::info!("transaction conflict, running {} again", msg.name());
+                    tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    attempt += 1;
+                }
+                error => return Err(error),
+            }
+        }
+    }
+
+    // the head of the client is rewritten, its new entries are added as nodes of their own
+    // and the dropped ones removed, so clients sharing the database don't touch each other's
+    async fn save_history(&mut self) -> Result<()> {
+        if !self.saves_history {
+            return Ok(());
+        }
+
+        let client_id = self.history.client_id().to_string();
+        let (entries, dropped) = self.history.unsaved();
+        let head = serde_json::to_string(&self.history.head()).map_err(Error::JsonError)?;
+        let mut set = vec![json!({
+            "uid": "uid(h)",
+            "history_client": client_id,
+            "history": head,
+        })];
+        for entry in entries {
+            set.push(json!({
+                "history_of": client_id,
+                "history_position": entry.position,
+                "history_entry": serde_json::to_string(&entry).map_err(Error::JsonError)?,
+            }));
+        }
+
+        let mut query = Query::new().eq("h", "history_client", &client_id, "h as uid");
+        if !dropped.is_empty() {
+            query = query
+                .eq("d", "history_of", &client_id, "d as uid")
+                .filter(&format!("lt(history_position, {})", self.history.start()));
         }
+        let mut upsert = Upsert::new(&query).set(JsonValue::Array(set));
+        if !dropped.is_empty() {
+            upsert = upsert.delete(json!({ "uid": "uid(d)" }));
+        }
+        let _: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
 
+        self.history.mark_saved();
         Ok(())
     }
 
-    async fn create_graph(&self, _: Properties) -> Result<(Action, GraphId)> {
-        Err(Error::Unimplemented)
+    async fn update_state_id(&self, graph_id: GraphId) -> Result<u64> {
+        let query = Query::new().eq(
+            "q",
+            "indra_id",
+            graph_id,
+            "
+            u as uid
+            s as state_id
+            n as math(s+1)
+            indra_id",
+        );
+        let upsert = Upsert::new(&query).set(json!({
+            "uid": "uid(u)",
+            "state_id": "val(n)",
+        }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        // the query sees the state id from before the mutation
+        let state_id = res
+            .data
+            .first("q")
+            .and_then(|node| node.state_id)
+            .ok_or(Error::GraphNotFound)?;
+
+        Ok(state_id + 1)
+    }
+
+    async fn set_state_id(&self, graph_id: GraphId, state_id: u64) -> Result<()> {
+        let query = Query::new().eq("q", "indra_id", graph_id, "u as uid indra_id");
+        let upsert = Upsert::new(&query).cond("eq(len(u), 1)").set(json!({
+            "uid": "uid(u)",
+            "state_id": state_id,
+        }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        res.data.first("q").map(|_| ()).ok_or(Error::GraphNotFound)
     }
 
     async fn create_graph_with_id(
@@ -98,69 +210,54 @@ impl Datastore for Store {
                 indra_id: graph_id.to_string(),
                 is_graph_root: true,
                 state_id: 0,
-                properties: properties,
+                properties: to_json_string(&properties)?,
             },
         };
 
-        self.json_req(MUTATE, &create_graph).await?;
+        let _: MutateRoot = self.json_req(MUTATE, &create_graph).await?;
 
         Ok((Action::DeleteGraph(graph_id), graph_id))
     }
 
     async fn list_graphs(&self) -> Result<Vec<(NodeId, Properties)>> {
-        let res: QueryRoot = self
-            .dql_req(
-                QUERY,
-                "{
-                q(func: eq(is_graph_root,true)) {
-                    uid
-                    state_id
-                    indra_id
-                }
-            }",
-            )
-            .await?;
+        let query = Query::new().func("q", "eq(is_graph_root, true)", "uid indra_id properties");
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
 
-        res.data
-            .get("q")
-            .unwrap()
-            .into_iter()
-            .map(|node| Ok((Uuid::from_str(&node.indra_id)?, node.properties.clone())))
+        res.nodes("q")
+            .iter()
+            .map(|node| Ok((Uuid::from_str(&node.indra_id)?, node_properties(node)?)))
             .collect::<Result<Vec<_>>>()
     }
 
     async fn read_graph(&self, graph_id: GraphId) -> Result<Graph> {
-        let res: QueryRoot = self
-            .dql_req(
-                QUERY,
-                format!(
-                    "{{
-                q(func: eq(indra_id, \"{}\")) @recurse{{
-                    uid
-                    indra_id
-                    state_id
-                    link
-                }}
+        let selection = format!(
+            "
+            uid
+            state_id
+            link {{
+                uid
+                indra_id
+                properties
+                {}
             }}",
-                    graph_id
-                ),
-            )
-            .await?;
-
-        if res.data.get("q").unwrap().len() < 1 {
-            return Err(Error::GraphNotFound);
-        }
+            NODE_EDGES
+        );
+        let query = Query::new().eq("q", "indra_id", graph_id, &selection);
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
 
-        let node = &res.data.get("q").unwrap()[0];
+        let graph_root = res.first("q").ok_or(Error::GraphNotFound)?;
 
-        let nodes = match node.link.as_ref() {
-            Some(nodes) => todo!(), //nodes.iter().map(|node| Node {}),
-            None => Vec::new(),
-        };
+        let nodes = graph_root
+            .link
+            .iter()
+            .flatten()
+            .map(|node| to_node(Uuid::from_str(&node.indra_id)?, node))
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Graph {
-            state_id: node.properties.get("state_id").unwrap().as_u64().unwrap(),
+            state_id: graph_root.state_id.ok_or(Error::GraphNotFound)?,
             nodes,
+            hash: None,
         })
     }
 
@@ -169,103 +266,61 @@ impl Datastore for Store {
         indra_id: NodeId,
         (graph_id, properties): (GraphId, Properties),
     ) -> Result<Action> {
-        let res: UpsertRoot = self
-            .json_req(
-                MUTATE,
-                &serde_json::json!({
-                    "query": format!(r#"{{
-                        q(func: eq(indra_id,"{}")) {{
-                        u as uid
-                        indra_id
-                    }}
-            }}"#, graph_id),
-                    "set": {
-                        "uid": "uid(u)",
-                        "link": MutateCreateNode {
-                            indra_id: indra_id.to_string(),
-                            properties,
-                        }
-                    },
-                }),
-            )
-            .await?;
-
-        // a -> b -> c -> a
-        //   b -> a
-
-        // graph_root { a { b }, b { c, a }, c { a } }
-
-        if res.data.queries.get("q").unwrap().len() < 1 {
+        // without the condition a missing graph would leave a detached node behind
+        // and a replayed action a second node with the same id
+        let query = Query::new()
+            .eq("q", "indra_id", graph_id, "u as uid indra_id")
+            .eq("existing", "indra_id", indra_id, "e as uid");
+        let upsert = Upsert::new(&query)
+            .cond("eq(len(u), 1) AND eq(len(e), 0)")
+            .set(json!({
+                "uid": "uid(u)",
+                "link": MutateCreateNode {
+                    indra_id: indra_id.to_string(),
+                    properties: to_json_string(&properties)?,
+                },
+            }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        if res.data.first("q").is_none() {
             return Err(Error::GraphNotFound);
         }
+        if res.data.first("existing").is_some() {
+            return Err(Error::NodeAlreadyExists(indra_id));
+        }
 
         Ok(Action::Mutate(graph_id, MutateKind::DeleteNode(indra_id)))
     }
 
     async fn read_node(&self, node_id: NodeId) -> Result<Node> {
-        let res: QueryRoot = self
-            .dql_req(
-                QUERY,
-                format!(
-                    "{{
-                outbound(func: eq(indra_id, \"{}\")) {{
-                    uid
-                    indra_id
-                    link {{
-                        uid
-                        indra_id
-                    }}
-                }}
-
-                inbound(func: eq(indra_id, \"{}\")) {{
-                    uid
-                    indra_id
-                    ~link {{
-                        uid
-                        indra_id
-                    }}
-                }}
-            }}",
-                    node_id, node_id
-                ),
-            )
-            .await?;
+        let selection = format!("uid indra_id properties {}", NODE_EDGES);
+        let query = Query::new().eq("q", "indra_id", node_id, &selection);
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
 
-        let outbound = res.data.get("outbound").unwrap();
-        let inbound = res.data.get("inbound").unwrap();
+        let node = res.first("q").ok_or(Error::NodeNotFound)?;
 
-        if outbound.len() < 1 {
-            return Err(Error::NodeNotFound);
-        }
-
-        let outbound = &outbound[0];
-
-        if inbound.len() < 1 {
-            return Err(Error::NodeNotFound);
-        }
-
-        let inbound = &inbound[0];
-
-        let outbound_edges: Vec<Edge> = match outbound.link.as_ref() {
-            Some(nodes) => todo!(),
-            None => Vec::new(),
-        };
-
-        let inbound_edges: Vec<Edge> = match inbound.link.as_ref() {
-            Some(nodes) => todo!(),
-            None => Vec::new(),
-        };
-
-        Ok(Node {
-            node_id,
-            properties: outbound.properties.clone(),
-            outbound_edges: Vec::new(),
-            inbound_edges: Vec::new(),
-        })
+        to_node(node_id, node)
     }
 
-    async fn update_node(&self, args: (NodeId, Properties), graph_id: GraphId) -> Result<Action> {
-        todo!();
+    async fn update_node(
+        &self,
+        (node_id, properties): (NodeId, Properties),
+        graph_id: GraphId,
+    ) -> Result<Action> {
+        let query = Query::new().eq("q", "indra_id", node_id, "u as uid properties");
+        let upsert = Upsert::new(&query).cond("eq(len(u), 1)").set(json!({
+            "uid": "uid(u)",
+            "properties": to_json_string(&properties)?,
+        }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        // the query sees the properties from before the mutation
+        let prev_node = res.data.first("q").ok_or(Error::NodeNotFound)?;
+
+        Ok(Action::Mutate(
+            graph_id,
+            MutateKind::UpdateNode((node_id, node_properties(prev_node)?)),
+        ))
     }
 
     async fn recreate_node(
@@ -273,23 +328,140 @@ impl Datastore for Store {
         recreate_node: RecreateNode,
         graph_id: GraphId,
     ) -> Result<Action> {
-        todo!();
+        let RecreateNode {
+            node_id,
+            properties,
+            edges,
+        } = recreate_node;
+
+        self.create_node_with_id(node_id, (graph_id, properties))
+            .await?;
+        for (edge, properties) in edges {
+            self.recreate_edge(edge, properties).await?;
+        }
+
+        Ok(Action::Mutate(graph_id, MutateKind::DeleteNode(node_id)))
     }
 
     // deletes inbound and outbound edges as well
     async fn delete_node(&self, node_id: NodeId, graph_id: GraphId) -> Result<Action> {
-        todo!();
+        // edge properties are gone once the edges are deleted, so they are read along
+        let selection = format!("uid indra_id properties {}", NODE_EDGES);
+        let query = Query::new().eq("q", "indra_id", node_id, &selection);
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
+
+        let deleted_node = res.first("q").ok_or(Error::NodeNotFound)?;
+        let properties = node_properties(deleted_node)?;
+        let (outbound_edges, inbound_edges) = node_edges(node_id, deleted_node)?;
+        let edges: Vec<(Edge, Properties)> =
+            inbound_edges.into_iter().chain(outbound_edges).collect();
+
+        // the links and edges pointing at the node belong to the nodes they come
+        // from, the graph root among them, so they are found through the reverse edges
+        let query = Query::new().eq(
+            "q",
+            "indra_id",
+            node_id,
+            "
+            n as uid
+            ~link {
+                p as uid
+            }
+            edges {
+                o as uid
+            }
+            ~to {
+                i as uid
+                ~edges {
+                    f as uid
+                }
+            }",
+        );
+        let upsert = Upsert::new(&query).delete(json!([
+            {
+                "uid": "uid(p)",
+                "link": { "uid": "uid(n)" },
+            },
+            {
+                "uid": "uid(f)",
+                "edges": { "uid": "uid(i)" },
+            },
+            {
+                "uid": "uid(o)",
+                "edge_id": null,
+                "properties": null,
+                "to": null,
+            },
+            {
+                "uid": "uid(i)",
+                "edge_id": null,
+                "properties": null,
+                "to": null,
+            },
+            {
+                "uid": "uid(n)",
+                "indra_id": null,
+                "properties": null,
+                "link": null,
+                "edges": null,
+            },
+        ]));
+        let _: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        Ok(Action::Mutate(
+            graph_id,
+            MutateKind::RecreateNode(RecreateNode {
+                node_id,
+                properties,
+                edges,
+            }),
+        ))
     }
 
     async fn create_edge(&self, msg: CreateEdge, graph_id: GraphId) -> Result<(Action, EdgeId)> {
-        todo!();
+        let edge = Edge {
+            id: Uuid::new_v4(),
+            from: msg.from,
+            to: msg.to,
+        };
+        self.recreate_edge(edge, msg.properties).await?;
+
+        Ok((
+            Action::Mutate(graph_id, MutateKind::DeleteEdge(edge)),
+            edge.id,
+        ))
     }
 
     async fn read_edge_properties(&self, msg: Edge) -> Result<Properties> {
-        todo!();
+        let query = Query::new().eq("q", "edge_id", msg.id, "uid properties");
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
+
+        node_properties(res.first("q").ok_or(Error::EdgeNotFound)?)
     }
+
     async fn recreate_edge(&self, edge: Edge, properties: Properties) -> Result<()> {
-        todo!();
+        let query = Query::new()
+            .eq("from", "indra_id", edge.from, "f as uid")
+            .eq("to", "indra_id", edge.to, "t as uid");
+        let upsert = Upsert::new(&query)
+            .cond("eq(len(f), 1) AND eq(len(t), 1)")
+            .set(json!({
+                "uid": "uid(f)",
+                "edges": MutateCreateEdge {
+                    edge_id: edge.id.to_string(),
+                    properties: to_json_string(&properties)?,
+                    to: MutateUid {
+                        uid: "uid(t)".into(),
+                    },
+                },
+            }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        if res.data.first("from").is_none() || res.data.first("to").is_none() {
+            return Err(Error::NodeNotFound);
+        }
+
+        Ok(())
     }
 
     async fn update_edge(
@@ -297,21 +469,82 @@ impl Datastore for Store {
         (edge, properties): (Edge, Properties),
         graph_id: GraphId,
     ) -> Result<Action> {
-        todo!();
+        let query = Query::new().eq("q", "edge_id", edge.id, "e as uid properties");
+        let upsert = Upsert::new(&query).cond("eq(len(e), 1)").set(json!({
+            "uid": "uid(e)",
+            "properties": to_json_string(&properties)?,
+        }));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        // the query sees the properties from before the mutation
+        let prev_edge = res.data.first("q").ok_or(Error::EdgeNotFound)?;
+
+        Ok(Action::Mutate(
+            graph_id,
+            MutateKind::UpdateEdge((edge, node_properties(prev_edge)?)),
+        ))
     }
 
     async fn delete_edge(&self, edge: Edge, graph_id: GraphId) -> Result<Action> {
-        todo!();
+        let query = Query::new().eq(
+            "q",
+            "edge_id",
+            edge.id,
+            "
+            e as uid
+            properties
+            ~edges {
+                f as uid
+            }",
+        );
+        let upsert = Upsert::new(&query).delete(json!([
+            {
+                "uid": "uid(f)",
+                "edges": { "uid": "uid(e)" },
+            },
+            {
+                "uid": "uid(e)",
+                "edge_id": null,
+                "properties": null,
+                "to": null,
+            },
+        ]));
+        let res: UpsertRoot = self.json_req(MUTATE, &upsert).await?;
+
+        let deleted_edge = res.data.first("q").ok_or(Error::EdgeNotFound)?;
+
+        Ok(Action::Mutate(
+            graph_id,
+            MutateKind::RecreateEdge((edge, node_properties(deleted_edge)?)),
+        ))
     }
 }
 
-struct Store {
+pub struct Store {
     undo: Vec<Action>,
     redo: Vec<Action>,
-    history: Vec<Action>,
+    undo_capacity: Option<usize>,
+    history: History,
+    saves_history: bool,
+    timeline: Timeline,
+    hash_cache: HashCache,
     client: reqwest::Client,
     base_url: String,
     auth_token: String,
+    user: String,
+    password: String,
+    namespace: u64,
+    tokens: Mutex<Option<LoginData>>,
+    txn: Mutex<Option<Transaction>>, // set while an action runs
+}
+
+// dgraph starts the transaction on the first request and tells its start_ts
+#[derive(Debug, Default)]
+struct Transaction {
+    start_ts: Option<i64>,
+    mutated: bool,
+    keys: Vec<String>,
+    preds: Vec<String>,
 }
 
 impl Store {
@@ -320,166 +553,1014 @@ impl Store {
         Store {
             undo: Vec::new(),
             redo: Vec::new(),
-            history: Vec::new(),
+            undo_capacity: cfg.undo_capacity,
+            history: cfg.client_id.map(History::new).unwrap_or_default(),
+            saves_history: cfg.client_id.is_some(),
+            timeline: Timeline::default(),
+            hash_cache: HashCache::default(),
             client,
             base_url: cfg.base_url.clone(),
             auth_token: cfg.auth_token.clone(),
+            user: cfg.user.clone(),
+            password: cfg.password.clone(),
+            namespace: cfg.namespace,
+            tokens: Mutex::new(None),
+            txn: Mutex::new(None),
         }
     }
 
-    async fn json_req<B: Serialize, T: DeserializeOwned>(
+    // a store whose schema is known to be up to date, with the history its client id
+    // had in the last run, processes sharing a database each need an id of their own
+    pub async fn connect(cfg: &Config) -> Result<Store> {
+        let mut store = Store::new(cfg);
+        store.migrate().await?;
+        store.load_history().await?;
+
+        Ok(store)
+    }
+
+    async fn load_history(&mut self) -> Result<()> {
+        if !self.saves_history {
+            return Ok(());
+        }
+
+        let client_id = self.history.client_id().to_string();
+        let query = Query::new()
+            .eq("h", "history_client", &client_id, "uid history")
+            .eq("e", "history_of", &client_id, "uid history_entry");
+        let res: QueryRoot = self.json_req(QUERY, &query).await?;
+
+        if let Some(head) = res.first("h").and_then(|node| node.history.as_ref()) {
+            let head = serde_json::from_str(head).map_err(Error::JsonError)?;
+            let entries = res
+                .nodes("e")
+                .iter()
+                .filter_map(|node| node.history_entry.as_ref())
+                .map(|entry| serde_json::from_str(entry).map_err(Error::JsonError))
+                .collect::<Result<Vec<_>>>()?;
+            self.history = History::from_parts(head, entries);
+        }
+
+        Ok(())
+    }
+
+    // mutations outside of an action commit right away
+    fn url(&self, url_part: &str) -> String {
+        let mut txn = self.txn.lock().unwrap();
+        let params = match txn.as_mut() {
+            Some(txn) => {
+                txn.mutated |= url_part == MUTATE;
+                match txn.start_ts {
+                    Some(start_ts) => format!("?startTs={}", start_ts),
+                    None => String::new(),
+                }
+            }
+            None if url_part == MUTATE => "?commitNow=true".into(),
+            None => String::new(),
+        };
+
+        format!("{}{}{}", self.base_url, url_part, params)
+    }
+
+    pub(crate) async fn json_req<B: Serialize + Sync, T: DeserializeOwned>(
         &self,
         url_part: &str,
         body: &B,
     ) -> Result<T> {
-        let url = self.base_url.to_owned() + url_part;
+        let url = self.url(url_part);
+        let json = self.send(|| self.client.post(&url).json(body)).await?;
+
+        self.parse_response(json)
+    }
+
+    pub(crate) async fn dql_req<S: Into<String>, T: DeserializeOwned>(
+        &self,
+        url_part: &str,
+        body: S,
+    ) -> Result<T> {
+        let url = self.url(url_part);
+        let body = body.into();
+        let json = self
+            .send(|| {
+                self.client
+                    .post(&url)
+                    .body(body.clone())
+                    .header("content-type", "application/dql")
+            })
+            .await?;
+
+        self.parse_response(json)
+    }
+
+    // commits or aborts the transaction of the action that just ran,
+    // one that never got to mutate has nothing to commit
+    async fn finish_txn(&self, abort: bool) -> Result<()> {
+        let txn = self.txn.lock().unwrap().take();
+        let (start_ts, txn) = match txn {
+            Some(txn) if txn.mutated => match txn.start_ts {
+                Some(start_ts) => (start_ts, txn),
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        let abort = if abort { "&abort=true" } else { "" };
+        let url = format!("{}{}?startTs={}{}", self.base_url, COMMIT, start_ts, abort);
+        let body = json!({ "keys": txn.keys, "preds": txn.preds });
+        let json = self.send(|| self.client.post(&url).json(&body)).await?;
+        let _: AlterRoot = self.parse_response(json)?;
+
+        Ok(())
+    }
+
+    // sends a request with the credentials, logging in first when there is a user,
+    // a request turned away for an expired access token is sent again after a refresh
+    async fn send<F>(&self, request: F) -> Result<JsonValue>
+    where
+        F: Fn() -> reqwest::RequestBuilder + Send + Sync,
+    {
+        if !self.user.is_empty() && self.tokens.lock().unwrap().is_none() {
+            self.login().await?;
+        }
 
         let res = self
-            .client
-            .post(url)
-            .header("x-auth-token", &self.auth_token)
-            .json(body)
+            .authorize(request())
             .send()
             .await
             .map_err(Error::HttpClientError)?;
+        match Self::check_err_response(res).await {
+            Err(Error::DGraphError(err)) if !self.user.is_empty() && is_expired(&err) => {
+                self.refresh().await?;
+                let res = self
+                    .authorize(request())
+                    .send()
+                    .await
+                    .map_err(Error::HttpClientError)?;
+                Self::check_err_response(res).await
+            }
+            json => json,
+        }
+    }
 
-        Self::parse_response(res).await
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request.header("x-auth-token", &self.auth_token);
+        match &*self.tokens.lock().unwrap() {
+            Some(tokens) => request.header("X-Dgraph-AccessToken", &tokens.access_jwt),
+            None => request,
+        }
     }
 
-    async fn dql_req<S: Into<String>, T: DeserializeOwned>(
-        &self,
-        url_part: &str,
-        body: S,
-    ) -> Result<T> {
-        let url = self.base_url.to_owned() + url_part;
+    async fn login(&self) -> Result<()> {
+        self.request_tokens(json!({
+            "userid": self.user,
+            "password": self.password,
+            "namespace": self.namespace,
+        }))
+        .await
+    }
 
+    // falls back to logging in again once the refresh token has expired too
+    async fn refresh(&self) -> Result<()> {
+        let refresh_jwt = self
+            .tokens
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|tokens| tokens.refresh_jwt.clone());
+
+        if let Some(refresh_jwt) = refresh_jwt {
+            match self
+                .request_tokens(json!({ "refresh_token": refresh_jwt }))
+                .await
+            {
+                Err(Error::DGraphError(err)) if is_expired(&err) => {}
+                res => return res,
+            }
+        }
+
+        self.login().await
+    }
+
+    async fn request_tokens(&self, body: JsonValue) -> Result<()> {
         let res = self
             .client
-            .post(url)
+            .post(format!("{}{}", self.base_url, LOGIN))
             .header("x-auth-token", &self.auth_token)
-            .body(body.into())
-            .header("content-type", "application/dql")
+            .json(&body)
             .send()
             .await
             .map_err(Error::HttpClientError)?;
+        let res: LoginRoot = self.parse_response(Self::check_err_response(res).await?)?;
+        *self.tokens.lock().unwrap() = Some(res.data);
 
-        Self::parse_response(res).await
+        Ok(())
     }
 
+    // dgraph answers the errors of a request with a 200, a 5xx is dgraph or a proxy in
+    // front of it failing
     async fn check_err_response(res: reqwest::Response) -> Result<JsonValue> {
+        if res.status().is_server_error() {
+            return Err(Error::RemoteUnavailable(format!(
+                "dgraph answered {}",
+                res.status()
+            )));
+        }
+
         let json = res
             .json::<JsonValue>()
             .await
             .map_err(Error::HttpClientError)?;
 
-        if json.as_object().unwrap().contains_key("errors") {
-            let err = serde_json::to_string_pretty(&json).map_err(Error::JsonError)?;
-            return Err(Error::DGraphError(err));
+        check_errors(json)
+    }
+
+    fn parse_response<T: DeserializeOwned>(&self, json: JsonValue) -> Result<T> {
+        log::debug!("{:#?}", json);
+
+        if let Some(txn) = self.txn.lock().unwrap().as_mut() {
+            if let Some(Ok(res_txn)) = json
+                .pointer("/extensions/txn")
+                .map(|res_txn| serde_json::from_value::<Txn>(res_txn.clone()))
+            {
+                txn.start_ts.get_or_insert(res_txn.start_ts);
+                txn.keys.extend(res_txn.keys);
+                txn.preds.extend(res_txn.preds);
+            }
         }
 
-        Ok(json)
+        serde_json::from_value(json).map_err(Error::JsonError)
     }
+}
 
-    async fn parse_response<T: DeserializeOwned>(res: reqwest::Response) -> Result<T> {
-        let json = Self::check_err_response(res).await?;
+// the error messages joined, or the conflict when dgraph aborted the transaction
+fn check_errors(json: JsonValue) -> Result<JsonValue> {
+    let object = json
+        .as_object()
+        .ok_or_else(|| Error::DGraphError(format!("unexpected response {}", json)))?;
+    if !object.contains_key("errors") {
+        return Ok(json);
+    }
 
-        println!("{:#?}", json);
+    let res: ErrorRoot = serde_json::from_value(json.clone())
+        .map_err(|_| Error::DGraphError(format!("unexpected errors {}", json)))?;
+    if res.errors.iter().any(|error| error.is_aborted()) {
+        return Err(Error::TransactionConflict);
+    }
 
-        serde_json::from_value(json).map_err(Error::JsonError)
+    let messages = res
+        .errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(Error::DGraphError(messages))
+}
+
+fn is_expired(err: &str) -> bool {
+    err.to_lowercase().contains("token is expired")
+}
+
+// properties are stored as a json string, dgraph can't list the predicates
+// of a node without a type naming them
+fn to_json_string(properties: &Properties) -> Result<String> {
+    serde_json::to_string(properties).map_err(Error::JsonError)
+}
+
+fn node_properties(node: &DNode) -> Result<Properties> {
+    match &node.properties {
+        Some(properties) => serde_json::from_str(properties).map_err(Error::JsonError),
+        None => Ok(Properties::new()),
     }
 }
 
+fn to_node(node_id: NodeId, node: &DNode) -> Result<Node> {
+    let (outbound_edges, inbound_edges) = node_edges(node_id, node)?;
+    let without_properties =
+        |edges: EdgesWithProperties| edges.into_iter().map(|(edge, _)| edge).collect();
+
+    Ok(Node {
+        node_id,
+        properties: node_properties(node)?,
+        outbound_edges: without_properties(outbound_edges),
+        inbound_edges: without_properties(inbound_edges),
+    })
+}
+
+type EdgesWithProperties = Vec<(Edge, Properties)>;
+
+// the outbound and inbound edges selected with NODE_EDGES
+fn node_edges(node_id: NodeId, node: &DNode) -> Result<(EdgesWithProperties, EdgesWithProperties)> {
+    let outbound_edges = node
+        .edges
+        .iter()
+        .flatten()
+        .map(|edge| {
+            Ok((
+                Edge {
+                    id: edge_id(edge)?,
+                    from: node_id,
+                    to: end_id(&edge.to)?,
+                },
+                node_properties(edge)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let inbound_edges = node
+        .in_edges
+        .iter()
+        .flatten()
+        .map(|edge| {
+            Ok((
+                Edge {
+                    id: edge_id(edge)?,
+                    from: end_id(&edge.from)?,
+                    to: node_id,
+                },
+                node_properties(edge)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((outbound_edges, inbound_edges))
+}
+
+fn edge_id(edge: &DNode) -> Result<EdgeId> {
+    let edge_id = edge.edge_id.as_deref().ok_or(Error::EdgeNotFound)?;
+    Ok(Uuid::from_str(edge_id)?)
+}
+
+// the node at the other end of an edge node
+fn end_id(end: &Option<Vec<DNode>>) -> Result<NodeId> {
+    let node = end.iter().flatten().next().ok_or(Error::NodeNotFound)?;
+    Ok(Uuid::from_str(&node.indra_id)?)
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    base_url: String,
-    auth_token: String,
+    pub base_url: String,
+    pub auth_token: String, // dgraph cloud api key
+    pub undo_capacity: Option<usize>,
+    // ACL login of a self-hosted dgraph, skipped without a user
+    pub user: String,
+    pub password: String,
+    pub namespace: u64,
+    // the history is kept under this id across runs, only for the connection without one
+    pub client_id: Option<Uuid>,
+}
+
+impl Config {
+    pub fn new(base_url: impl Into<String>, auth_token: impl Into<String>) -> Config {
+        Config {
+            base_url: base_url.into(),
+            auth_token: auth_token.into(),
+            ..Default::default()
+        }
+    }
+}
+
+// the secrets stay out of logs and error messages
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("base_url", &self.base_url)
+            .field("auth_token", &redacted(&self.auth_token))
+            .field("undo_capacity", &self.undo_capacity)
+            .field("user", &self.user)
+            .field("password", &redacted(&self.password))
+            .field("namespace", &self.namespace)
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+fn redacted(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Store as StoreImpl;
     use super::*;
-    use serde_json::json;
-    use std::str::FromStr;
-    use sunshine_core::store::Datastore;
-
-    fn make_store() -> StoreImpl {
-        StoreImpl::new(&Config {
-            base_url: "https://quiet-leaf.us-west-2.aws.cloud.dgraph.io".into(),
-            auth_token: "NmY2YWQ1YzlkNjg4NjUwMzc0MDJmMjk4ZTg3Yzk5Yzc=".into(),
-        })
+    use crate::mock::{values_at, MockDgraph};
+    use sunshine_core::test_utils::props;
+
+    fn make_store() -> (MockDgraph, Store) {
+        let (mock, url) = MockDgraph::spawn();
+        let config = Config {
+            client_id: Some(Uuid::new_v4()),
+            ..Config::new(url, "token")
+        };
+        (mock, Store::new(&config))
+    }
+
+    fn done() -> JsonValue {
+        json!({ "code": "Success", "message": "Done" })
+    }
+
+    fn edge(id: EdgeId, from: NodeId, to: NodeId) -> Edge {
+        Edge { id, from, to }
     }
 
-    #[tokio::test(flavor = "multi_thread")]
+    #[tokio::test]
     async fn test_update_state_id() {
-        make_store()
-            .update_state_id(Uuid::from_str("0d0bd4ee-40f0-11ec-973a-0242ac130003").unwrap())
+        let (mock, store) = make_store();
+        let graph_id = Uuid::new_v4();
+
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 4 }] }));
+        assert_eq!(store.update_state_id(graph_id).await.unwrap(), 5);
+        let requests = mock.take_requests();
+        assert_eq!(requests[0].path, "/mutate?commitNow=true");
+        let query = values_at(&requests, "/query").pop().unwrap();
+        assert!(query
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("{{ q(func: eq(indra_id, \"{}\"))", graph_id)));
+
+        mock.respond_upsert(json!({ "q": [] }));
+        assert!(matches!(
+            store.update_state_id(graph_id).await,
+            Err(Error::GraphNotFound)
+        ));
+
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x1" }] }));
+        store.set_state_id(graph_id, 7).await.unwrap();
+        let request = mock.take_requests().pop().unwrap().json();
+        assert_eq!(request["set"]["state_id"], json!(7));
+        assert_eq!(request["cond"], json!("@if(eq(len(u), 1))"));
+    }
+
+    #[tokio::test]
+    async fn test_graphs() {
+        let (mock, store) = make_store();
+        let (graph_id, a, b, e) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+
+        mock.respond(
+            "/mutate",
+            json!({ "code": "Success", "message": "Done", "uids": {} }),
+        );
+        store
+            .create_graph_with_id(graph_id, props(json!({ "name": "graph" })))
             .await
             .unwrap();
+        let set = &mock.take_requests()[0].json()["set"];
+        assert_eq!(set["indra_id"], json!(graph_id.to_string()));
+        assert_eq!(set["properties"], json!(r#"{"name":"graph"}"#));
+
+        mock.respond_query(json!({ "q": [{
+            "uid": "0x1",
+            "indra_id": graph_id.to_string(),
+            "properties": r#"{"name":"graph"}"#,
+        }] }));
+        let graphs = store.list_graphs().await.unwrap();
+        assert_eq!(graphs[0].0, graph_id);
+        assert_eq!(graphs[0].1["name"], json!("graph"));
+
+        mock.respond_query(json!({ "q": [{
+            "uid": "0x1",
+            "state_id": 3,
+            "link": [
+                {
+                    "uid": "0x2",
+                    "indra_id": a.to_string(),
+                    "properties": "{}",
+                    "edges": [{ "edge_id": e.to_string(), "to": [{ "indra_id": b.to_string() }] }],
+                },
+                {
+                    "uid": "0x3",
+                    "indra_id": b.to_string(),
+                    "properties": "{}",
+                    "in_edges": [{ "edge_id": e.to_string(), "from": [{ "indra_id": a.to_string() }] }],
+                },
+            ],
+        }] }));
+        let graph = store.read_graph(graph_id).await.unwrap();
+        assert_eq!(graph.state_id, 3);
+        assert_eq!(graph.nodes[0].outbound_edges, vec![edge(e, a, b)]);
+        assert_eq!(graph.nodes[1].inbound_edges, vec![edge(e, a, b)]);
+
+        mock.respond_query(json!({ "q": [] }));
+        assert!(matches!(
+            store.read_graph(graph_id).await,
+            Err(Error::GraphNotFound)
+        ));
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_create_graph_with_id() {
-        let store = make_store();
-        let properties = json!({
-            "name":"test",
-            "cost":2800,
+    #[tokio::test]
+    async fn test_update_and_undo() {
+        let (mock, mut store) = make_store();
+        let (graph_id, a) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // create, update and undo each run an upsert, bump the state id and save
+        // the history, then commit
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x1" }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 0 }] }))
+            .respond_upsert(json!({ "q": [] }))
+            .respond(COMMIT, done())
+            .respond_upsert(json!({ "q": [{ "uid": "0x2", "properties": r#"{"name":"a"}"# }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 1 }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x3" }] }))
+            .respond(COMMIT, done())
+            .respond_upsert(json!({ "q": [{ "uid": "0x2", "properties": r#"{"name":"b"}"# }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 2 }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x3" }] }))
+            .respond(COMMIT, done());
+
+        let actions = [
+            Action::Mutate(
+                graph_id,
+                MutateKind::CreateNodeWithId((a, props(json!({ "name": "a" })))),
+            ),
+            Action::Mutate(
+                graph_id,
+                MutateKind::UpdateNode((a, props(json!({ "name": "b" })))),
+            ),
+            Action::Undo,
+        ];
+        for action in actions {
+            store.execute(action).await.unwrap();
+        }
+
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 12);
+        assert_eq!(requests[0].path, "/mutate");
+        assert_eq!(requests[1].path, "/mutate?startTs=1");
+        assert_eq!(requests[2].path, "/mutate?startTs=1");
+        assert_eq!(requests[3].path, "/commit?startTs=1");
+        assert!(
+            values_at(&requests, "/cond").contains(&json!("@if(eq(len(u), 1) AND eq(len(e), 0))"))
+        );
+        assert_eq!(
+            values_at(&requests, "/set/link/indra_id"),
+            vec![json!(a.to_string())]
+        );
+        // the update, then its undo
+        assert_eq!(
+            values_at(&requests, "/set/properties"),
+            vec![json!(r#"{"name":"b"}"#), json!(r#"{"name":"a"}"#)]
+        );
+        match store.redo_buf().last() {
+            Some(Action::Mutate(_, MutateKind::UpdateNode((node_id, properties)))) => {
+                assert_eq!(*node_id, a);
+                assert_eq!(properties["name"], json!("b"));
+            }
+            action => panic!("unexpected redo {:?}", action),
+        }
+
+        // each action adds its entries to the history of the client and rewrites its head,
+        // the undo adds its reverse and itself
+        let client_id = store.history.client_id().to_string();
+        let saves: Vec<JsonValue> = values_at(&requests, "/set")
+            .into_iter()
+            .filter(|set| set[0]["history_client"] == json!(client_id))
+            .collect();
+        assert_eq!(saves.len(), 3);
+        assert_eq!(saves[2].as_array().unwrap().len(), 3);
+        assert_eq!(saves[2][2]["history_position"], json!(3));
+
+        // a store connecting later under the same client id picks up the history
+        let head = saves[2][0]["history"].clone();
+        let entries: Vec<JsonValue> = saves
+            .iter()
+            .flat_map(|set| set.as_array().unwrap()[1..].to_vec())
+            .collect();
+        let mut reopened = Store::new(&Config {
+            client_id: Some(store.history.client_id()),
+            ..Config::new(store.base_url.clone(), "token")
         });
-        let properties = match properties {
-            JsonValue::Object(props) => props,
-            _ => unreachable!(),
-        };
+        mock.respond_query(json!({
+            "h": [{ "uid": "0x3", "history": head }],
+            "e": entries,
+        }));
+        reopened.load_history().await.unwrap();
+        let query = mock.take_requests()[0].json();
+        assert_eq!(query["variables"]["$h"], json!(client_id));
+        assert_eq!(reopened.history.client_id(), store.history.client_id());
+        assert_eq!(reopened.history.len(), 4);
+        let action_id = store.history.entries()[0].0;
+        assert!(reopened.history.contains(action_id));
+
+        mock.respond_upsert(json!({ "q": [] }));
+        assert!(matches!(
+            store.update_node((a, Properties::new()), graph_id).await,
+            Err(Error::NodeNotFound)
+        ));
+
+        // a replay finds the node and the condition keeps it from being created twice
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x1" }], "existing": [{ "uid": "0x2" }] }));
+        assert!(matches!(
+            store.create_node_with_id(a, (graph_id, Properties::new())).await,
+            Err(Error::NodeAlreadyExists(node_id)) if node_id == a
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transactions() {
+        let (mock, mut store) = make_store();
+        let (graph_id, a) = (Uuid::new_v4(), Uuid::new_v4());
+        let update = Action::Mutate(
+            graph_id,
+            MutateKind::UpdateNode((a, props(json!({ "name": "b" })))),
+        );
+        let conflict = json!({ "errors": [{
+            "message": "Transaction has been aborted. Please retry",
+        }] });
+
+        // a conflicting commit runs the whole action again
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x2", "properties": "{}" }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 0 }] }))
+            .respond_upsert(json!({ "q": [] }))
+            .respond_raw(COMMIT, conflict.clone())
+            .respond_upsert(json!({ "q": [{ "uid": "0x2", "properties": "{}" }] }))
+            .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 0 }] }))
+            .respond_upsert(json!({ "q": [] }))
+            .respond(COMMIT, done());
+        store.execute(update.clone()).await.unwrap();
+        assert_eq!(mock.take_requests().len(), 8);
+        assert_eq!(store.undo_buf().len(), 1);
+        assert_eq!(store.history_buf().len(), 1);
+
+        // a retry of the action is answered from the history
+        let action_id = store.history_buf().entries()[0].0;
         store
-            .create_graph_with_id(
-                Uuid::from_str("0d0bd4ee-40f0-11ec-973a-0242ac130003").unwrap(),
-                properties,
-            )
+            .execute_with_id(action_id, update.clone())
             .await
             .unwrap();
+        assert!(mock.take_requests().is_empty());
+
+        // a failed action is aborted and leaves the buffers as they were
+        mock.respond_upsert(json!({ "q": [] }))
+            .respond(COMMIT, done());
+        assert!(matches!(
+            store.execute(update.clone()).await,
+            Err(Error::NodeNotFound)
+        ));
+        assert_eq!(mock.take_requests()[1].path, "/commit?startTs=1&abort=true");
+        assert_eq!(store.undo_buf().len(), 1);
+        assert_eq!(store.history_buf().len(), 1);
+
+        // and conflicts are given up on eventually
+        for _ in 0..MAX_ATTEMPTS {
+            mock.respond_upsert(json!({ "q": [{ "uid": "0x2", "properties": "{}" }] }))
+                .respond_upsert(json!({ "q": [{ "uid": "0x1", "state_id": 1 }] }))
+                .respond_upsert(json!({ "q": [{ "uid": "0x3" }] }))
+                .respond_raw(COMMIT, conflict.clone());
+        }
+        assert!(matches!(
+            store.execute(update).await,
+            Err(Error::TransactionConflict)
+        ));
+        mock.take_requests();
+        assert_eq!(store.history_buf().len(), 1);
+
+        // state 2 was never committed, so the timeline doesn't know it
+        let current = Subgraph {
+            nodes: vec![(a, props(json!({ "name": "b" })))],
+            edges: Vec::new(),
+        };
+        assert!(store
+            .timeline()
+            .rewind(graph_id, (1, current.clone()), 0)
+            .is_ok());
+        assert!(matches!(
+            store.timeline().rewind(graph_id, (2, current), 1),
+            Err(Error::StateNotFound(1))
+        ));
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_list_graphs() {
-        dbg!(make_store().list_graphs().await);
+    #[test]
+    fn test_check_errors() {
+        let data = json!({ "data": { "q": [] } });
+        assert_eq!(check_errors(data.clone()).unwrap(), data);
+
+        assert!(matches!(
+            check_errors(json!(["not", "an", "object"])),
+            Err(Error::DGraphError(_))
+        ));
+        assert!(matches!(
+            check_errors(json!({ "errors": "not a list" })),
+            Err(Error::DGraphError(_))
+        ));
+
+        let aborted = json!({ "errors": [{
+            "message": "Transaction has been aborted. Please retry",
+            "extensions": { "code": "ErrorInvalidRequest" },
+        }] });
+        assert!(matches!(
+            check_errors(aborted),
+            Err(Error::TransactionConflict)
+        ));
+
+        // an abort quoted in another error's data isn't a conflict
+        let invalid = json!({
+            "errors": [{
+                "message": "while parsing query",
+                "extensions": { "code": "ErrorInvalidRequest" },
+            }],
+            "data": { "text": "Transaction has been aborted" },
+        });
+        match check_errors(invalid) {
+            Err(Error::DGraphError(err)) => {
+                assert_eq!(err, "ErrorInvalidRequest: while parsing query")
+            }
+            res => panic!("unexpected {:?}", res),
+        }
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_read_graph() {
-        dbg!(
-            make_store()
-                .read_graph(Uuid::from_str("2ac209c6-40ce-11ec-9884-8b4b20e8c2eb").unwrap())
-                .await
+    #[tokio::test]
+    async fn test_login() {
+        let (mock, url) = MockDgraph::spawn();
+        let config = Config {
+            base_url: url,
+            user: "groot".into(),
+            password: "password".into(),
+            namespace: 1,
+            ..Default::default()
+        };
+        assert!(!format!("{:?}", config).contains("\"password\""));
+        let store = Store::new(&config);
+        let tokens =
+            |access: &str, refresh: &str| json!({ "accessJWT": access, "refreshJWT": refresh });
+        let expired = json!({ "errors": [{
+            "message": "unable to parse jwt token:Token is expired",
+        }] });
+
+        // the first request logs in
+        mock.respond(LOGIN, tokens("access1", "refresh1"))
+            .respond_query(json!({ "q": [] }));
+        store.list_graphs().await.unwrap();
+        let requests = mock.take_requests();
+        assert_eq!(
+            requests[0].json(),
+            json!({ "userid": "groot", "password": "password", "namespace": 1 })
+        );
+        assert_eq!(
+            requests.last().unwrap().access_token.as_deref(),
+            Some("access1")
+        );
+
+        // an expired access token is refreshed and the request sent again
+        mock.respond_raw(QUERY, expired.clone())
+            .respond(LOGIN, tokens("access2", "refresh2"))
+            .respond_query(json!({ "q": [] }));
+        store.list_graphs().await.unwrap();
+        let requests = mock.take_requests();
+        assert_eq!(
+            values_at(&requests, "/refresh_token"),
+            vec![json!("refresh1")]
+        );
+        assert_eq!(
+            requests.last().unwrap().access_token.as_deref(),
+            Some("access2")
+        );
+
+        // and once the refresh token expired as well the user logs in again
+        mock.respond_raw(QUERY, expired.clone())
+            .respond_raw(LOGIN, expired)
+            .respond(LOGIN, tokens("access3", "refresh3"))
+            .respond_query(json!({ "q": [] }));
+        store.list_graphs().await.unwrap();
+        let requests = mock.take_requests();
+        assert_eq!(values_at(&requests, "/userid"), vec![json!("groot")]);
+        assert_eq!(
+            requests.last().unwrap().access_token.as_deref(),
+            Some("access3")
         );
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_create_node_with_id() {
-        let properties = json!({
-            "name":"first Node",
-            "age": 13
-        });
-        let properties = match properties {
-            JsonValue::Object(props) => props,
-            _ => unreachable!(),
+    #[tokio::test]
+    async fn test_delete_and_recreate_node() {
+        let (mock, store) = make_store();
+        let (graph_id, a, b, c) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        let (outbound, inbound) = (edge(Uuid::new_v4(), a, b), edge(Uuid::new_v4(), c, a));
+
+        mock.respond_query(json!({ "q": [{
+            "uid": "0x2",
+            "indra_id": a.to_string(),
+            "properties": r#"{"name":"a"}"#,
+            "edges": [{
+                "edge_id": outbound.id.to_string(),
+                "properties": r#"{"weight":1}"#,
+                "to": [{ "indra_id": b.to_string() }],
+            }],
+            "in_edges": [{
+                "edge_id": inbound.id.to_string(),
+                "properties": r#"{"weight":2}"#,
+                "from": [{ "indra_id": c.to_string() }],
+            }],
+        }] }))
+        .respond_upsert(json!({ "q": [{ "uid": "0x2" }] }));
+
+        let recreate_node = match store.delete_node(a, graph_id).await.unwrap() {
+            Action::Mutate(_, MutateKind::RecreateNode(recreate_node)) => recreate_node,
+            action => panic!("unexpected reverse {:?}", action),
         };
+        assert_eq!(recreate_node.properties["name"], json!("a"));
+        assert_eq!(recreate_node.edges[0].0, inbound);
+        assert_eq!(recreate_node.edges[0].1["weight"], json!(2));
+        assert_eq!(recreate_node.edges[1].0, outbound);
+        assert_eq!(recreate_node.edges[1].1["weight"], json!(1));
+
+        // one query for the node and its edges, then the upsert deleting them
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            values_at(&requests, "/delete")[0].as_array().unwrap().len(),
+            5
+        );
 
-        dbg!(
-            make_store()
-                .create_node_with_id(
-                    Uuid::from_str("5dd79972-4329-11ec-81d3-0242ac130003").unwrap(),
-                    (
-                        Uuid::from_str("0d0bd4ee-40f0-11ec-973a-0242ac130003").unwrap(),
-                        properties
-                    )
-                )
-                .await
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x1" }] }))
+            .respond_upsert(json!({ "from": [{ "uid": "0x3" }], "to": [{ "uid": "0x6" }] }))
+            .respond_upsert(json!({ "from": [{ "uid": "0x6" }], "to": [{ "uid": "0x4" }] }));
+        assert!(matches!(
+            store.recreate_node(recreate_node, graph_id).await.unwrap(),
+            Action::Mutate(_, MutateKind::DeleteNode(node_id)) if node_id == a
+        ));
+        let requests = mock.take_requests();
+        assert_eq!(
+            values_at(&requests, "/set/edges/edge_id"),
+            vec![
+                json!(inbound.id.to_string()),
+                json!(outbound.id.to_string())
+            ]
         );
+        let edge_froms: Vec<bool> = values_at(&requests, "/query")[1..]
+            .iter()
+            .zip([c, a])
+            .map(|(query, from)| {
+                let from = format!("from(func: eq(indra_id, \"{}\"))", from);
+                query.as_str().unwrap().contains(&from)
+            })
+            .collect();
+        assert_eq!(edge_froms, vec![true, true]);
     }
-}
 
-// 0xfffd8d6aac73f42d
-// 2ac209c6-40ce-11ec-9884-8b4b20e8c2eb
-//0d0bd4ee-40f0-11ec-973a-0242ac130003
+    #[tokio::test]
+    async fn test_edges() {
+        let (mock, store) = make_store();
+        let (graph_id, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let create_edge = CreateEdge {
+            from: a,
+            to: b,
+            properties: props(json!({ "weight": 1 })),
+        };
+
+        mock.respond_upsert(json!({ "from": [{ "uid": "0x2" }], "to": [] }));
+        assert!(matches!(
+            store.create_edge(create_edge.clone(), graph_id).await,
+            Err(Error::NodeNotFound)
+        ));
+
+        mock.respond_upsert(json!({ "from": [{ "uid": "0x2" }], "to": [{ "uid": "0x3" }] }));
+        let (reverse, edge_id) = store.create_edge(create_edge, graph_id).await.unwrap();
+        let created = edge(edge_id, a, b);
+        assert!(matches!(
+            reverse,
+            Action::Mutate(_, MutateKind::DeleteEdge(edge)) if edge == created
+        ));
+        // the upsert refused for the missing node sent the same mutation
+        assert_eq!(
+            values_at(&mock.take_requests(), "/set/edges/properties"),
+            vec![json!(r#"{"weight":1}"#); 2]
+        );
+
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x4", "properties": r#"{"weight":1}"# }] }));
+        match store
+            .update_edge((created, props(json!({ "weight": 2 }))), graph_id)
+            .await
+            .unwrap()
+        {
+            Action::Mutate(_, MutateKind::UpdateEdge((edge, properties))) => {
+                assert_eq!(edge, created);
+                assert_eq!(properties["weight"], json!(1));
+            }
+            action => panic!("unexpected reverse {:?}", action),
+        }
+
+        mock.respond_upsert(json!({ "q": [{ "uid": "0x4", "properties": r#"{"weight":2}"# }] }))
+            .respond_upsert(json!({ "q": [] }));
+        match store.delete_edge(created, graph_id).await.unwrap() {
+            Action::Mutate(_, MutateKind::RecreateEdge((edge, properties))) => {
+                assert_eq!(edge, created);
+                assert_eq!(properties["weight"], json!(2));
+            }
+            action => panic!("unexpected reverse {:?}", action),
+        }
+        assert!(matches!(
+            store.delete_edge(created, graph_id).await,
+            Err(Error::EdgeNotFound)
+        ));
+    }
+
+    // the mock doesn't run DQL, this runs the queries against a real server, started with
+    // `dgraph standalone` and run with `cargo test -p sunshine_dgraph -- --ignored`,
+    // SUNSHINE_TEST_DGRAPH_URL points it elsewhere than http://localhost:8080
+    #[tokio::test]
+    #[ignore = "needs a running dgraph server"]
+    async fn test_against_dgraph() {
+        let base_url = std::env::var("SUNSHINE_TEST_DGRAPH_URL")
+            .unwrap_or_else(|_| "http://localhost:8080".into());
+        let auth_token = std::env::var("SUNSHINE_TEST_DGRAPH_AUTH_TOKEN").unwrap_or_default();
+        let config = Config {
+            client_id: Some(Uuid::new_v4()),
+            ..Config::new(base_url, auth_token)
+        };
+        let mut store = Store::connect(&config).await.unwrap();
+
+        let graph_id = store
+            .execute(Action::CreateGraph(props(json!({ "name": "test" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let mut node_ids = Vec::new();
+        for name in ["a", "b"] {
+            let node_id = store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateNode(props(json!({ "name": name }))),
+                ))
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+            node_ids.push(node_id);
+        }
+        let (a, b) = (node_ids[0], node_ids[1]);
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateEdge(CreateEdge {
+                    from: a,
+                    to: b,
+                    properties: props(json!({ "weight": 1 })),
+                }),
+            ))
+            .await
+            .unwrap();
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::UpdateNode((a, props(json!({ "name": "A" })))),
+            ))
+            .await
+            .unwrap();
+
+        let graph = store.read_graph(graph_id).await.unwrap();
+        let node_a = graph.nodes.iter().find(|node| node.node_id == a).unwrap();
+        assert_eq!(node_a.properties["name"], json!("A"));
+        assert_eq!(node_a.outbound_edges.len(), 1);
 
-// history: CreateNode, UpdateNode ....
+        // deleting a node takes its edges along, undo brings both back
+        store
+            .execute(Action::Mutate(graph_id, MutateKind::DeleteNode(a)))
+            .await
+            .unwrap();
+        let graph = store.read_graph(graph_id).await.unwrap();
+        assert_eq!(graph.nodes.len(), 1);
+        assert!(graph.nodes[0].inbound_edges.is_empty());
+
+        store.execute(Action::Undo).await.unwrap();
+        let graph = store.read_graph(graph_id).await.unwrap();
+        let node_b = graph.nodes.iter().find(|node| node.node_id == b).unwrap();
+        assert_eq!(node_b.inbound_edges.len(), 1);
+        let edge = node_b.inbound_edges[0];
+        assert_eq!(
+            store.read_edge_properties(edge).await.unwrap()["weight"],
+            json!(1)
+        );
 
-// history: CreateNode
+        store.execute(Action::DeleteGraph(graph_id)).await.unwrap();
+        assert!(matches!(
+            store.read_graph(graph_id).await,
+            Err(Error::GraphNotFound)
+        ));
+
+        // the history of the client comes back with its next connection, another client
+        // starts one of its own
+        let reconnected = Store::connect(&config).await.unwrap();
+        assert_eq!(reconnected.history.client_id(), store.history.client_id());
+        assert_eq!(reconnected.history.len(), store.history.len());
+        let other = Store::connect(&Config {
+            client_id: Some(Uuid::new_v4()),
+            ..config
+        })
+        .await
+        .unwrap();
+        assert!(other.history.is_empty());
+    }
+}
@@ -1,19 +1,24 @@
 use async_trait::async_trait;
 use indradb::{
-    Datastore as IndraDatastore, EdgeKey, EdgePropertyQuery, RangeVertexQuery, RocksdbDatastore,
-    SpecificEdgeQuery, SpecificVertexQuery, Transaction, Type, Vertex, VertexPropertyQuery,
-    VertexQuery, VertexQueryExt,
+    Datastore as IndraDatastore, EdgeKey, EdgePropertyQuery, MemoryDatastore, RangeVertexQuery,
+    RocksdbDatastore, SpecificEdgeQuery, SpecificVertexQuery, Transaction, Type, Vertex,
+    VertexPropertyQuery, VertexQuery, VertexQueryExt,
 };
 
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use uuid::Uuid;
 
 use sunshine_core::error::*;
+use sunshine_core::hash::HashCache;
+use sunshine_core::history::History;
 use sunshine_core::msg::{
     Action, CreateEdge, Edge, EdgeId, Graph, GraphId, MutateKind, Node, NodeId, Properties,
     RecreateNode,
 };
 use sunshine_core::store::Datastore;
+use sunshine_core::timeline::{Timeline, TimelineEntry};
 
 const VERTEX_PROPERTY_HOLDER: &str = "data";
 const VERTEX_TYPE: &str = "node";
@@ -21,40 +26,163 @@ const VERTEX_TYPE: &str = "node";
 const GRAPH_ROOT_TYPE: &str = "_root_type";
 const STATE_ID_PROPERTY: &str = "_state_id_prop";
 
+// the history lives next to the graphs, its head in a vertex under a fixed id
+// and each entry in a vertex of its own, under the ids after it in the order of their positions
+const HISTORY_TYPE: &str = "_history";
+const HISTORY_ENTRY_TYPE: &str = "_history_entry";
+const HISTORY_ID: Uuid = Uuid::from_u128(0x5e7a_1d0b_4a11_4c3e_9b1f_0000_0000_0001);
+
+fn history_entry_id(position: usize) -> Uuid {
+    Uuid::from_u128(HISTORY_ID.as_u128() + 1 + position as u128)
+}
+
+// the timeline is saved with it, a vertex for each state still kept, under the id of
+// its graph with the state id in the last bits, flipped by a mask so it can't be a node
+const TIMELINE_ENTRY_TYPE: &str = "_timeline_entry";
+const TIMELINE_MASK: u128 = 0x7a3c_95e1_0d4b_4f62_0000_0000_0000_0000;
+
+fn timeline_entry_id(graph_id: GraphId, state_id: u64) -> Uuid {
+    Uuid::from_u128(graph_id.as_u128() ^ TIMELINE_MASK ^ u128::from(state_id))
+}
+
 pub fn generate_uuid_v1() -> Uuid {
     indradb::util::generate_uuid_v1()
 }
 
+// only a graph root has a state id, any other node isn't a graph
+fn state_id_of(properties: &Properties) -> Result<u64> {
+    properties
+        .get(STATE_ID_PROPERTY)
+        .and_then(JsonValue::as_u64)
+        .ok_or(Error::GraphNotFound)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct DbConfig {
-    pub db_path: String,
+    pub db_path: String, // unused in memory
+    pub undo_capacity: Option<usize>,
 }
 
+// RocksDB on disk unless created in memory
 #[derive(Debug)]
-pub struct DB {
-    source: RocksdbDatastore,
+pub struct DB<D = RocksdbDatastore> {
+    source: D,
     root_node_type: Type,
     undo: Vec<Action>,
     redo: Vec<Action>,
-    history: Vec<Action>,
+    undo_capacity: Option<usize>,
+    history: History,
+    timeline: Timeline,
+    hash_cache: HashCache,
 }
 
 impl DB {
     pub fn new(cfg: &DbConfig) -> Result<DB> {
         let rocks_db = RocksdbDatastore::new(&cfg.db_path, None).map_err(Error::DatastoreCreate)?;
-        let db = DB {
-            source: rocks_db,
+        DB::with_source(rocks_db, cfg).load_history()
+    }
+}
+
+// nothing outlives the store, for tests and scratch sessions
+pub type MemoryDB = DB<MemoryDatastore>;
+
+impl MemoryDB {
+    pub fn in_memory(cfg: &DbConfig) -> MemoryDB {
+        DB::with_source(MemoryDatastore::default(), cfg)
+    }
+}
+
+impl Default for MemoryDB {
+    fn default() -> Self {
+        MemoryDB::in_memory(&DbConfig::default())
+    }
+}
+
+impl<D: IndraDatastore> DB<D> {
+    fn with_source(source: D, cfg: &DbConfig) -> DB<D> {
+        DB {
+            source,
             root_node_type: Type::new(GRAPH_ROOT_TYPE).unwrap(),
             undo: Vec::new(),
             redo: Vec::new(),
-            history: Vec::new(),
-        };
-        Ok(db)
+            undo_capacity: cfg.undo_capacity,
+            history: History::default(),
+            timeline: Timeline::default(),
+            hash_cache: HashCache::default(),
+        }
     }
 
     fn transaction(&self) -> Result<impl Transaction> {
         self.source.transaction().map_err(Error::CreateTransaction)
     }
 
+    // picks up the client id, the applied ids and the past states of the last run
+    fn load_history(mut self) -> Result<Self> {
+        let trans = self.transaction()?;
+        let timeline = self.read_entries::<TimelineEntry>(&trans, TIMELINE_ENTRY_TYPE)?;
+        self.timeline.load(timeline);
+
+        let head = trans
+            .get_vertex_properties(VertexPropertyQuery {
+                inner: SpecificVertexQuery::single(HISTORY_ID).into(),
+                name: VERTEX_PROPERTY_HOLDER.into(),
+            })
+            .map_err(Error::GetNodes)?
+            .pop();
+        let head = match head {
+            Some(property) => serde_json::from_value(property.value).map_err(Error::JsonError)?,
+            None => return Ok(self),
+        };
+
+        let entries = self.read_entries(&trans, HISTORY_ENTRY_TYPE)?;
+        self.history = History::from_parts(head, entries);
+
+        Ok(self)
+    }
+
+    fn read_entries<T: DeserializeOwned>(
+        &self,
+        trans: &impl Transaction,
+        t: &str,
+    ) -> Result<Vec<T>> {
+        trans
+            .get_vertex_properties(VertexPropertyQuery {
+                inner: RangeVertexQuery {
+                    limit: u32::MAX,
+                    t: Some(Type::new(t).map_err(Error::CreateType)?),
+                    start_id: None,
+                }
+                .into(),
+                name: VERTEX_PROPERTY_HOLDER.into(),
+            })
+            .map_err(Error::GetNodes)?
+            .into_iter()
+            .map(|property| serde_json::from_value(property.value).map_err(Error::JsonError))
+            .collect()
+    }
+
+    fn write_entry<T: Serialize>(
+        &self,
+        trans: &impl Transaction,
+        (entry_id, entry_type): (Uuid, &Type),
+        entry: &T,
+    ) -> Result<()> {
+        trans
+            .create_vertex(&Vertex::with_id(entry_id, entry_type.clone()))
+            .map_err(Error::CreateNode)?;
+        let entry = serde_json::to_value(entry).map_err(Error::JsonError)?;
+        trans
+            .set_vertex_properties(
+                VertexPropertyQuery {
+                    inner: SpecificVertexQuery::single(entry_id).into(),
+                    name: VERTEX_PROPERTY_HOLDER.into(),
+                },
+                &entry,
+            )
+            .map_err(Error::SetNodeProperties)
+    }
+
     pub async fn create_graph_root(
         &self,
         graph_id: GraphId,
@@ -81,7 +209,11 @@ impl DB {
 }
 
 #[async_trait]
-impl Datastore for DB {
+impl<D> Datastore for DB<D>
+where
+    D: IndraDatastore + Send + Sync,
+    D::Trans: Send,
+{
     fn undo_buf(&mut self) -> &mut Vec<Action> {
         &mut self.undo
     }
@@ -90,18 +222,71 @@ impl Datastore for DB {
         &mut self.redo
     }
 
-    fn history_buf(&mut self) -> &mut Vec<Action> {
+    fn undo_capacity(&self) -> Option<usize> {
+        self.undo_capacity
+    }
+
+    fn history(&self) -> &History {
+        &self.history
+    }
+
+    fn history_buf(&mut self) -> &mut History {
         &mut self.history
     }
 
-    async fn update_state_id(&self, graph_id: Uuid) -> Result<()> {
+    fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    fn hash_cache(&self) -> Option<&HashCache> {
+        Some(&self.hash_cache)
+    }
+
+    fn timeline_buf(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+
+    // the new entries are added and the dropped ones removed, only the head is rewritten,
+    // the states of the timeline that changed are written or removed the same way
+    async fn save_history(&mut self) -> Result<()> {
+        let trans = self.transaction()?;
+        let (entries, dropped) = self.history.unsaved();
+
+        let entry_type = Type::new(HISTORY_ENTRY_TYPE).map_err(Error::CreateType)?;
+        for entry in entries {
+            self.write_entry(
+                &trans,
+                (history_entry_id(entry.position), &entry_type),
+                &entry,
+            )?;
+        }
+        let mut removed: Vec<Uuid> = dropped.map(history_entry_id).collect();
+
+        let entry_type = Type::new(TIMELINE_ENTRY_TYPE).map_err(Error::CreateType)?;
+        for entry in self.timeline.unsaved() {
+            let entry_id = timeline_entry_id(entry.graph_id, entry.state_id);
+            match (&entry.reverse, &entry.checkpoint) {
+                (None, None) => removed.push(entry_id),
+                _ => self.write_entry(&trans, (entry_id, &entry_type), &entry)?,
+            }
+        }
+        if !removed.is_empty() {
+            trans
+                .delete_vertices(VertexQuery::Specific(SpecificVertexQuery::new(removed)))
+                .map_err(Error::DeleteNode)?;
+        }
+
+        let history_type = Type::new(HISTORY_TYPE).map_err(Error::CreateType)?;
+        self.write_entry(&trans, (HISTORY_ID, &history_type), &self.history.head())?;
+
+        self.history.mark_saved();
+        self.timeline.mark_saved();
+        Ok(())
+    }
+
+    async fn update_state_id(&self, graph_id: Uuid) -> Result<u64> {
         let mut graph_root = self.read_node(graph_id).await?;
-        let current_id = graph_root
-            .properties
-            .get(STATE_ID_PROPERTY)
-            .unwrap()
-            .as_u64()
-            .unwrap();
+        let current_id = state_id_of(&graph_root.properties)?;
         let new_id = JsonValue::Number(serde_json::Number::from(current_id + 1));
 
         graph_root
@@ -111,7 +296,24 @@ impl Datastore for DB {
         self.update_node((graph_id, graph_root.properties), graph_id)
             .await?;
 
-        Ok(())
+        Ok(current_id + 1)
+    }
+
+    async fn set_state_id(&self, graph_id: GraphId, state_id: u64) -> Result<()> {
+        let mut graph_root = self.read_node(graph_id).await?;
+        graph_root
+            .properties
+            .insert(STATE_ID_PROPERTY.into(), state_id.into());
+
+        self.update_node((graph_id, graph_root.properties), graph_id)
+            .await
+            .map(|_| ())
+    }
+
+    // only the graph root, the nodes aren't read
+    async fn read_state_id(&self, graph_id: GraphId) -> Result<u64> {
+        let graph_root = self.read_node(graph_id).await?;
+        state_id_of(&graph_root.properties)
     }
 
     async fn create_graph_with_id(
@@ -132,7 +334,8 @@ impl Datastore for DB {
         let trans = self.transaction()?;
         let futures = trans
             .get_vertices(RangeVertexQuery {
-                limit: 0,
+                // a limit of 0 returns no vertex at all
+                limit: u32::MAX,
                 t: Some(self.root_node_type.clone()),
                 start_id: None,
             })
@@ -155,14 +358,13 @@ impl Datastore for DB {
 
         let nodes = futures::future::try_join_all(nodes).await?;
 
-        let state_id = graph_node
-            .properties
-            .get(STATE_ID_PROPERTY)
-            .unwrap()
-            .as_u64()
-            .unwrap();
+        let state_id = state_id_of(&graph_node.properties)?;
 
-        Ok(Graph { nodes, state_id })
+        Ok(Graph {
+            nodes,
+            state_id,
+            hash: None,
+        })
     }
 
     async fn create_node_with_id(
@@ -174,7 +376,9 @@ impl Datastore for DB {
 
         let node_type = Type::new(VERTEX_TYPE).map_err(Error::CreateType)?;
         let node: Vertex = Vertex::with_id(node_id, node_type);
-        trans.create_vertex(&node).map_err(Error::CreateNode)?;
+        if !trans.create_vertex(&node).map_err(Error::CreateNode)? {
+            return Err(Error::NodeAlreadyExists(node_id));
+        }
 
         let vertex_query = SpecificVertexQuery::single(node.id).into();
 
@@ -224,6 +428,8 @@ impl Datastore for DB {
                     .unwrap()
                     .value
             }
+            // a missing node has no properties at all
+            0 => return Err(Error::NodeNotFound),
             _ => unreachable!(),
         };
 
@@ -345,6 +551,19 @@ impl Datastore for DB {
 
         let deleted_node = self.read_node(node_id).await?;
 
+        // edge properties are gone once the edges are deleted
+        let edges = deleted_node
+            .inbound_edges
+            .into_iter()
+            .chain(deleted_node.outbound_edges)
+            .map(|edge| async move {
+                self.read_edge_properties(edge)
+                    .await
+                    .map(|props| (edge, props))
+            });
+
+        let edges = futures::future::try_join_all(edges).await?;
+
         let outbound_query = query.clone().outbound();
         let inbound_query = query.clone().inbound();
         trans
@@ -357,18 +576,6 @@ impl Datastore for DB {
             .delete_vertices(VertexQuery::Specific(query))
             .map_err(Error::DeleteNode)?;
 
-        let edges = deleted_node
-            .inbound_edges
-            .into_iter()
-            .chain(deleted_node.outbound_edges.into_iter())
-            .map(|edge| async move {
-                self.read_edge_properties(edge)
-                    .await
-                    .map(|props| (edge, props))
-            });
-
-        let edges = futures::future::try_join_all(edges).await?;
-
         Ok(Action::Mutate(
             graph_id,
             MutateKind::RecreateNode(RecreateNode {
@@ -425,7 +632,7 @@ impl Datastore for DB {
 
         let properties = match properties.len() {
             1 => properties.pop().unwrap().value,
-            0 => JsonValue::Null,
+            0 => JsonValue::Object(Properties::new()),
             _ => unreachable!(),
         };
 
@@ -440,7 +647,7 @@ impl Datastore for DB {
         (edge, properties): (Edge, Properties),
         graph_id: GraphId,
     ) -> Result<Action> {
-        let prev_state = self.read_node(edge.id).await?;
+        let prev_properties = self.read_edge_properties(edge).await?;
 
         let trans = self.transaction()?;
         let edge_key = edge.into();
@@ -458,7 +665,7 @@ impl Datastore for DB {
 
         Ok(Action::Mutate(
             graph_id,
-            MutateKind::UpdateEdge((edge, prev_state.properties)),
+            MutateKind::UpdateEdge((edge, prev_properties)),
         ))
     }
 
@@ -468,19 +675,691 @@ impl Datastore for DB {
         let query = SpecificEdgeQuery {
             keys: vec![edge_key],
         };
-        trans.delete_edges(query).map_err(Error::DeleteEdge)?;
         let properties = self.read_edge_properties(edge).await?;
+        trans.delete_edges(query).map_err(Error::DeleteEdge)?;
         Ok(Action::Mutate(
             graph_id,
-            MutateKind::CreateEdge(CreateEdge {
-                to: edge.to,
-                from: edge.from,
-                properties,
-            }),
+            MutateKind::RecreateEdge((edge, properties)),
         ))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use serde_json::json;
+    use sunshine_core::crdt::{apply_to_datastore, GraphCrdt};
+    use sunshine_core::hash::content_hash;
+    use sunshine_core::merge::{self, merge_with_datastore, Side};
+    use sunshine_core::msg::{ActionId, QueryKind, Reply, Subgraph};
+    use sunshine_core::test_utils::props;
+
+    async fn make_graph() -> (MemoryDB, GraphId) {
+        let mut store = MemoryDB::default();
+        let graph_id = store
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        (store, graph_id)
+    }
+
+    async fn create_node(store: &mut MemoryDB, graph_id: GraphId, name: &str) -> NodeId {
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": name }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap()
+    }
+
+    async fn read_at(store: &mut MemoryDB, graph_id: GraphId, state_id: u64) -> Result<Reply> {
+        store
+            .execute(Action::Query(QueryKind::ReadGraphAt { graph_id, state_id }))
+            .await
+    }
+
+    #[test]
+    fn test_delete_edge_and_undo() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+            let a = create_node(&mut store, graph_id, "a").await;
+            let b = create_node(&mut store, graph_id, "b").await;
+            let edge = Edge {
+                id: generate_uuid_v1(),
+                from: a,
+                to: b,
+            };
+
+            // an edge without stored properties reads as empty properties
+            assert!(store.read_edge_properties(edge).await.unwrap().is_empty());
+
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::RecreateEdge((edge, props(json!({ "weight": 1 })))),
+                ))
+                .await
+                .unwrap();
+
+            // the properties are read before the edge is gone, so undo brings them back
+            store
+                .execute(Action::Mutate(graph_id, MutateKind::DeleteEdge(edge)))
+                .await
+                .unwrap();
+            assert!(store.read_node(a).await.unwrap().outbound_edges.is_empty());
+
+            store.execute(Action::Undo).await.unwrap();
+            assert_eq!(store.read_node(a).await.unwrap().outbound_edges, vec![edge]);
+            assert_eq!(
+                store.read_edge_properties(edge).await.unwrap(),
+                props(json!({ "weight": 1 }))
+            );
+        });
+    }
+
+    #[test]
+    fn test_copy_paste_subgraph() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            let a = create_node(&mut store, graph_id, "a").await;
+            let b = create_node(&mut store, graph_id, "b").await;
+            let c = create_node(&mut store, graph_id, "c").await;
+            for (from, to) in [(a, b), (b, c)] {
+                store
+                    .execute(Action::Mutate(
+                        graph_id,
+                        MutateKind::CreateEdge(CreateEdge {
+                            from,
+                            to,
+                            properties: props(json!({ "weight": 1 })),
+                        }),
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            let subgraph = store
+                .execute(Action::Query(QueryKind::CopySubgraph(vec![a, b])))
+                .await
+                .unwrap()
+                .into_subgraph()
+                .unwrap();
+            assert_eq!(subgraph.nodes.len(), 2);
+            assert_eq!(subgraph.edges.len(), 1);
+
+            let ids = store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::PasteSubgraph(subgraph),
+                ))
+                .await
+                .unwrap()
+                .into_id_map()
+                .unwrap();
+
+            let pasted_a = store.read_node(ids[&a]).await.unwrap();
+            assert_eq!(pasted_a.properties, props(json!({ "name": "a" })));
+            assert_eq!(pasted_a.outbound_edges.len(), 1);
+            assert_eq!(pasted_a.outbound_edges[0].to, ids[&b]);
+            assert_eq!(store.read_graph(graph_id).await.unwrap().nodes.len(), 5);
+
+            store.execute(Action::Undo).await.unwrap();
+            assert_eq!(store.read_graph(graph_id).await.unwrap().nodes.len(), 3);
+
+            // the paste is redone as the batch it resolved to
+            let reply = store.execute(Action::Redo).await.unwrap();
+            assert!(matches!(reply, Reply::Batch(_)));
+            assert_eq!(store.read_graph(graph_id).await.unwrap().nodes.len(), 5);
+        });
+    }
+
+    #[test]
+    fn test_instantiate_template() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            let template = Subgraph {
+                nodes: vec![(generate_uuid_v1(), props(json!({ "name": "{{name}}" })))],
+                edges: Vec::new(),
+            };
+            let template_id = store
+                .execute(Action::CreateTemplate("person".into(), template))
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+
+            let templates = match store
+                .execute(Action::Query(QueryKind::ListTemplates))
+                .await
+                .unwrap()
+            {
+                Reply::NodeList(templates) => templates,
+                _ => unreachable!(),
+            };
+            assert_eq!(templates.len(), 1);
+            assert_eq!(templates[0].0, template_id);
+
+            let graphs = match store
+                .execute(Action::Query(QueryKind::ListGraphs))
+                .await
+                .unwrap()
+            {
+                Reply::NodeList(graphs) => graphs,
+                _ => unreachable!(),
+            };
+            assert!(graphs.iter().all(|(id, _)| *id != template_id));
+
+            let ids = store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::InstantiateTemplate((template_id, props(json!({ "name": "bob" })))),
+                ))
+                .await
+                .unwrap()
+                .into_id_map()
+                .unwrap();
+
+            let node_id = *ids.values().next().unwrap();
+            let node = store.read_node(node_id).await.unwrap();
+            assert_eq!(node.properties, props(json!({ "name": "bob" })));
+
+            // the template content is its first state, undo takes all of it
+            assert_eq!(store.read_state_id(template_id).await.unwrap(), 1);
+            store.execute(Action::Undo).await.unwrap();
+            store.execute(Action::Undo).await.unwrap();
+            assert_eq!(store.list_graphs().await.unwrap().len(), 1);
+            store.execute(Action::Redo).await.unwrap();
+            assert_eq!(store.read_graph(template_id).await.unwrap().nodes.len(), 1);
+
+            // a template that fails half way, on a node listed twice, leaves nothing behind
+            let a = generate_uuid_v1();
+            let broken = Subgraph {
+                nodes: vec![(a, Properties::new()), (a, Properties::new())],
+                edges: Vec::new(),
+            };
+            let graphs = store.list_graphs().await.unwrap().len();
+            assert!(store
+                .execute(Action::CreateTemplate("broken".into(), broken))
+                .await
+                .is_err());
+            assert_eq!(store.list_graphs().await.unwrap().len(), graphs);
+        });
+    }
+
+    #[test]
+    fn test_restore_version() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            let a = create_node(&mut store, graph_id, "a").await;
+            let b = create_node(&mut store, graph_id, "b").await;
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateEdge(CreateEdge {
+                        from: a,
+                        to: b,
+                        properties: props(json!({ "weight": 1 })),
+                    }),
+                ))
+                .await
+                .unwrap();
+
+            let version_id = store
+                .execute(Action::CreateVersion(graph_id, "v1".into()))
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+            assert!(store
+                .execute(Action::CreateVersion(graph_id, "v1".into()))
+                .await
+                .is_err());
+
+            // the version is created again under its id, so replays agree on it
+            store.execute(Action::Undo).await.unwrap();
+            assert!(store.list_versions(graph_id).await.unwrap().is_empty());
+            store.execute(Action::Redo).await.unwrap();
+            let versions = store.list_versions(graph_id).await.unwrap();
+            assert_eq!(versions[0].0, version_id);
+            assert!(store
+                .history_buf()
+                .entries()
+                .iter()
+                .any(|(_, action)| matches!(
+                    action,
+                    Action::CreateVersionWithId(id, _, _) if *id == version_id
+                )));
+
+            store
+                .execute(Action::Mutate(graph_id, MutateKind::DeleteNode(b)))
+                .await
+                .unwrap();
+            create_node(&mut store, graph_id, "c").await;
+
+            let versions = match store
+                .execute(Action::Query(QueryKind::ListVersions(graph_id)))
+                .await
+                .unwrap()
+            {
+                Reply::NodeList(versions) => versions,
+                _ => unreachable!(),
+            };
+            assert_eq!(versions.len(), 1);
+
+            let version = store
+                .execute(Action::Query(QueryKind::ReadGraphVersion((
+                    graph_id,
+                    "v1".into(),
+                ))))
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert_eq!(version.nodes.len(), 2);
+
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::RestoreVersion("v1".into()),
+                ))
+                .await
+                .unwrap();
+
+            let node_ids = |graph: Graph| {
+                let mut ids: Vec<NodeId> = graph.nodes.iter().map(|node| node.node_id).collect();
+                ids.sort();
+                ids
+            };
+            let mut expected = vec![a, b];
+            expected.sort();
+            assert_eq!(
+                node_ids(store.read_graph(graph_id).await.unwrap()),
+                expected
+            );
+            assert_eq!(store.read_node(a).await.unwrap().outbound_edges[0].to, b);
+
+            store.execute(Action::Undo).await.unwrap();
+            assert_eq!(store.read_graph(graph_id).await.unwrap().nodes.len(), 2);
+            assert!(store.read_node(a).await.unwrap().outbound_edges.is_empty());
+
+            store.execute(Action::Redo).await.unwrap();
+            assert_eq!(
+                node_ids(store.read_graph(graph_id).await.unwrap()),
+                expected
+            );
+        });
+    }
+
+    #[test]
+    fn test_read_graph_at() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            // state 1
+            let node_id = create_node(&mut store, graph_id, "a").await;
+            // states 2..=40, past the first checkpoint
+            for count in 2..=40 {
+                store
+                    .execute(Action::Mutate(
+                        graph_id,
+                        MutateKind::UpdateNode((node_id, props(json!({ "count": count })))),
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            let graph = read_at(&mut store, graph_id, 0)
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert!(graph.nodes.is_empty());
+
+            let graph = read_at(&mut store, graph_id, 1)
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert_eq!(graph.nodes[0].properties, props(json!({ "name": "a" })));
+
+            for state_id in [10, 32, 33, 40] {
+                let graph = read_at(&mut store, graph_id, state_id)
+                    .await
+                    .unwrap()
+                    .into_graph()
+                    .unwrap();
+                assert_eq!(graph.state_id, state_id);
+                assert_eq!(
+                    graph.nodes[0].properties,
+                    props(json!({ "count": state_id }))
+                );
+            }
+
+            assert!(read_at(&mut store, graph_id, 41).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_read_graph_at_after_reopening() {
+        block_on(async {
+            let source = MemoryDatastore::default();
+            let reopen = || {
+                DB::with_source(source.clone(), &DbConfig::default())
+                    .load_history()
+                    .unwrap()
+            };
+            let mut store = reopen();
+            let graph_id = store
+                .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+            let node_id = create_node(&mut store, graph_id, "a").await;
+            let update = |count: u64| {
+                Action::Mutate(
+                    graph_id,
+                    MutateKind::UpdateNode((node_id, props(json!({ "count": count })))),
+                )
+            };
+            for count in 2..=40 {
+                store.execute(update(count)).await.unwrap();
+            }
+
+            // the reverses and the checkpoint were saved with the history
+            let mut store = reopen();
+            let graph = read_at(&mut store, graph_id, 1)
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert_eq!(graph.nodes[0].properties, props(json!({ "name": "a" })));
+            for state_id in [10, 32, 33] {
+                let graph = read_at(&mut store, graph_id, state_id)
+                    .await
+                    .unwrap()
+                    .into_graph()
+                    .unwrap();
+                assert_eq!(
+                    graph.nodes[0].properties,
+                    props(json!({ "count": state_id }))
+                );
+            }
+
+            // and what comes after the reopening is added to them
+            store.execute(update(41)).await.unwrap();
+            let mut store = reopen();
+            for state_id in [0, 40] {
+                assert!(read_at(&mut store, graph_id, state_id).await.is_ok());
+            }
+            assert!(read_at(&mut store, graph_id, 42).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_read_graph_hash() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+            let node_id = create_node(&mut store, graph_id, "a").await;
+
+            let first = store.read_graph_with_hash(graph_id).await.unwrap();
+            assert!(first.hash.is_some());
+            assert_eq!(store.hash_cache.get(graph_id, 1), first.hash);
+            let hash = store.read_graph_hash(graph_id).await.unwrap();
+            assert_eq!(Some(hash), first.hash);
+
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::UpdateNode((node_id, props(json!({ "name": "b" })))),
+                ))
+                .await
+                .unwrap();
+            let current = store.read_graph_with_hash(graph_id).await.unwrap();
+            assert_ne!(current.hash, first.hash);
+
+            // a past state hashes the same as it did back then
+            let at_1 = read_at(&mut store, graph_id, 1)
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert_eq!(at_1.hash, first.hash);
+
+            // a graph created again under the id doesn't reuse the old hashes
+            store.execute(Action::DeleteGraph(graph_id)).await.unwrap();
+            assert_eq!(store.hash_cache.get(graph_id, 2), None);
+        });
+    }
+
+    #[test]
+    fn test_apply_crdt() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            let mut crdt = GraphCrdt::new(generate_uuid_v1());
+            let (a, b) = (generate_uuid_v1(), generate_uuid_v1());
+            crdt.add_node(a);
+            crdt.add_node(b);
+            crdt.set_property(a, "name".into(), Some(json!("a")));
+            crdt.add_edge(Edge {
+                id: generate_uuid_v1(),
+                from: a,
+                to: b,
+            });
+
+            apply_to_datastore(&crdt, &mut store, graph_id)
+                .await
+                .unwrap();
+            let (_, snapshot) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(content_hash(&snapshot), content_hash(&crdt.to_subgraph()));
+
+            crdt.remove_node(b);
+            apply_to_datastore(&crdt, &mut store, graph_id)
+                .await
+                .unwrap();
+            let (_, snapshot) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(content_hash(&snapshot), content_hash(&crdt.to_subgraph()));
+            assert_eq!(snapshot.nodes.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_merge_into_datastore() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+            let a = create_node(&mut store, graph_id, "a").await;
+            let (base_state_id, base) = store.read_graph_snapshot(graph_id).await.unwrap();
+
+            // we rename the node while they rename it too and add another one
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::UpdateNode((a, props(json!({ "name": "ours" })))),
+                ))
+                .await
+                .unwrap();
+            let mut theirs = base.clone();
+            theirs.nodes[0].1 = props(json!({ "name": "theirs" }));
+            let b = generate_uuid_v1();
+            theirs.nodes.push((b, props(json!({ "name": "b" }))));
+
+            let mut merge = merge_with_datastore(&store, graph_id, base_state_id, &theirs)
+                .await
+                .unwrap();
+            assert_eq!(merge.conflicts.len(), 1);
+            assert!(matches!(
+                merge::apply_to_datastore(&merge, &mut store, graph_id).await,
+                Err(Error::UnresolvedConflicts(1))
+            ));
+
+            let conflict = merge.conflicts[0].clone();
+            merge.resolve(&conflict, Side::Theirs);
+            merge::apply_to_datastore(&merge, &mut store, graph_id)
+                .await
+                .unwrap();
+            let (_, snapshot) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(content_hash(&snapshot), content_hash(&merge.merged));
+
+            // the merge is undone as a whole
+            store.execute(Action::Undo).await.unwrap();
+            let (_, snapshot) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(snapshot.nodes, vec![(a, props(json!({ "name": "ours" })))]);
+        });
+    }
+
+    #[test]
+    fn test_execute_with_id_is_idempotent() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+
+            let action_id = ActionId {
+                client_id: generate_uuid_v1(),
+                seq: 1,
+            };
+            let create = Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "a" }))),
+            );
+
+            let node_id = store
+                .execute_with_id(action_id, create.clone())
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+
+            // a retry of the same action is skipped and gets the id it created
+            let reply = store.execute_with_id(action_id, create).await.unwrap();
+            assert_eq!(reply.as_id(), Some(node_id));
+
+            let (_, snapshot) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(snapshot.nodes.len(), 1);
+
+            // replayed under another id the node isn't created twice
+            let node_id = snapshot.nodes[0].0;
+            assert!(matches!(
+                store
+                    .create_node_with_id(node_id, (graph_id, Properties::new()))
+                    .await,
+                Err(Error::NodeAlreadyExists(id)) if id == node_id
+            ));
+
+            let history = store.history_buf();
+            assert_eq!(history.len(), 2);
+            assert_eq!(history.entries()[1].0, action_id);
+            assert_eq!(history.entries()[0].0.client_id, history.client_id());
+        });
+    }
+
+    #[test]
+    fn test_history_survives_reopening() {
+        block_on(async {
+            let source = MemoryDatastore::default();
+            let mut store = DB::with_source(source.clone(), &DbConfig::default());
+            let create = Action::CreateGraph(props(json!({ "name": "graph" })));
+            let graph_id = store
+                .execute(create.clone())
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+            let action_id = store.history().entries()[0].0;
+
+            let mut reopened = DB::with_source(source.clone(), &DbConfig::default())
+                .load_history()
+                .unwrap();
+            assert_eq!(reopened.history().client_id(), store.history().client_id());
+            assert_eq!(
+                reopened.history_buf().next_id(),
+                store.history_buf().next_id()
+            );
+
+            // a retry after the restart is still skipped
+            let reply = reopened.execute_with_id(action_id, create).await.unwrap();
+            assert_eq!(reply.as_id(), Some(graph_id));
+            assert_eq!(reopened.list_graphs().await.unwrap().len(), 1);
+
+            // later actions are added to the saved entries
+            create_node(&mut reopened, graph_id, "a").await;
+            let reopened = DB::with_source(source, &DbConfig::default())
+                .load_history()
+                .unwrap();
+            let entries = reopened.history().entries();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].0, action_id);
+            assert!(matches!(
+                entries[1].1,
+                Action::Mutate(_, MutateKind::CreateNodeWithId(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_delete_graph() {
+        block_on(async {
+            let (mut store, graph_id) = make_graph().await;
+            let a = create_node(&mut store, graph_id, "a").await;
+            let b = create_node(&mut store, graph_id, "b").await;
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateEdge(CreateEdge {
+                        from: a,
+                        to: b,
+                        properties: props(json!({ "weight": 1 })),
+                    }),
+                ))
+                .await
+                .unwrap();
+            store
+                .execute(Action::CreateVersion(graph_id, "v1".into()))
+                .await
+                .unwrap();
+            let (_, before) = store.read_graph_snapshot(graph_id).await.unwrap();
+
+            // the versions go along with the graph
+            store.execute(Action::DeleteGraph(graph_id)).await.unwrap();
+            assert!(store.list_graphs().await.unwrap().is_empty());
+
+            // the state id carries on where it was, so the timeline still lines up
+            store.execute(Action::Undo).await.unwrap();
+            let (state_id, after) = store.read_graph_snapshot(graph_id).await.unwrap();
+            assert_eq!(content_hash(&after), content_hash(&before));
+            assert_eq!(state_id, 3);
+            assert_eq!(store.list_versions(graph_id).await.unwrap().len(), 1);
+
+            let at_1 = read_at(&mut store, graph_id, 1)
+                .await
+                .unwrap()
+                .into_graph()
+                .unwrap();
+            assert_eq!(at_1.nodes.len(), 1);
+            assert_eq!(at_1.nodes[0].node_id, a);
+
+            create_node(&mut store, graph_id, "c").await;
+            assert_eq!(store.read_state_id(graph_id).await.unwrap(), 4);
+
+            store.execute(Action::Undo).await.unwrap();
+            store.execute(Action::Redo).await.unwrap();
+            store.execute(Action::Undo).await.unwrap();
+            store.execute(Action::Redo).await.unwrap();
+            store.execute(Action::DeleteGraph(graph_id)).await.unwrap();
+            assert!(store.list_graphs().await.unwrap().is_empty());
+        });
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
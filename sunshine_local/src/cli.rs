@@ -0,0 +1,438 @@
+use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use sunshine_core::error::{Error, Result};
+use sunshine_core::msg::{
+    Action, CreateEdge, Edge, EdgeId, GraphId, MutateKind, NodeId, Properties, QueryKind, Reply,
+    Subgraph,
+};
+use sunshine_core::store::Datastore;
+
+use crate::config::{Backend, Config};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "sunshine",
+    about = "Manage sunshine graphs from the command line"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: Options,
+    // the interactive shell starts without a command
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// flags win over the environment and the config file
+#[derive(Debug, Default, Args)]
+pub struct Options {
+    /// toml file, see config
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// RocksDB directory of the indra backend
+    #[arg(long, global = true)]
+    pub db_path: Option<String>,
+    #[arg(long, global = true)]
+    pub dgraph_url: Option<String>,
+    #[arg(long, global = true)]
+    pub server_url: Option<String>,
+    /// where undo and redo are kept between runs
+    #[arg(long, global = true)]
+    pub session: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn load(self) -> Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+        if let Some(db_path) = self.db_path {
+            config.indra.db_path = db_path;
+        }
+        if let Some(url) = self.dgraph_url {
+            config.dgraph.url = url;
+        }
+        if let Some(url) = self.server_url {
+            config.server.url = url;
+        }
+        if let Some(session) = self.session {
+            config.session_path = session;
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_backend(name: &str) -> std::result::Result<Backend, String> {
+    name.parse().map_err(|error: Error| error.to_string())
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(subcommand)]
+    Graph(GraphCommand),
+    #[command(subcommand)]
+    Node(NodeCommand),
+    #[command(subcommand)]
+    Edge(EdgeCommand),
+    /// runs a QueryKind given as json, e.g. '"ListGraphs"' or '{"ReadNode": "<id>"}'
+    Query {
+        #[arg(value_parser = parse_json::<QueryKind>)]
+        query: QueryKind,
+    },
+    Undo,
+    Redo,
+    /// writes the nodes and edges of a graph as json
+    Export {
+        graph_id: GraphId,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// pastes exported nodes and edges with fresh ids, into a new graph unless one is given
+    Import {
+        input: PathBuf,
+        #[arg(long)]
+        graph: Option<GraphId>,
+        #[arg(long, value_parser = parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GraphCommand {
+    Create {
+        #[arg(value_parser = parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+    List,
+    Show {
+        graph_id: GraphId,
+    },
+    Delete {
+        graph_id: GraphId,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NodeCommand {
+    Add {
+        graph_id: GraphId,
+        #[arg(value_parser = parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+    Show {
+        node_id: NodeId,
+    },
+    Update {
+        graph_id: GraphId,
+        node_id: NodeId,
+        #[arg(value_parser = parse_json::<Properties>)]
+        properties: Properties,
+    },
+    Delete {
+        graph_id: GraphId,
+        node_id: NodeId,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EdgeCommand {
+    Add {
+        graph_id: GraphId,
+        from: NodeId,
+        to: NodeId,
+        #[arg(value_parser = parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+    Show {
+        #[command(flatten)]
+        edge: EdgeArgs,
+    },
+    Update {
+        graph_id: GraphId,
+        #[command(flatten)]
+        edge: EdgeArgs,
+        #[arg(value_parser = parse_json::<Properties>)]
+        properties: Properties,
+    },
+    Delete {
+        graph_id: GraphId,
+        #[command(flatten)]
+        edge: EdgeArgs,
+    },
+}
+
+impl Command {
+    // commands that leave the store as it was
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Graph(GraphCommand::List | GraphCommand::Show { .. })
+                | Command::Node(NodeCommand::Show { .. })
+                | Command::Edge(EdgeCommand::Show { .. })
+                | Command::Query { .. }
+                | Command::Export { .. }
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct EdgeArgs {
+    edge_id: EdgeId,
+    from: NodeId,
+    to: NodeId,
+}
+
+impl From<EdgeArgs> for Edge {
+    fn from(args: EdgeArgs) -> Edge {
+        Edge {
+            id: args.edge_id,
+            from: args.from,
+            to: args.to,
+        }
+    }
+}
+
+pub(crate) fn parse_json<T: serde::de::DeserializeOwned>(
+    text: &str,
+) -> std::result::Result<T, String> {
+    serde_json::from_str(text).map_err(|error| error.to_string())
+}
+
+// undo and redo outlive a single run of the tool this way, the history is kept
+// by the store itself, the session names the store its reverses belong to
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(default)]
+    store: Option<String>,
+    undo: Vec<Action>,
+    redo: Vec<Action>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Session> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(Error::JsonError),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Session::default()),
+            Err(error) => Err(Error::File(error)),
+        }
+    }
+
+    // the session of another store is dropped, its reverses would undo the wrong data
+    pub fn restore(self, store: &mut dyn Datastore, store_id: Option<&str>) {
+        if self.store.is_none() || self.store.as_deref() != store_id {
+            if !self.undo.is_empty() || !self.redo.is_empty() {
+                log::warn!("the session belongs to another store, undo and redo start empty");
+            }
+            return;
+        }
+
+        *store.undo_buf() = self.undo;
+        *store.redo_buf() = self.redo;
+    }
+
+    // a store without an id is gone with the run and so is its session
+    pub fn save(store: &mut dyn Datastore, store_id: Option<&str>, path: &Path) -> Result<()> {
+        if store_id.is_none() {
+            return Ok(());
+        }
+
+        let session = Session {
+            store: store_id.map(Into::into),
+            undo: store.undo_buf().clone(),
+            redo: store.redo_buf().clone(),
+        };
+        let text = serde_json::to_string(&session).map_err(Error::JsonError)?;
+        fs::write(path, text).map_err(Error::File)
+    }
+}
+
+pub async fn run(store: &mut dyn Datastore, command: Command) -> Result<Reply> {
+    let action = match command {
+        Command::Graph(GraphCommand::Create { properties }) => Action::CreateGraph(properties),
+        Command::Graph(GraphCommand::List) => Action::Query(QueryKind::ListGraphs),
+        Command::Graph(GraphCommand::Show { graph_id }) => {
+            Action::Query(QueryKind::ReadGraph(graph_id))
+        }
+        Command::Graph(GraphCommand::Delete { graph_id }) => Action::DeleteGraph(graph_id),
+        Command::Node(NodeCommand::Add {
+            graph_id,
+            properties,
+        }) => Action::Mutate(graph_id, MutateKind::CreateNode(properties)),
+        Command::Node(NodeCommand::Show { node_id }) => Action::Query(QueryKind::ReadNode(node_id)),
+        Command::Node(NodeCommand::Update {
+            graph_id,
+            node_id,
+            properties,
+        }) => Action::Mutate(graph_id, MutateKind::UpdateNode((node_id, properties))),
+        Command::Node(NodeCommand::Delete { graph_id, node_id }) => {
+            Action::Mutate(graph_id, MutateKind::DeleteNode(node_id))
+        }
+        Command::Edge(EdgeCommand::Add {
+            graph_id,
+            from,
+            to,
+            properties,
+        }) => Action::Mutate(
+            graph_id,
+            MutateKind::CreateEdge(CreateEdge {
+                from,
+                to,
+                properties,
+            }),
+        ),
+        Command::Edge(EdgeCommand::Show { edge }) => {
+            Action::Query(QueryKind::ReadEdgeProperties(edge.into()))
+        }
+        Command::Edge(EdgeCommand::Update {
+            graph_id,
+            edge,
+            properties,
+        }) => Action::Mutate(graph_id, MutateKind::UpdateEdge((edge.into(), properties))),
+        Command::Edge(EdgeCommand::Delete { graph_id, edge }) => {
+            Action::Mutate(graph_id, MutateKind::DeleteEdge(edge.into()))
+        }
+        Command::Query { query } => Action::Query(query),
+        Command::Undo => Action::Undo,
+        Command::Redo => Action::Redo,
+        Command::Export { graph_id, output } => {
+            let (_, subgraph) = store.read_graph_snapshot(graph_id).await?;
+            return match output {
+                Some(path) => {
+                    let text = serde_json::to_string_pretty(&subgraph).map_err(Error::JsonError)?;
+                    fs::write(path, text).map_err(Error::File)?;
+                    Ok(Reply::Empty)
+                }
+                None => Ok(Reply::Subgraph(subgraph)),
+            };
+        }
+        Command::Import {
+            input,
+            graph,
+            properties,
+        } => {
+            let text = fs::read_to_string(input).map_err(Error::File)?;
+            let subgraph: Subgraph = serde_json::from_str(&text).map_err(Error::JsonError)?;
+            let graph_id = match graph {
+                Some(graph_id) => graph_id,
+                None => store
+                    .execute(Action::CreateGraph(properties))
+                    .await?
+                    .as_id()
+                    .ok_or(Error::GraphNotFound)?,
+            };
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::PasteSubgraph(subgraph),
+                ))
+                .await?;
+            return Ok(Reply::Id(graph_id));
+        }
+    };
+
+    store.execute(action).await
+}
+
+// ids on their own line so they can be piped into the next command
+pub fn print_reply(reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Empty => {}
+        Reply::Id(id) => println!("{}", id),
+        reply => println!(
+            "{}",
+            serde_json::to_string_pretty(reply).map_err(Error::JsonError)?
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sunshine_indra::store::MemoryDB;
+
+    fn parse(args: &str) -> Cli {
+        Cli::try_parse_from(std::iter::once("sunshine").chain(args.split_whitespace())).unwrap()
+    }
+
+    async fn run_args(store: &mut MemoryDB, args: &str) -> Reply {
+        run(store, parse(args).command.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let mut store = MemoryDB::default();
+        let tmp = tempfile::TempDir::new().unwrap();
+        let dir = tmp.path();
+        let export = dir.join("export.json");
+
+        let graph_id = run_args(&mut store, r#"graph create {"name":"graph"}"#)
+            .await
+            .as_id()
+            .unwrap();
+        let a = run_args(
+            &mut store,
+            &format!(r#"node add {} {{"name":"a"}}"#, graph_id),
+        )
+        .await
+        .as_id()
+        .unwrap();
+        let b = run_args(&mut store, &format!("node add {}", graph_id))
+            .await
+            .as_id()
+            .unwrap();
+        run_args(&mut store, &format!("edge add {} {} {}", graph_id, a, b)).await;
+
+        run_args(
+            &mut store,
+            &format!("export {} --output {}", graph_id, export.display()),
+        )
+        .await;
+        let imported = run_args(&mut store, &format!("import {}", export.display()))
+            .await
+            .as_id()
+            .unwrap();
+
+        let graph = run_args(&mut store, &format!("graph show {}", imported))
+            .await
+            .into_graph()
+            .unwrap();
+        assert_eq!(graph.nodes.len(), 2);
+
+        // undo and redo survive a new store through the session file
+        let session = dir.join("session.json");
+        Session::save(&mut store, Some("indra somewhere"), &session).unwrap();
+
+        let mut store = MemoryDB::default();
+        Session::load(&session)
+            .unwrap()
+            .restore(&mut store, Some("indra somewhere"));
+        assert_eq!(store.undo_buf().len(), 6);
+
+        // but not a store the session wasn't saved for
+        let mut store = MemoryDB::default();
+        Session::load(&session)
+            .unwrap()
+            .restore(&mut store, Some("indra elsewhere"));
+        assert!(store.undo_buf().is_empty());
+        Session::load(&session).unwrap().restore(&mut store, None);
+        assert!(store.undo_buf().is_empty());
+
+        let config = parse("--db-path somewhere --backend memory undo")
+            .options
+            .load()
+            .unwrap();
+        assert_eq!(config.indra.db_path, "somewhere");
+        assert_eq!(config.backend, Backend::Memory);
+        assert!(Cli::try_parse_from(["sunshine", "--backend", "postgres"]).is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sunshine_core::error::{Error, Result};
+use sunshine_core::store::Datastore;
+use sunshine_dgraph::store::{Config as DgraphConfig, Store as DgraphStore};
+use sunshine_indra::store::{DbConfig, DB};
+use sunshine_server::client::RemoteStore;
+
+// backend = "indra"              # indra, memory, dgraph or server
+// undo_capacity = 100            # unbounded when left out
+// log_path = "sunshine.log"      # no logging when left out
+// session_path = "sunshine_session.json"
+//
+// [indra]
+// db_path = "indra_datastore"
+//
+// [dgraph]
+// url = "https://example.cloud.dgraph.io"
+// auth_token = "..."             # better kept in SUNSHINE_DGRAPH_AUTH_TOKEN
+// user = "groot"                 # ACL login of a self-hosted dgraph
+// password = "..."               # better kept in SUNSHINE_DGRAPH_PASSWORD
+// namespace = 0
+// client_id = "..."              # keeps the history across runs, one per process
+//
+// [server]
+// url = "http://localhost:8080"  # a sunshine_server, which keeps undo and redo
+// history_path = "sunshine_client.json"  # the client id the server knows this client by
+//
+// every option can be overridden by the variable named in `apply_env`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub backend: Backend,
+    pub undo_capacity: Option<usize>,
+    pub log_path: Option<PathBuf>,
+    pub session_path: PathBuf,
+    pub indra: IndraSection,
+    pub dgraph: DgraphSection,
+    pub server: ServerSection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Indra,
+    Memory,
+    Dgraph,
+    Server,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndraSection {
+    pub db_path: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DgraphSection {
+    pub url: String,
+    pub auth_token: String,
+    pub user: String,
+    pub password: String,
+    pub namespace: u64,
+    pub client_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub url: String,
+    pub history_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: Backend::Indra,
+            undo_capacity: None,
+            log_path: None,
+            session_path: "sunshine_session.json".into(),
+            indra: IndraSection::default(),
+            dgraph: DgraphSection::default(),
+            server: ServerSection::default(),
+        }
+    }
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            url: String::new(),
+            history_path: "sunshine_client.json".into(),
+        }
+    }
+}
+
+impl Default for IndraSection {
+    fn default() -> Self {
+        IndraSection {
+            db_path: "indra_datastore".into(),
+        }
+    }
+}
+
+// the secrets stay out of logs and error messages
+impl std::fmt::Debug for DgraphSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DgraphSection")
+            .field("url", &self.url)
+            .field("auth_token", &redacted(&self.auth_token))
+            .field("user", &self.user)
+            .field("password", &redacted(&self.password))
+            .field("namespace", &self.namespace)
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+fn redacted(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Backend> {
+        match name {
+            "indra" => Ok(Backend::Indra),
+            "memory" => Ok(Backend::Memory),
+            "dgraph" => Ok(Backend::Dgraph),
+            "server" => Ok(Backend::Server),
+            _ => Err(Error::Config(format!("unknown backend {}", name))),
+        }
+    }
+}
+
+impl Config {
+    // the file if there is one, then the environment on top
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => Config::parse(&fs::read_to_string(path).map_err(Error::File)?)?,
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        toml::from_str(text).map_err(|error| Error::Config(error.to_string()))
+    }
+
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<()> {
+        if let Some(backend) = var("SUNSHINE_BACKEND") {
+            self.backend = backend.parse()?;
+        }
+        if let Some(capacity) = var("SUNSHINE_UNDO_CAPACITY") {
+            let capacity = capacity
+                .parse()
+                .map_err(|_| Error::Config(format!("invalid undo capacity {}", capacity)))?;
+            self.undo_capacity = Some(capacity);
+        }
+        if let Some(log_path) = var("SUNSHINE_LOG_PATH") {
+            self.log_path = Some(log_path.into());
+        }
+        if let Some(session_path) = var("SUNSHINE_SESSION_PATH") {
+            self.session_path = session_path.into();
+        }
+        if let Some(db_path) = var("SUNSHINE_DB_PATH") {
+            self.indra.db_path = db_path;
+        }
+        if let Some(url) = var("SUNSHINE_DGRAPH_URL") {
+            self.dgraph.url = url;
+        }
+        if let Some(auth_token) = var("SUNSHINE_DGRAPH_AUTH_TOKEN") {
+            self.dgraph.auth_token = auth_token;
+        }
+        if let Some(user) = var("SUNSHINE_DGRAPH_USER") {
+            self.dgraph.user = user;
+        }
+        if let Some(password) = var("SUNSHINE_DGRAPH_PASSWORD") {
+            self.dgraph.password = password;
+        }
+        if let Some(namespace) = var("SUNSHINE_DGRAPH_NAMESPACE") {
+            let namespace = namespace
+                .parse()
+                .map_err(|_| Error::Config(format!("invalid dgraph namespace {}", namespace)))?;
+            self.dgraph.namespace = namespace;
+        }
+        if let Some(client_id) = var("SUNSHINE_DGRAPH_CLIENT_ID") {
+            let client_id = client_id
+                .parse()
+                .map_err(|_| Error::Config(format!("invalid dgraph client id {}", client_id)))?;
+            self.dgraph.client_id = Some(client_id);
+        }
+        if let Some(url) = var("SUNSHINE_SERVER_URL") {
+            self.server.url = url;
+        }
+        if let Some(history_path) = var("SUNSHINE_SERVER_HISTORY_PATH") {
+            self.server.history_path = history_path.into();
+        }
+
+        Ok(())
+    }
+
+    pub async fn open(&self) -> Result<Box<dyn Datastore>> {
+        let store: Box<dyn Datastore> = match self.backend {
+            Backend::Indra => Box::new(DB::new(&self.db_config())?),
+            Backend::Memory => Box::new(DB::in_memory(&self.db_config())),
+            Backend::Dgraph => {
+                if self.dgraph.url.is_empty() {
+                    return Err(Error::Config("the dgraph backend needs a url".into()));
+                }
+                Box::new(
+                    DgraphStore::connect(&DgraphConfig {
+                        base_url: self.dgraph.url.clone(),
+                        auth_token: self.dgraph.auth_token.clone(),
+                        undo_capacity: self.undo_capacity,
+                        user: self.dgraph.user.clone(),
+                        password: self.dgraph.password.clone(),
+                        namespace: self.dgraph.namespace,
+                        client_id: self.dgraph.client_id,
+                    })
+                    .await?,
+                )
+            }
+            Backend::Server => {
+                if self.server.url.is_empty() {
+                    return Err(Error::Config("the server backend needs a url".into()));
+                }
+                Box::new(RemoteStore::with_history_path(
+                    self.server.url.clone(),
+                    &self.server.history_path,
+                )?)
+            }
+        };
+
+        Ok(store)
+    }
+
+    // what the session file belongs to, a memory store has no id since it ends with the run
+    pub fn store_id(&self) -> Option<String> {
+        match self.backend {
+            Backend::Indra => {
+                let db_path = fs::canonicalize(&self.indra.db_path)
+                    .unwrap_or_else(|_| PathBuf::from(&self.indra.db_path));
+                Some(format!("indra {}", db_path.display()))
+            }
+            Backend::Memory => None,
+            Backend::Dgraph => Some(format!(
+                "dgraph {} {}",
+                self.dgraph.url, self.dgraph.namespace
+            )),
+            Backend::Server => Some(format!("server {}", self.server.url)),
+        }
+    }
+
+    fn db_config(&self) -> DbConfig {
+        DbConfig {
+            db_path: self.indra.db_path.clone(),
+            undo_capacity: self.undo_capacity,
+        }
+    }
+
+    // RUST_LOG picks the level, info by default
+    pub fn init_logging(&self) -> Result<()> {
+        let log_path = match &self.log_path {
+            Some(log_path) => log_path,
+            None => return Ok(()),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .map_err(Error::File)?;
+
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .try_init()
+            .map_err(|error| Error::Config(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use sunshine_core::msg::{Action, MutateKind};
+    use sunshine_core::test_utils::props;
+
+    #[test]
+    fn test_file_and_env() {
+        let mut config = Config::parse(
+            r#"
+            backend = "dgraph"
+            undo_capacity = 10
+
+            [dgraph]
+            url = "https://example.cloud.dgraph.io"
+            user = "groot"
+            namespace = 2
+            client_id = "5e7a1d0b-4a11-4c3e-9b1f-000000000002"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.backend, Backend::Dgraph);
+        assert_eq!(config.indra.db_path, "indra_datastore");
+
+        let env: HashMap<&str, &str> = [
+            ("SUNSHINE_BACKEND", "memory"),
+            ("SUNSHINE_DGRAPH_AUTH_TOKEN", "secret"),
+            ("SUNSHINE_DGRAPH_PASSWORD", "password"),
+        ]
+        .into_iter()
+        .collect();
+        config
+            .apply_env(|name| env.get(name).map(|value| value.to_string()))
+            .unwrap();
+        assert_eq!(config.backend, Backend::Memory);
+        assert_eq!(config.dgraph.auth_token, "secret");
+        assert_eq!(config.dgraph.user, "groot");
+        assert_eq!(config.dgraph.password, "password");
+        assert_eq!(config.dgraph.namespace, 2);
+        assert_eq!(
+            config.dgraph.client_id,
+            Some(Uuid::from_u128(0x5e7a_1d0b_4a11_4c3e_9b1f_0000_0000_0002))
+        );
+        assert_eq!(config.undo_capacity, Some(10));
+
+        // the session of a memory store isn't kept, those of other stores name them
+        assert_eq!(config.store_id(), None);
+        config.backend = Backend::Dgraph;
+        assert_eq!(
+            config.store_id().as_deref(),
+            Some("dgraph https://example.cloud.dgraph.io 2")
+        );
+
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains("secret") && !debug.contains("\"password\""));
+
+        assert!(Config::parse("backend = \"postgres\"").is_err());
+        assert!(Config::parse("db_path = \"misplaced\"").is_err());
+        assert!(config
+            .apply_env(|name| (name == "SUNSHINE_UNDO_CAPACITY").then(|| "lots".into()))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_with_undo_capacity() {
+        let mut config = Config::parse("backend = \"memory\"\nundo_capacity = 2").unwrap();
+        let mut store = config.open().await.unwrap();
+
+        let graph_id = store
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        for name in ["a", "b", "c"] {
+            store
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateNode(props(json!({ "name": name }))),
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(store.undo_buf().len(), 2);
+
+        config.backend = Backend::Dgraph;
+        assert!(matches!(config.open().await, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_open_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let router = sunshine_server::router(Box::new(DB::in_memory(&DbConfig::default())));
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+
+        let mut config = Config::parse("backend = \"server\"").unwrap();
+        assert!(matches!(config.open().await, Err(Error::Config(_))));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let history_path = dir.path().join("client.json");
+        let env: HashMap<&str, String> = [
+            ("SUNSHINE_SERVER_URL", url),
+            (
+                "SUNSHINE_SERVER_HISTORY_PATH",
+                history_path.display().to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        config.apply_env(|name| env.get(name).cloned()).unwrap();
+        let mut store = config.open().await.unwrap();
+        let graph_id = store
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        // the next run is the same client of the server and sees the graph
+        let reopened = config.open().await.unwrap();
+        assert_eq!(reopened.list_graphs().await.unwrap()[0].0, graph_id);
+        assert_eq!(reopened.history().client_id(), store.history().client_id());
+    }
+}
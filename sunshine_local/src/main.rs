@@ -1,39 +1,33 @@
-// use sunshine_core::msg::{
-//     Action, CreateEdge, Edge, EdgeId, Graph, GraphId, MutateKind, Node, NodeId, Properties,
-//     RecreateNode,
-// };
-// // use sunshine_core::store::Datastore;
-// use sunshine_indra::store::{DbConfig, DB};
+use clap::Parser;
 
-// #[tokio::main]
-// async fn main() {
-//     let mut indra_store = DB::new(&DbConfig {
-//         db_path: "indra_datastore".into(),
-//     })
-//     .unwrap();
-//     indra_store
-//         .execute(Action::CreateGraph(serde_json::Map::new()))
-//         .await
-//         .unwrap();
-// }
+use sunshine_core::error::Result;
+use sunshine_local::cli::{print_reply, run, Cli, Session};
+use sunshine_local::shell;
 
-// struct State {
-//     indra_store: DB,
-//     cloud_handle: Option<CloudHandle>,
-// }
+#[tokio::main]
+async fn main() {
+    if let Err(error) = try_main().await {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
 
-// struct CloudHandle {
-//     client: reqwest::Client,
-// }
+async fn try_main() -> Result<()> {
+    let Cli { options, command } = Cli::parse();
+    let config = options.load()?;
+    config.init_logging()?;
 
-// impl CloudHandle {
-//     fn new(url: String, history: &[Action]) {
-//         todo!()
-//     }
+    let mut store = config.open().await?;
+    let store_id = config.store_id();
+    let command = match command {
+        Some(command) => command,
+        None => return shell::run(store, &config.session_path, store_id.as_deref()).await,
+    };
 
-//     fn execute(&self, action: Action) -> () {
-//         todo!()
-//     }
-// }
+    Session::load(&config.session_path)?.restore(store.as_mut(), store_id.as_deref());
 
-fn main() {}
+    let reply = run(store.as_mut(), command).await;
+    Session::save(store.as_mut(), store_id.as_deref(), &config.session_path)?;
+
+    print_reply(&reply?)
+}
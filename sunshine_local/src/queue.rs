@@ -0,0 +1,747 @@
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use sunshine_core::delegate::Delegate;
+use sunshine_core::error::{Error, Result};
+use sunshine_core::msg::{Action, ActionId, GraphId, Reply};
+use sunshine_core::store::Datastore;
+
+use crate::sync::is_replayed;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// applies actions to the local store right away and delivers them to the remote when it
+// can be reached, undelivered actions are kept on disk as json lines until then,
+// actions the remote refuses are moved to a dead letter file next to the queue, along
+// with the queued actions on the same graph that build on them, they stay applied locally
+// and their graphs are reported as diverged until the caller resolves them
+pub struct OfflineQueue<L, R> {
+    local: L,
+    remote: R,
+    queue_path: PathBuf,
+    pending: VecDeque<(ActionId, Action)>,
+    failures: u32,
+    retry_at: Option<Instant>,
+    last_error: Option<Error>,
+    rejected: Vec<Rejected>,
+    // the graphs with dead letters, rebuilt from the file when opened
+    diverged: BTreeSet<GraphId>,
+}
+
+// an action the remote refused, retrying it wouldn't help
+#[derive(Debug)]
+pub struct Rejected {
+    pub action_id: ActionId,
+    pub action: Action,
+    pub error: Error,
+}
+
+impl<L: Datastore, R: Datastore> OfflineQueue<L, R> {
+    // picks up whatever was left undelivered in the queue file
+    pub fn new(local: L, remote: R, queue_path: impl Into<PathBuf>) -> Result<Self> {
+        let queue_path = queue_path.into();
+        let pending = read_lines(&queue_path)?.into();
+
+        let mut queue = OfflineQueue {
+            local,
+            remote,
+            queue_path,
+            pending,
+            failures: 0,
+            retry_at: None,
+            last_error: None,
+            rejected: Vec::new(),
+            diverged: BTreeSet::new(),
+        };
+        queue.diverged = queue
+            .dead_letters()?
+            .iter()
+            .filter_map(|(_, action, _)| graph_of(action))
+            .collect();
+
+        Ok(queue)
+    }
+
+    // actions executed straight on the local store aren't queued
+    pub fn local(&mut self) -> &mut L {
+        &mut self.local
+    }
+
+    pub fn remote(&mut self) -> &mut R {
+        &mut self.remote
+    }
+
+    // number of actions not delivered yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // why the last delivery failed, cleared once the remote accepts an action again
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    // the actions the remote refused since the last call, they are in the dead letter file too
+    pub fn take_rejected(&mut self) -> Vec<Rejected> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.queue_path.with_extension("dead.jsonl")
+    }
+
+    // the graphs the local store has refused actions on, so they differ from the remote
+    pub fn diverged(&self) -> Vec<GraphId> {
+        self.diverged.iter().copied().collect()
+    }
+
+    // once the graph was brought back in line, e.g. from the remote, its dead letters go
+    pub fn resolve(&mut self, graph_id: GraphId) -> Result<()> {
+        let kept: Vec<_> = self
+            .dead_letters()?
+            .into_iter()
+            .filter(|(_, action, _)| graph_of(action) != Some(graph_id))
+            .collect();
+        write_lines(&self.dead_letter_path(), &kept)?;
+
+        self.diverged.remove(&graph_id);
+        Ok(())
+    }
+
+    fn dead_letters(&self) -> Result<Vec<(ActionId, Action, String)>> {
+        read_lines(&self.dead_letter_path())
+    }
+
+    // delivers the queue in order until it's empty or the remote can't be reached,
+    // returns how many actions were delivered
+    pub async fn flush(&mut self) -> Result<usize> {
+        let mut delivered = 0;
+        let mut changed = false;
+
+        while let Some((action_id, action)) = self.pending.front().cloned() {
+            match self.remote.execute_with_id(action_id, action.clone()).await {
+                Ok(_) => {
+                    self.pending.pop_front();
+                    delivered += 1;
+                }
+                Err(error) if is_transient(&error) => {
+                    self.failures += 1;
+                    self.retry_at = Some(Instant::now() + backoff(self.failures));
+                    self.last_error = Some(error);
+                    break;
+                }
+                Err(error) => {
+                    self.pending.pop_front();
+                    self.reject(Rejected {
+                        action_id,
+                        action: action.clone(),
+                        error,
+                    })?;
+                    self.reject_dependents(action_id, &action)?;
+                }
+            }
+
+            self.failures = 0;
+            self.retry_at = None;
+            self.last_error = None;
+            changed = true;
+        }
+
+        if changed {
+            self.persist()?;
+        }
+
+        Ok(delivered)
+    }
+
+    // retries in the background, so the queue drains without new actions coming in
+    pub fn spawn_retry(queue: Arc<Mutex<Self>>) -> JoinHandle<()>
+    where
+        L: 'static,
+        R: 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                let wait = {
+                    let queue = queue.lock().await;
+                    match queue.retry_at {
+                        _ if queue.pending.is_empty() => INITIAL_BACKOFF,
+                        Some(retry_at) => retry_at.saturating_duration_since(Instant::now()),
+                        None => Duration::ZERO,
+                    }
+                };
+                tokio::time::sleep(wait).await;
+
+                if let Err(error) = queue.lock().await.flush_if_due().await {
+                    log::warn!("could not flush the offline queue: {}", error);
+                    tokio::time::sleep(INITIAL_BACKOFF).await;
+                }
+            }
+        })
+    }
+
+    // flushes unless the remote failed recently and the backoff hasn't passed
+    pub async fn flush_if_due(&mut self) -> Result<usize> {
+        match self.retry_at {
+            Some(retry_at) if Instant::now() < retry_at => Ok(0),
+            _ => self.flush().await,
+        }
+    }
+
+    fn enqueue(&mut self, entries: Vec<(ActionId, Action)>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.queue_path)
+            .map_err(Error::OfflineQueue)?;
+
+        for entry in entries {
+            let line = serde_json::to_string(&entry).map_err(Error::JsonError)?;
+            writeln!(file, "{}", line).map_err(Error::OfflineQueue)?;
+            self.pending.push_back(entry);
+        }
+
+        file.sync_data().map_err(Error::OfflineQueue)
+    }
+
+    // the queued actions on the graph of a refused action would apply to a state the
+    // remote never had
+    fn reject_dependents(&mut self, refused_id: ActionId, refused: &Action) -> Result<()> {
+        let graph_id = match graph_of(refused) {
+            Some(graph_id) => graph_id,
+            None => return Ok(()),
+        };
+
+        let (dependents, rest) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(_, action)| graph_of(action) == Some(graph_id));
+        self.pending = rest;
+
+        for (action_id, action) in dependents {
+            self.reject(Rejected {
+                action_id,
+                action,
+                error: Error::DependsOnRejected(refused_id.client_id, refused_id.seq),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn reject(&mut self, rejected: Rejected) -> Result<()> {
+        log::warn!(
+            "the remote refused {}/{}: {}",
+            rejected.action_id.client_id,
+            rejected.action_id.seq,
+            rejected.error
+        );
+
+        let entry = (
+            rejected.action_id,
+            &rejected.action,
+            rejected.error.to_string(),
+        );
+        let line = serde_json::to_string(&entry).map_err(Error::JsonError)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dead_letter_path())
+            .map_err(Error::OfflineQueue)?;
+        writeln!(file, "{}", line).map_err(Error::OfflineQueue)?;
+        file.sync_data().map_err(Error::OfflineQueue)?;
+
+        self.diverged.extend(graph_of(&rejected.action));
+        self.rejected.push(rejected);
+        Ok(())
+    }
+
+    // rewrites the queue file with what's left
+    fn persist(&self) -> Result<()> {
+        write_lines(&self.queue_path, self.pending.iter())
+    }
+}
+
+fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .map(|line| {
+                let line = line.map_err(Error::OfflineQueue)?;
+                serde_json::from_str(&line).map_err(Error::JsonError)
+            })
+            .collect(),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(Error::OfflineQueue(error)),
+    }
+}
+
+// through a rename so a crash keeps either copy
+fn write_lines<T: Serialize>(path: &Path, entries: impl IntoIterator<Item = T>) -> Result<()> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(&serde_json::to_string(&entry).map_err(Error::JsonError)?);
+        contents.push('\n');
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents).map_err(Error::OfflineQueue)?;
+    fs::rename(&tmp_path, path).map_err(Error::OfflineQueue)
+}
+
+// the remote couldn't be reached or failed on its side, as opposed to refusing the action
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::HttpClientError(error) => {
+            error.is_connect() || error.is_timeout() || error.is_request()
+        }
+        Error::TransactionConflict | Error::RemoteUnavailable(_) => true,
+        _ => false,
+    }
+}
+
+fn graph_of(action: &Action) -> Option<GraphId> {
+    match action {
+        Action::Mutate(graph_id, _)
+        | Action::CreateGraphWithId(graph_id, _)
+        | Action::DeleteGraph(graph_id)
+        | Action::CreateVersionWithId(_, graph_id, _)
+        | Action::CreateVersion(graph_id, _) => Some(*graph_id),
+        Action::RecreateGraph(recreate) => Some(recreate.graph_id),
+        _ => None,
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[async_trait]
+impl<L: Datastore, R: Datastore> Delegate for OfflineQueue<L, R> {
+    type Inner = L;
+
+    fn inner(&self) -> &L {
+        &self.local
+    }
+
+    fn inner_mut(&mut self) -> &mut L {
+        &mut self.local
+    }
+
+    // a failing remote doesn't fail the action, it stays queued, and once applied locally
+    // the action succeeded, a queue that can't be written is kept as the last error
+    async fn forward(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        let seen = self.local.history_buf().len();
+        let reply = self.local.execute_with_id(action_id, msg).await?;
+
+        let executed = self
+            .local
+            .history_buf()
+            .since(seen)
+            .iter()
+            .filter(|(_, action)| is_replayed(action))
+            .cloned()
+            .collect();
+        if let Err(error) = self.enqueue(executed) {
+            log::warn!(
+                "could not queue {}/{}: {}",
+                action_id.client_id,
+                action_id.seq,
+                error
+            );
+            self.last_error = Some(error);
+        }
+        if let Err(error) = self.flush_if_due().await {
+            log::warn!("could not flush the offline queue: {}", error);
+            self.last_error = Some(error);
+        }
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sunshine_core::msg::{MutateKind, QueryKind};
+    use sunshine_core::test_utils::props;
+    use sunshine_indra::store::MemoryDB;
+    use tempfile::TempDir;
+
+    // a remote that can be taken offline or fail on its side
+    struct Flaky {
+        store: MemoryDB,
+        online: bool,
+        unavailable: bool,
+    }
+
+    #[async_trait]
+    impl Delegate for Flaky {
+        type Inner = MemoryDB;
+
+        fn inner(&self) -> &MemoryDB {
+            &self.store
+        }
+
+        fn inner_mut(&mut self) -> &mut MemoryDB {
+            &mut self.store
+        }
+
+        async fn forward(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+            if !self.online {
+                return Err(refused_connection().await);
+            }
+            if self.unavailable {
+                return Err(Error::RemoteUnavailable("503 Service Unavailable".into()));
+            }
+            self.store.execute_with_id(action_id, msg).await
+        }
+    }
+
+    async fn refused_connection() -> Error {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        Error::HttpClientError(reqwest::get(format!("http://{}", addr)).await.unwrap_err())
+    }
+
+    fn queue_path(dir: &TempDir) -> PathBuf {
+        dir.path().join("queue.jsonl")
+    }
+
+    #[tokio::test]
+    async fn test_queue_while_offline() {
+        let dir = TempDir::new().unwrap();
+        let queue_path = queue_path(&dir);
+        let remote = Flaky {
+            store: MemoryDB::default(),
+            online: false,
+            unavailable: false,
+        };
+        let mut queue = OfflineQueue::new(MemoryDB::default(), remote, &queue_path).unwrap();
+
+        let graph_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let retry_at = queue.next_retry().unwrap();
+
+        for i in 0..11 {
+            queue
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateNode(props(json!({ "index": i }))),
+                ))
+                .await
+                .unwrap();
+        }
+        queue
+            .execute(Action::Query(QueryKind::ReadGraph(graph_id)))
+            .await
+            .unwrap();
+
+        // the local store has everything, the remote nothing yet
+        assert_eq!(queue.pending(), 12);
+        assert!(queue.last_error().is_some());
+        assert_eq!(queue.next_retry(), Some(retry_at));
+        assert_eq!(
+            queue
+                .local()
+                .read_graph(graph_id)
+                .await
+                .unwrap()
+                .nodes
+                .len(),
+            11
+        );
+
+        // a failed retry backs off further
+        queue.flush().await.unwrap();
+        assert!(queue.next_retry().unwrap() > retry_at);
+
+        // the queue survives a restart
+        let OfflineQueue { local, remote, .. } = queue;
+        let mut queue = OfflineQueue::new(local, remote, &queue_path).unwrap();
+        assert_eq!(queue.pending(), 12);
+
+        queue.remote().online = true;
+        assert_eq!(queue.flush().await.unwrap(), 12);
+        assert_eq!(queue.pending(), 0);
+        assert!(queue.last_error().is_none());
+        assert_eq!(
+            queue
+                .remote()
+                .store
+                .read_graph(graph_id)
+                .await
+                .unwrap()
+                .nodes
+                .len(),
+            11
+        );
+
+        let queue =
+            OfflineQueue::new(MemoryDB::default(), MemoryDB::default(), &queue_path).unwrap();
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_refused_action_is_dead_lettered() {
+        let dir = TempDir::new().unwrap();
+        let mut queue =
+            OfflineQueue::new(MemoryDB::default(), MemoryDB::default(), queue_path(&dir)).unwrap();
+
+        let graph_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        // a node the remote never hears of
+        let node_id = queue
+            .local()
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "a" }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        queue
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::UpdateNode((node_id, props(json!({ "name": "b" })))),
+            ))
+            .await
+            .unwrap();
+        queue
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "c" }))),
+            ))
+            .await
+            .unwrap();
+
+        // the node created after the refusal came back doesn't build on the update
+        assert_eq!(queue.pending(), 0);
+        assert!(queue.last_error().is_none());
+        let rejected = queue.take_rejected();
+        assert_eq!(rejected.len(), 1);
+        assert!(matches!(rejected[0].error, Error::NodeNotFound));
+        let dead_letters = fs::read_to_string(queue.dead_letter_path()).unwrap();
+        assert_eq!(dead_letters.lines().count(), 1);
+        assert_eq!(
+            queue
+                .remote()
+                .read_graph(graph_id)
+                .await
+                .unwrap()
+                .nodes
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dependents_are_dead_lettered() {
+        let dir = TempDir::new().unwrap();
+        let remote = Flaky {
+            store: MemoryDB::default(),
+            online: false,
+            unavailable: false,
+        };
+        let queue_path = queue_path(&dir);
+        let mut queue = OfflineQueue::new(MemoryDB::default(), remote, &queue_path).unwrap();
+
+        let graph_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let node_id = queue
+            .local()
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "a" }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        for name in ["b", "c"] {
+            queue
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::UpdateNode((node_id, props(json!({ "name": name })))),
+                ))
+                .await
+                .unwrap();
+        }
+        let other_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "other" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        queue
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "d" }))),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(queue.pending(), 5);
+
+        // the actions queued behind the refused update on its graph aren't delivered
+        queue.remote().online = true;
+        assert_eq!(queue.flush().await.unwrap(), 2);
+        assert_eq!(queue.pending(), 0);
+        let rejected = queue.take_rejected();
+        assert_eq!(rejected.len(), 3);
+        assert!(matches!(rejected[0].error, Error::NodeNotFound));
+        assert!(rejected[1..]
+            .iter()
+            .all(|rejected| matches!(rejected.error, Error::DependsOnRejected(..))));
+        let dead_letters = fs::read_to_string(queue.dead_letter_path()).unwrap();
+        assert_eq!(dead_letters.lines().count(), 3);
+        assert!(queue.remote().store.read_graph(other_id).await.is_ok());
+        assert!(queue
+            .remote()
+            .store
+            .read_graph(graph_id)
+            .await
+            .unwrap()
+            .nodes
+            .is_empty());
+
+        // the local graph still has them, which is reported until it's resolved
+        assert_eq!(queue.diverged(), vec![graph_id]);
+        assert_eq!(
+            queue
+                .local()
+                .read_graph(graph_id)
+                .await
+                .unwrap()
+                .nodes
+                .len(),
+            2
+        );
+        let OfflineQueue { local, remote, .. } = queue;
+        let mut queue = OfflineQueue::new(local, remote, &queue_path).unwrap();
+        assert_eq!(queue.diverged(), vec![graph_id]);
+
+        queue.resolve(graph_id).unwrap();
+        assert!(queue.diverged().is_empty());
+        let dead_letters = fs::read_to_string(queue.dead_letter_path()).unwrap();
+        assert!(dead_letters.is_empty());
+        let OfflineQueue { local, remote, .. } = queue;
+        let queue = OfflineQueue::new(local, remote, &queue_path).unwrap();
+        assert!(queue.diverged().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_queue_keeps_the_local_action() {
+        let dir = TempDir::new().unwrap();
+        let remote = Flaky {
+            store: MemoryDB::default(),
+            online: false,
+            unavailable: false,
+        };
+        // the directory of the queue file is missing, so it can't be appended to
+        let queue_path = dir.path().join("missing").join("queue.jsonl");
+        let mut queue = OfflineQueue::new(MemoryDB::default(), remote, queue_path).unwrap();
+
+        let graph_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        assert!(queue.local().read_graph(graph_id).await.is_ok());
+        assert_eq!(queue.pending(), 0);
+        assert!(matches!(queue.last_error(), Some(Error::OfflineQueue(_))));
+    }
+
+    #[tokio::test]
+    async fn test_server_error_is_retried() {
+        let dir = TempDir::new().unwrap();
+        let remote = Flaky {
+            store: MemoryDB::default(),
+            online: true,
+            unavailable: true,
+        };
+        let mut queue = OfflineQueue::new(MemoryDB::default(), remote, queue_path(&dir)).unwrap();
+
+        let graph_id = queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        assert_eq!(queue.pending(), 1);
+        assert!(matches!(
+            queue.last_error(),
+            Some(Error::RemoteUnavailable(_))
+        ));
+        assert!(queue.take_rejected().is_empty());
+
+        queue.remote().unavailable = false;
+        assert_eq!(queue.flush().await.unwrap(), 1);
+        assert!(queue.remote().store.read_graph(graph_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_background_retry() {
+        let dir = TempDir::new().unwrap();
+        let remote = Flaky {
+            store: MemoryDB::default(),
+            online: false,
+            unavailable: false,
+        };
+        let mut queue = OfflineQueue::new(MemoryDB::default(), remote, queue_path(&dir)).unwrap();
+        queue
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap();
+        assert_eq!(queue.pending(), 1);
+
+        let queue = Arc::new(Mutex::new(queue));
+        let retry = OfflineQueue::spawn_retry(queue.clone());
+        queue.lock().await.remote().online = true;
+
+        let drained = tokio::time::timeout(Duration::from_secs(10), async {
+            while queue.lock().await.pending() > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        retry.abort();
+        assert!(drained.is_ok());
+    }
+}
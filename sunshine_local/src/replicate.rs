@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+use sunshine_core::delegate::Delegate;
+use sunshine_core::error::{Error, Result};
+use sunshine_core::msg::{Action, ActionId, GraphId, RecreateGraph, Reply};
+use sunshine_core::store::Datastore;
+
+use crate::sync::is_replayed;
+
+#[derive(Debug)]
+pub enum Divergence {
+    // the secondary rejected an action the primary applied
+    Failed {
+        action_id: ActionId,
+        error: Error,
+    },
+    // the graph content differs between the stores after the action
+    Content {
+        action_id: ActionId,
+        graph_id: GraphId,
+        primary_hash: String,
+        secondary_hash: String,
+    },
+}
+
+// applies every mutation to both stores with the same ids and serves reads from the primary,
+// the secondary is never rolled back, divergences are collected for the caller instead,
+// the content is compared on `check` or every few actions, as each comparison reads
+// and hashes whole graphs on both stores
+pub struct Replicated<P, S> {
+    primary: P,
+    secondary: S,
+    divergences: Vec<Divergence>,
+    check_every: Option<usize>,
+    since_check: usize,
+    // the graphs changed since the last check, with the last action that changed them
+    unchecked: HashMap<GraphId, ActionId>,
+}
+
+impl<P: Datastore, S: Datastore> Replicated<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        Replicated {
+            primary,
+            secondary,
+            divergences: Vec::new(),
+            check_every: None,
+            since_check: 0,
+            unchecked: HashMap::new(),
+        }
+    }
+
+    // checks the changed graphs after every `actions` replicated actions
+    pub fn check_every(mut self, actions: usize) -> Self {
+        self.check_every = Some(actions);
+        self
+    }
+
+    pub fn primary(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    pub fn secondary(&mut self) -> &mut S {
+        &mut self.secondary
+    }
+
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    pub fn take_divergences(&mut self) -> Vec<Divergence> {
+        std::mem::take(&mut self.divergences)
+    }
+
+    // compares the graphs changed since the last check
+    pub async fn check(&mut self) {
+        self.since_check = 0;
+        for (graph_id, action_id) in std::mem::take(&mut self.unchecked) {
+            self.compare(action_id, graph_id).await;
+        }
+    }
+
+    async fn replicate(&mut self, action_id: ActionId, action: Action) {
+        match action {
+            Action::Mutate(graph_id, _)
+            | Action::CreateGraphWithId(graph_id, _)
+            | Action::CreateTemplateWithId(graph_id, _, _)
+            | Action::RecreateGraph(RecreateGraph { graph_id, .. }) => {
+                self.unchecked.insert(graph_id, action_id);
+            }
+            // nothing left to compare
+            Action::DeleteGraph(graph_id) => {
+                self.unchecked.remove(&graph_id);
+            }
+            _ => (),
+        }
+
+        if let Err(error) = self.secondary.execute_with_id(action_id, action).await {
+            self.divergences
+                .push(Divergence::Failed { action_id, error });
+            return;
+        }
+
+        self.since_check += 1;
+        if self
+            .check_every
+            .is_some_and(|actions| self.since_check >= actions)
+        {
+            self.check().await;
+        }
+    }
+
+    // only the hashes are compared, a remote store computes its own
+    async fn compare(&mut self, action_id: ActionId, graph_id: GraphId) {
+        let primary = self.primary.read_graph_hash(graph_id).await;
+        let secondary = self.secondary.read_graph_hash(graph_id).await;
+
+        match (primary, secondary) {
+            (Ok(primary_hash), Ok(secondary_hash)) => {
+                if primary_hash != secondary_hash {
+                    self.divergences.push(Divergence::Content {
+                        action_id,
+                        graph_id,
+                        primary_hash,
+                        secondary_hash,
+                    });
+                }
+            }
+            (_, Err(error)) | (Err(error), _) => {
+                self.divergences
+                    .push(Divergence::Failed { action_id, error });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Datastore, S: Datastore> Delegate for Replicated<P, S> {
+    type Inner = P;
+
+    fn inner(&self) -> &P {
+        &self.primary
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    // the primary history holds the actions with their generated ids filled in,
+    // replaying those keeps the ids of both stores in step
+    async fn forward(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        let seen = self.primary.history_buf().len();
+        let reply = self.primary.execute_with_id(action_id, msg).await?;
+
+        let executed: Vec<(ActionId, Action)> = self
+            .primary
+            .history_buf()
+            .since(seen)
+            .iter()
+            .filter(|(_, action)| is_replayed(action))
+            .cloned()
+            .collect();
+        for (action_id, action) in executed {
+            self.replicate(action_id, action).await;
+        }
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sunshine_core::msg::{CreateEdge, MutateKind, NodeId, QueryKind};
+    use sunshine_core::test_utils::props;
+    use sunshine_indra::store::MemoryDB;
+
+    async fn create_node<D: Datastore>(store: &mut D, graph_id: GraphId, name: &str) -> NodeId {
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": name }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replicate_with_matching_ids() {
+        let mut store = Replicated::new(MemoryDB::default(), MemoryDB::default());
+
+        let graph_id = store
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let a = create_node(&mut store, graph_id, "a").await;
+        let b = create_node(&mut store, graph_id, "b").await;
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateEdge(CreateEdge {
+                    from: a,
+                    to: b,
+                    properties: props(json!({ "weight": 1 })),
+                }),
+            ))
+            .await
+            .unwrap();
+
+        let copied = store
+            .execute(Action::Query(QueryKind::CopySubgraph(vec![a, b])))
+            .await
+            .unwrap()
+            .into_subgraph()
+            .unwrap();
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::PasteSubgraph(copied.clone()),
+            ))
+            .await
+            .unwrap();
+        store.execute(Action::Undo).await.unwrap();
+        store.execute(Action::Redo).await.unwrap();
+
+        // templates and versions get the ids the primary generated
+        let template_id = store
+            .execute(Action::CreateTemplate("pair".into(), copied))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let version_id = store
+            .execute(Action::CreateVersion(graph_id, "v1".into()))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        assert_eq!(
+            store
+                .secondary()
+                .read_graph_hash(template_id)
+                .await
+                .unwrap(),
+            store.primary().read_graph_hash(template_id).await.unwrap()
+        );
+        let versions = store.secondary().list_versions(graph_id).await.unwrap();
+        assert_eq!(versions[0].0, version_id);
+
+        store.check().await;
+        assert!(store.divergences().is_empty(), "{:?}", store.divergences());
+        let secondary = store.secondary().read_node(a).await.unwrap();
+        assert_eq!(secondary.properties["name"], json!("a"));
+
+        // a write that bypasses the replication shows up once the graph is checked
+        create_node(store.secondary(), graph_id, "stray").await;
+        let c = create_node(&mut store, graph_id, "c").await;
+        assert!(store.divergences().is_empty());
+        store.check().await;
+
+        let divergences = store.take_divergences();
+        assert_eq!(divergences.len(), 1);
+        assert!(
+            matches!(divergences[0], Divergence::Content { graph_id: id, .. } if id == graph_id)
+        );
+        assert!(store.divergences().is_empty());
+
+        // or every few actions when asked for
+        let mut store = store.check_every(2);
+        store
+            .execute(Action::Mutate(graph_id, MutateKind::DeleteNode(c)))
+            .await
+            .unwrap();
+        assert!(store.divergences().is_empty());
+        create_node(&mut store, graph_id, "d").await;
+        assert_eq!(store.take_divergences().len(), 1);
+    }
+}
@@ -0,0 +1,561 @@
+use clap::Parser;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use sunshine_core::error::{Error, Result};
+use sunshine_core::msg::{
+    Action, CreateEdge, GraphId, MutateKind, NodeId, Properties, QueryKind, Reply,
+};
+use sunshine_core::store::Datastore;
+
+use crate::cli::{self, Command, Session};
+
+// the cli commands are available as well, with the graph given explicitly
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true, name = "sunshine")]
+enum ShellCommand {
+    /// selects the graph the commands below work on
+    Use {
+        graph_id: GraphId,
+    },
+    /// lists the graphs
+    Graphs,
+    /// lists the nodes of the graph
+    Nodes,
+    /// shows a node with its properties and neighbours
+    Show {
+        node_id: NodeId,
+    },
+    /// adds a node to the graph
+    Add {
+        #[arg(value_parser = cli::parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+    /// sets a single property of a node, the value is json
+    Set {
+        node_id: NodeId,
+        key: String,
+        #[arg(value_parser = cli::parse_json::<JsonValue>)]
+        value: JsonValue,
+    },
+    /// adds an edge between two nodes of the graph
+    Link {
+        from: NodeId,
+        to: NodeId,
+        #[arg(value_parser = cli::parse_json::<Properties>, default_value = "{}")]
+        properties: Properties,
+    },
+    /// deletes a node of the graph along with its edges
+    Rm {
+        node_id: NodeId,
+    },
+    Exit,
+    #[command(flatten)]
+    Cli(Command),
+}
+
+impl ShellCommand {
+    fn needs_graph(&self) -> bool {
+        matches!(
+            self,
+            ShellCommand::Nodes
+                | ShellCommand::Add { .. }
+                | ShellCommand::Set { .. }
+                | ShellCommand::Link { .. }
+                | ShellCommand::Rm { .. }
+        )
+    }
+
+    fn mutates(&self) -> bool {
+        match self {
+            ShellCommand::Add { .. }
+            | ShellCommand::Set { .. }
+            | ShellCommand::Link { .. }
+            | ShellCommand::Rm { .. } => true,
+            ShellCommand::Cli(command) => !command.is_read_only(),
+            _ => false,
+        }
+    }
+}
+
+pub struct Shell {
+    store: Box<dyn Datastore>,
+    graph_id: Option<GraphId>,
+    // the graph the completions were read for, None once a command changed the store
+    completed_for: Option<Option<GraphId>>,
+}
+
+impl Shell {
+    pub fn new(store: Box<dyn Datastore>) -> Self {
+        Shell {
+            store,
+            graph_id: None,
+            completed_for: None,
+        }
+    }
+
+    pub fn store(&mut self) -> &mut dyn Datastore {
+        self.store.as_mut()
+    }
+
+    fn prompt(&self) -> String {
+        match self.graph_id {
+            Some(graph_id) => format!("sunshine {}> ", graph_id),
+            None => "sunshine> ".into(),
+        }
+    }
+
+    // the output of the line, or None once the shell should exit
+    pub async fn execute(&mut self, line: &str) -> Result<Option<String>> {
+        let command = match ShellCommand::try_parse_from(split_args(line)) {
+            Ok(command) => command,
+            Err(error) => return Ok(Some(error.to_string())),
+        };
+
+        if command.needs_graph() && self.graph_id.is_none() {
+            return Ok(Some("no graph selected, pick one with `use`".into()));
+        }
+        let graph_id = self.graph_id.unwrap_or_default();
+        let mutates = command.mutates();
+
+        let output = match command {
+            ShellCommand::Use { graph_id } => {
+                self.store.read_graph(graph_id).await?;
+                self.graph_id = Some(graph_id);
+                String::new()
+            }
+            ShellCommand::Graphs => {
+                let reply = self.store.execute_read_only(QueryKind::ListGraphs).await?;
+                format_reply(&reply)?
+            }
+            ShellCommand::Nodes => {
+                let (_, subgraph) = self.store.read_graph_snapshot(graph_id).await?;
+                properties_table(&subgraph.nodes)
+            }
+            ShellCommand::Show { node_id } => self.neighbourhood(node_id).await?,
+            ShellCommand::Add { properties } => {
+                self.mutate(graph_id, MutateKind::CreateNode(properties))
+                    .await?
+            }
+            ShellCommand::Set {
+                node_id,
+                key,
+                value,
+            } => {
+                let mut properties = self.store.read_node(node_id).await?.properties;
+                properties.insert(key, value);
+                self.mutate(graph_id, MutateKind::UpdateNode((node_id, properties)))
+                    .await?
+            }
+            ShellCommand::Link {
+                from,
+                to,
+                properties,
+            } => {
+                let create_edge = CreateEdge {
+                    from,
+                    to,
+                    properties,
+                };
+                self.mutate(graph_id, MutateKind::CreateEdge(create_edge))
+                    .await?
+            }
+            ShellCommand::Rm { node_id } => {
+                self.mutate(graph_id, MutateKind::DeleteNode(node_id))
+                    .await?
+            }
+            ShellCommand::Exit => return Ok(None),
+            ShellCommand::Cli(command) => {
+                let reply = cli::run(self.store.as_mut(), command).await?;
+                format_reply(&reply)?
+            }
+        };
+
+        if mutates {
+            self.completed_for = None;
+        }
+        Ok(Some(output))
+    }
+
+    async fn mutate(&mut self, graph_id: GraphId, kind: MutateKind) -> Result<String> {
+        let reply = self.store.execute(Action::Mutate(graph_id, kind)).await?;
+        format_reply(&reply)
+    }
+
+    async fn neighbourhood(&mut self, node_id: NodeId) -> Result<String> {
+        let node = self.store.read_node(node_id).await?;
+
+        let properties = node
+            .properties
+            .iter()
+            .map(|(key, value)| vec![key.clone(), value.to_string()])
+            .collect();
+
+        let edges = node
+            .outbound_edges
+            .iter()
+            .map(|edge| ("->", edge, edge.to))
+            .chain(
+                node.inbound_edges
+                    .iter()
+                    .map(|edge| ("<-", edge, edge.from)),
+            )
+            // the graph root is linked to every node of the graph
+            .filter(|(_, _, other)| Some(*other) != self.graph_id);
+
+        let mut neighbours = Vec::new();
+        for (direction, edge, other) in edges {
+            let name = match self.store.read_node(other).await {
+                Ok(other) => other
+                    .properties
+                    .get("name")
+                    .map(JsonValue::to_string)
+                    .unwrap_or_default(),
+                Err(_) => String::new(),
+            };
+            let properties = self.store.read_edge_properties(*edge).await?;
+            neighbours.push(vec![
+                direction.into(),
+                other.to_string(),
+                name,
+                edge.id.to_string(),
+                JsonValue::Object(properties).to_string(),
+            ]);
+        }
+
+        Ok(format!(
+            "{}\n{}",
+            table(&["property", "value"], properties),
+            table(&["", "node", "name", "edge", "properties"], neighbours)
+        ))
+    }
+
+    // node ids, graph ids and property keys for tab-completion
+    async fn completions(&self) -> BTreeSet<String> {
+        let mut words = BTreeSet::new();
+
+        if let Ok(graphs) = self.store.list_graphs().await {
+            words.extend(graphs.iter().map(|(graph_id, _)| graph_id.to_string()));
+        }
+
+        if let Some(graph_id) = self.graph_id {
+            if let Ok((_, subgraph)) = self.store.read_graph_snapshot(graph_id).await {
+                for (node_id, properties) in &subgraph.nodes {
+                    words.insert(node_id.to_string());
+                    words.extend(properties.keys().cloned());
+                }
+            }
+        }
+
+        words
+    }
+
+    // completions are read again only after a command changed the store or when another
+    // graph is selected, changes made by other clients show up with the next local one
+    async fn refreshed_completions(&mut self) -> Option<BTreeSet<String>> {
+        let completed_for = Some(self.graph_id);
+        if self.completed_for == completed_for {
+            return None;
+        }
+
+        self.completed_for = completed_for;
+        Some(self.completions().await)
+    }
+}
+
+// runs until `exit` or end of input, then keeps undo and redo in the session file
+pub async fn run(
+    store: Box<dyn Datastore>,
+    session_path: &Path,
+    store_id: Option<&str>,
+) -> Result<()> {
+    let mut shell = Shell::new(store);
+    Session::load(session_path)?.restore(shell.store(), store_id);
+
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper::default()));
+
+    loop {
+        if let Some(words) = shell.refreshed_completions().await {
+            if let Some(helper) = editor.helper_mut() {
+                helper.words = words;
+            }
+        }
+
+        let line = match editor.readline(&shell.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Io(error)) => return Err(Error::File(error)),
+            Err(error) => {
+                eprintln!("{}", error);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str());
+
+        match shell.execute(&line).await {
+            Ok(Some(output)) if output.is_empty() => {}
+            Ok(Some(output)) => println!("{}", output.trim_end()),
+            Ok(None) => break,
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+
+    Session::save(shell.store(), store_id, session_path)
+}
+
+// splits on whitespace outside of json, so properties can be typed without quoting
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+
+    for c in line.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth = depth.saturating_sub(1),
+                c if c.is_whitespace() && depth == 0 => {
+                    if !current.is_empty() {
+                        args.push(std::mem::take(&mut current));
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+fn format_reply(reply: &Reply) -> Result<String> {
+    let output = match reply {
+        Reply::Empty => String::new(),
+        Reply::Id(id) => id.to_string(),
+        Reply::NodeList(nodes) => properties_table(nodes),
+        reply => serde_json::to_string_pretty(reply).map_err(Error::JsonError)?,
+    };
+
+    Ok(output)
+}
+
+// one row per id with a column for every property key in use
+fn properties_table(rows: &[(NodeId, Properties)]) -> String {
+    let keys: BTreeSet<&String> = rows
+        .iter()
+        .flat_map(|(_, properties)| properties.keys())
+        .collect();
+
+    let headers: Vec<&str> = std::iter::once("id")
+        .chain(keys.iter().map(|key| key.as_str()))
+        .collect();
+
+    let rows = rows
+        .iter()
+        .map(|(id, properties)| {
+            std::iter::once(id.to_string())
+                .chain(keys.iter().map(|key| {
+                    properties
+                        .get(*key)
+                        .map(JsonValue::to_string)
+                        .unwrap_or_default()
+                }))
+                .collect()
+        })
+        .collect();
+
+    table(&headers, rows)
+}
+
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        format!("{}\n", cells.join(" | ").trim_end())
+    };
+
+    let mut output = line(headers.to_vec());
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    output.push_str(&format!("{}\n", separator.join("-+-")));
+    for row in &rows {
+        output.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    output
+}
+
+#[derive(Default)]
+struct ShellHelper {
+    words: BTreeSet<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // property keys are completed inside json too
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || "{[,:\"".contains(c))
+            .map_or(0, |index| index + 1);
+        let prefix = &line[start..pos];
+
+        let candidates = self
+            .words
+            .iter()
+            .filter(|word| word.starts_with(prefix))
+            .map(|word| Pair {
+                display: word.clone(),
+                replacement: word.clone(),
+            })
+            .collect();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sunshine_indra::store::MemoryDB;
+
+    fn make_shell() -> Shell {
+        Shell::new(Box::new(MemoryDB::default()))
+    }
+
+    async fn execute(shell: &mut Shell, line: &str) -> String {
+        shell.execute(line).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args(r#"add {"name": "a b", "tags": ["x", "y"]}  "#),
+            vec!["add", r#"{"name": "a b", "tags": ["x", "y"]}"#]
+        );
+        assert_eq!(
+            split_args(r#"set id name "with \" quote""#),
+            vec!["set", "id", "name", r#""with \" quote""#]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let mut shell = make_shell();
+
+        let graph_id = execute(&mut shell, r#"graph create {"name": "graph"}"#).await;
+        assert!(execute(&mut shell, "add")
+            .await
+            .starts_with("no graph selected"));
+        execute(&mut shell, &format!("use {}", graph_id)).await;
+
+        let a: NodeId = execute(&mut shell, r#"add {"name": "a"}"#)
+            .await
+            .parse()
+            .unwrap();
+        let b: NodeId = execute(&mut shell, r#"add {"name": "b"}"#)
+            .await
+            .parse()
+            .unwrap();
+        execute(&mut shell, &format!(r#"link {} {} {{"weight": 1}}"#, a, b)).await;
+        execute(&mut shell, &format!(r#"set {} colour "red""#, a)).await;
+
+        let nodes = execute(&mut shell, "nodes").await;
+        assert!(nodes.starts_with("id"));
+        assert!(nodes.contains(r#""red""#));
+
+        let show = execute(&mut shell, &format!("show {}", a)).await;
+        assert!(show.contains(&b.to_string()));
+        assert!(show.contains(r#"{"weight":1}"#));
+
+        let words = shell.refreshed_completions().await.unwrap();
+        assert!(words.contains("colour"));
+        assert!(words.contains(&a.to_string()));
+        execute(&mut shell, "nodes").await;
+        execute(&mut shell, &format!("node show {}", b)).await;
+        assert!(shell.refreshed_completions().await.is_none());
+
+        // a failed command changes nothing
+        assert!(shell
+            .execute(&format!("rm {}", uuid::Uuid::new_v4()))
+            .await
+            .is_err());
+        assert!(shell.refreshed_completions().await.is_none());
+
+        // undo and redo carry across commands
+        execute(&mut shell, "undo").await;
+        let node = shell.store().read_node(a).await.unwrap();
+        assert_eq!(node.properties.get("colour"), None);
+        execute(&mut shell, "redo").await;
+        let node = shell.store().read_node(a).await.unwrap();
+        assert_eq!(node.properties["colour"], json!("red"));
+        let words = shell.refreshed_completions().await.unwrap();
+        assert!(words.contains("colour"));
+
+        // the next run of the shell goes on with the same undo
+        let dir = tempfile::TempDir::new().unwrap();
+        let session = dir.path().join("session.json");
+        Session::save(shell.store(), Some("indra shell"), &session).unwrap();
+        let mut restarted = make_shell();
+        Session::load(&session)
+            .unwrap()
+            .restore(restarted.store(), Some("indra shell"));
+        assert_eq!(
+            restarted.store().undo_buf().len(),
+            shell.store().undo_buf().len()
+        );
+
+        assert!(shell.execute("exit").await.unwrap().is_none());
+    }
+}
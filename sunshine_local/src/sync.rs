@@ -0,0 +1,254 @@
+use sunshine_core::error::Error;
+use sunshine_core::msg::{Action, ActionId, QueryKind, Reply};
+use sunshine_core::store::{Datastore, Result};
+
+// replays the local history on a remote store, each action is pushed exactly once
+pub struct HistorySync<L, R> {
+    local: L,
+    remote: R,
+    // position in the local history the remote had seen up to as of the last push
+    synced: usize,
+}
+
+impl<L: Datastore, R: Datastore> HistorySync<L, R> {
+    pub fn new(local: L, remote: R) -> Self {
+        HistorySync {
+            local,
+            remote,
+            synced: 0,
+        }
+    }
+
+    pub fn local(&mut self) -> &mut L {
+        &mut self.local
+    }
+
+    pub fn remote(&mut self) -> &mut R {
+        &mut self.remote
+    }
+
+    pub fn synced(&self) -> usize {
+        self.synced
+    }
+
+    pub fn pending(&mut self) -> &[(ActionId, Action)] {
+        self.local.history_buf().since(self.synced)
+    }
+
+    // asks the remote for the last action of this client it applied, entries up to it
+    // are seen, an undo comes after the reverse it executed so it counts as seen too
+    pub async fn fetch_progress(&mut self) -> Result<usize> {
+        let client_id = self.local.history_buf().client_id();
+        let last_seq = match self
+            .remote
+            .execute(Action::Query(QueryKind::LastApplied(client_id)))
+            .await?
+        {
+            Reply::Seq(last_seq) => last_seq,
+            _ => return Err(Error::UnexpectedReply),
+        };
+
+        // entries dropped from the local history can't be pushed anymore
+        let history = self.local.history_buf();
+        self.synced = match last_seq {
+            Some(last_seq) => history
+                .entries()
+                .iter()
+                .rposition(|(action_id, _)| {
+                    action_id.client_id == client_id && action_id.seq <= last_seq
+                })
+                .map_or(history.start(), |position| history.start() + position + 1),
+            None => history.start(),
+        };
+
+        Ok(self.synced)
+    }
+
+    // pushes everything the remote hasn't seen in order and returns how many actions
+    // were replayed, progress comes from the remote so a push after a failure or a
+    // restart resumes where it stopped, the remote skips ids it already applied so
+    // resending after a lost reply is harmless
+    pub async fn push(&mut self) -> Result<usize> {
+        self.fetch_progress().await?;
+        let pending = self.pending().to_vec();
+        let mut replayed = 0;
+
+        for (action_id, action) in pending {
+            if is_replayed(&action) {
+                self.remote.execute_with_id(action_id, action).await?;
+                replayed += 1;
+            }
+            self.synced += 1;
+        }
+
+        Ok(replayed)
+    }
+}
+
+// undo and redo are already in the history as the mutations they executed
+pub(crate) fn is_replayed(action: &Action) -> bool {
+    !matches!(action, Action::Undo | Action::Redo | Action::Query(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sunshine_core::msg::{CreateEdge, GraphId, MutateKind, Properties, QueryKind, Reply};
+    use sunshine_core::test_utils::props;
+    use sunshine_indra::store::{DbConfig, MemoryDB, DB};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    async fn graph_hash<D: Datastore>(store: &mut D, graph_id: GraphId) -> String {
+        match store
+            .execute(Action::Query(QueryKind::ReadGraphHash(graph_id)))
+            .await
+            .unwrap()
+        {
+            Reply::Hash(hash) => hash,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_offline_actions() {
+        let mut sync = HistorySync::new(MemoryDB::default(), MemoryDB::default());
+        let local = sync.local();
+
+        let graph_id = local
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        // 100 actions while offline, with generated ids and an undo in between
+        let mut previous = None;
+        for i in 0..48 {
+            let node_id = local
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateNode(props(json!({ "index": i }))),
+                ))
+                .await
+                .unwrap()
+                .as_id()
+                .unwrap();
+
+            if let Some(from) = previous {
+                local
+                    .execute(Action::Mutate(
+                        graph_id,
+                        MutateKind::CreateEdge(CreateEdge {
+                            from,
+                            to: node_id,
+                            properties: Properties::new(),
+                        }),
+                    ))
+                    .await
+                    .unwrap();
+            }
+            previous = Some(node_id);
+        }
+        local.execute(Action::Undo).await.unwrap();
+        local.execute(Action::Undo).await.unwrap();
+
+        assert_eq!(sync.pending().len(), 100);
+        assert_eq!(sync.push().await.unwrap(), 98);
+        assert!(sync.pending().is_empty());
+
+        let local_hash = graph_hash(sync.local(), graph_id).await;
+        assert_eq!(graph_hash(sync.remote(), graph_id).await, local_hash);
+
+        // reconnecting pushes only what happened since, the hash query isn't in the history
+        sync.local()
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "index": "late" }))),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(sync.push().await.unwrap(), 1);
+        assert_eq!(sync.synced(), 100 + 1);
+        assert!(sync.pending().is_empty());
+
+        // the generated ids inside a batch are replayed too
+        let a = Uuid::new_v4();
+        sync.local()
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::Batch(vec![
+                    MutateKind::CreateNodeWithId((a, props(json!({ "name": "a" })))),
+                    MutateKind::CreateNode(props(json!({ "name": "b" }))),
+                    MutateKind::CreateEdge(CreateEdge {
+                        from: a,
+                        to: a,
+                        properties: Properties::new(),
+                    }),
+                ]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(sync.push().await.unwrap(), 1);
+
+        let local_hash = graph_hash(sync.local(), graph_id).await;
+        assert_eq!(graph_hash(sync.remote(), graph_id).await, local_hash);
+
+        // a new sync over the same stores learns from the remote that nothing is left
+        let mut sync = HistorySync::new(sync.local, sync.remote);
+        assert_eq!(sync.synced(), 0);
+        assert_eq!(sync.push().await.unwrap(), 0);
+        assert_eq!(sync.synced(), sync.local().history_buf().len());
+    }
+
+    #[tokio::test]
+    async fn test_push_after_reopening() {
+        let dir = TempDir::new().unwrap();
+        let cfg = DbConfig {
+            db_path: dir.path().display().to_string(),
+            undo_capacity: None,
+        };
+        let mut sync = HistorySync::new(DB::new(&cfg).unwrap(), MemoryDB::default());
+
+        let graph_id = sync
+            .local()
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        for name in ["a", "b"] {
+            sync.local()
+                .execute(Action::Mutate(
+                    graph_id,
+                    MutateKind::CreateNode(props(json!({ "name": name }))),
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(sync.push().await.unwrap(), 3);
+
+        // an action made offline, then the local store is closed before it's pushed
+        sync.local()
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "c" }))),
+            ))
+            .await
+            .unwrap();
+        let HistorySync { local, remote, .. } = sync;
+        let client_id = local.history().client_id();
+        drop(local);
+
+        // the reopened store is the same client, the remote tells what it has seen of it
+        let mut sync = HistorySync::new(DB::new(&cfg).unwrap(), remote);
+        assert_eq!(sync.local().history().client_id(), client_id);
+        assert_eq!(sync.pending().len(), 4);
+        assert_eq!(sync.push().await.unwrap(), 1);
+        assert!(sync.pending().is_empty());
+
+        let local_hash = graph_hash(sync.local(), graph_id).await;
+        assert_eq!(graph_hash(sync.remote(), graph_id).await, local_hash);
+    }
+}
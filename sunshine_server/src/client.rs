@@ -0,0 +1,484 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use sunshine_core::error::{Error, Result};
+use sunshine_core::history::History;
+use sunshine_core::msg::{
+    Action, ActionId, CreateEdge, Edge, EdgeId, Graph, GraphId, Node, NodeId, Properties,
+    QueryKind, RecreateNode, Reply,
+};
+use sunshine_core::store::Datastore;
+use sunshine_core::timeline::Timeline;
+
+use crate::RemoteError;
+
+// a Datastore living in a sunshine server, actions run there as a whole with the history
+// and timeline of the server and undo and redo kept there for this client,
+// so the local buffers stay empty apart from id allocation, of the building blocks
+// only the ones that read are run there
+pub struct RemoteStore {
+    url: String,
+    client: reqwest::Client,
+    undo: Vec<Action>,
+    redo: Vec<Action>,
+    history: History,
+    // where the client id and the last id handed out are kept between runs
+    history_path: Option<PathBuf>,
+    timeline: Timeline,
+}
+
+impl RemoteStore {
+    // a client the server hasn't seen before
+    pub fn new(url: impl Into<String>) -> Self {
+        RemoteStore {
+            url: url.into(),
+            client: reqwest::Client::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            history: History::default(),
+            history_path: None,
+            timeline: Timeline::default(),
+        }
+    }
+
+    // the same client as in the last run, with its undo on the server and without
+    // handing out ids the server already applied
+    pub fn with_history_path(url: impl Into<String>, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let history = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(Error::JsonError)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => History::default(),
+            Err(error) => return Err(Error::File(error)),
+        };
+
+        Ok(RemoteStore {
+            history,
+            history_path: Some(path),
+            ..RemoteStore::new(url)
+        })
+    }
+
+    async fn post<T: Serialize + Sync, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<R> {
+        let response = self
+            .client
+            .post(format!("{}{}", self.url, path))
+            .json(body)
+            .send()
+            .await
+            .map_err(Error::HttpClientError)?;
+
+        // the error of the store when the server names it, otherwise by the status
+        let status = response.status();
+        if !status.is_success() {
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            if let Ok(remote_error) = RemoteError::deserialize(&body["kind"]) {
+                return Err(remote_error.into());
+            }
+            let message = body["error"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or_else(|| status.to_string());
+            return Err(match status {
+                StatusCode::NOT_IMPLEMENTED => Error::Unimplemented,
+                _ if status.is_server_error() => Error::RemoteUnavailable(message),
+                _ => Error::Server(message),
+            });
+        }
+
+        response.json().await.map_err(Error::HttpClientError)
+    }
+
+    async fn query(&self, kind: QueryKind) -> Result<Reply> {
+        self.post("/action", &Action::Query(kind)).await
+    }
+}
+
+fn unexpected_reply() -> Error {
+    Error::Server("unexpected reply".into())
+}
+
+#[async_trait]
+impl Datastore for RemoteStore {
+    fn undo_buf(&mut self) -> &mut Vec<Action> {
+        &mut self.undo
+    }
+
+    fn redo_buf(&mut self) -> &mut Vec<Action> {
+        &mut self.redo
+    }
+
+    fn history(&self) -> &History {
+        &self.history
+    }
+
+    fn history_buf(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    fn timeline_buf(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+
+    async fn save_history(&mut self) -> Result<()> {
+        let path = match &self.history_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text = serde_json::to_string(&self.history).map_err(Error::JsonError)?;
+        fs::write(path, text).map_err(Error::File)
+    }
+
+    // the id is kept as handed out even when the action fails, a retry uses it again
+    async fn execute_with_id(&mut self, action_id: ActionId, msg: Action) -> Result<Reply> {
+        let path = format!("/action/{}/{}", action_id.client_id, action_id.seq);
+        let reply = self.post(&path, &msg).await;
+        self.save_history().await?;
+        reply
+    }
+
+    // the building blocks that write are refused, writes reach the server only as whole
+    // actions, which it records, checks, broadcasts and keeps the undo of
+    async fn update_state_id(&self, _: GraphId) -> Result<u64> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn set_state_id(&self, _: GraphId, _: u64) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn create_graph_with_id(&self, _: GraphId, _: Properties) -> Result<(Action, GraphId)> {
+        Err(Error::Unimplemented)
+    }
+
+    // as listed by the server, without templates and versions
+    async fn list_graphs(&self) -> Result<Vec<(NodeId, Properties)>> {
+        match self.query(QueryKind::ListGraphs).await? {
+            Reply::NodeList(graphs) => Ok(graphs),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    async fn read_graph(&self, graph_id: GraphId) -> Result<Graph> {
+        self.query(QueryKind::ReadGraph(graph_id))
+            .await?
+            .into_graph()
+            .ok_or_else(unexpected_reply)
+    }
+
+    async fn read_graph_hash(&self, graph_id: GraphId) -> Result<String> {
+        match self.query(QueryKind::ReadGraphHash(graph_id)).await? {
+            Reply::Hash(hash) => Ok(hash),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    async fn create_node_with_id(&self, _: NodeId, _: (GraphId, Properties)) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn read_node(&self, node_id: NodeId) -> Result<Node> {
+        self.query(QueryKind::ReadNode(node_id))
+            .await?
+            .into_node()
+            .ok_or_else(unexpected_reply)
+    }
+
+    async fn update_node(&self, _: (NodeId, Properties), _: GraphId) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn recreate_node(&self, _: RecreateNode, _: GraphId) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn recreate_edge(&self, _: Edge, _: Properties) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn delete_node(&self, _: NodeId, _: GraphId) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn create_edge(&self, _: CreateEdge, _: GraphId) -> Result<(Action, EdgeId)> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn read_edge_properties(&self, msg: Edge) -> Result<Properties> {
+        self.query(QueryKind::ReadEdgeProperties(msg))
+            .await?
+            .into_properties()
+            .ok_or_else(unexpected_reply)
+    }
+
+    async fn update_edge(&self, _: (Edge, Properties), _: GraphId) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn delete_edge(&self, _: Edge, _: GraphId) -> Result<Action> {
+        Err(Error::Unimplemented)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{props, spawn_server};
+    use serde_json::json;
+    use sunshine_core::msg::MutateKind;
+    use sunshine_indra::store::MemoryDB;
+    use uuid::Uuid;
+
+    async fn create_node(store: &mut RemoteStore, graph_id: GraphId, name: &str) -> NodeId {
+        store
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": name }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap()
+    }
+
+    async fn graph_hash<D: Datastore>(store: &mut D, graph_id: GraphId) -> String {
+        match store
+            .execute(Action::Query(QueryKind::ReadGraphHash(graph_id)))
+            .await
+            .unwrap()
+        {
+            Reply::Hash(hash) => hash,
+            _ => unreachable!(),
+        }
+    }
+
+    // ids are fixed so both stores end up with the same content
+    fn actions(graph_id: GraphId, a: NodeId, b: NodeId, edge: Edge) -> Vec<Action> {
+        vec![
+            Action::CreateGraphWithId(graph_id, props(json!({ "name": "graph" }))),
+            Action::Mutate(
+                graph_id,
+                MutateKind::CreateNodeWithId((a, props(json!({ "name": "a" })))),
+            ),
+            Action::Mutate(
+                graph_id,
+                MutateKind::CreateNodeWithId((b, props(json!({ "name": "b" })))),
+            ),
+            Action::Mutate(
+                graph_id,
+                MutateKind::RecreateEdge((edge, props(json!({ "weight": 1 })))),
+            ),
+            Action::Mutate(
+                graph_id,
+                MutateKind::UpdateNode((a, props(json!({ "name": "renamed" })))),
+            ),
+            Action::Undo,
+            Action::Redo,
+            Action::Mutate(
+                graph_id,
+                MutateKind::UpdateEdge((edge, props(json!({ "weight": 2 })))),
+            ),
+            Action::Mutate(graph_id, MutateKind::DeleteNode(b)),
+            Action::Undo,
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_remote_matches_embedded() {
+        let mut remote = RemoteStore::new(spawn_server());
+        let mut embedded = MemoryDB::default();
+
+        let (graph_id, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let edge = Edge {
+            id: Uuid::new_v4(),
+            from: a,
+            to: b,
+        };
+
+        for action in actions(graph_id, a, b, edge) {
+            let remote_reply = remote.execute(action.clone()).await.unwrap();
+            let embedded_reply = embedded.execute(action).await.unwrap();
+            assert_eq!(remote_reply.as_id(), embedded_reply.as_id());
+        }
+
+        let hash = graph_hash(&mut embedded, graph_id).await;
+        assert_eq!(graph_hash(&mut remote, graph_id).await, hash);
+
+        // the building blocks go over the wire too
+        let (_, snapshot) = remote.read_graph_snapshot(graph_id).await.unwrap();
+        assert_eq!(snapshot.nodes.len(), 2);
+        assert_eq!(
+            remote.read_edge_properties(edge).await.unwrap()["weight"],
+            json!(2)
+        );
+
+        let node_id = remote
+            .execute(Action::Mutate(
+                graph_id,
+                MutateKind::CreateNode(props(json!({ "name": "c" }))),
+            ))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        assert_eq!(
+            remote.read_node(node_id).await.unwrap().properties["name"],
+            json!("c")
+        );
+
+        assert!(matches!(
+            remote
+                .execute(Action::Query(QueryKind::ReadGraphVersion((
+                    graph_id,
+                    "missing".into()
+                ))))
+                .await,
+            Err(Error::VersionNotFound)
+        ));
+        assert!(matches!(
+            remote.read_node(Uuid::new_v4()).await,
+            Err(Error::NodeNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_is_skipped() {
+        let mut remote = RemoteStore::new(spawn_server());
+
+        let graph_id = remote
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        let action_id = remote.history_buf().next_id();
+        let create = Action::Mutate(
+            graph_id,
+            MutateKind::CreateNode(props(json!({ "name": "a" }))),
+        );
+        let reply = remote
+            .execute_with_id(action_id, create.clone())
+            .await
+            .unwrap();
+        let retried = remote.execute_with_id(action_id, create).await.unwrap();
+        assert_eq!(retried.as_id(), reply.as_id());
+        assert!(reply.as_id().is_some());
+
+        let graph = remote
+            .execute(Action::Query(QueryKind::ReadGraph(graph_id)))
+            .await
+            .unwrap()
+            .into_graph()
+            .unwrap();
+        assert_eq!(graph.nodes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_undo_per_client() {
+        let url = spawn_server();
+        let (mut alice, mut bob) = (RemoteStore::new(url.clone()), RemoteStore::new(url));
+
+        let graph_id = alice
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let a = create_node(&mut alice, graph_id, "a").await;
+        let b = create_node(&mut bob, graph_id, "b").await;
+
+        // alice undoes her own node, not bob's later one
+        alice.execute(Action::Undo).await.unwrap();
+        assert!(alice.read_node(a).await.is_err());
+        assert!(alice.read_node(b).await.is_ok());
+
+        bob.execute(Action::Undo).await.unwrap();
+        assert!(bob.read_node(b).await.is_err());
+        assert!(matches!(
+            bob.execute(Action::Undo).await,
+            Err(Error::UndoBufferEmpty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_survives_restart() {
+        let url = spawn_server();
+        let dir = tempfile::TempDir::new().unwrap();
+        let history_path = dir.path().join("client.json");
+
+        let mut remote = RemoteStore::with_history_path(url.clone(), &history_path).unwrap();
+        let graph_id = remote
+            .execute(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let a = create_node(&mut remote, graph_id, "a").await;
+        let client_id = remote.history().client_id();
+        let next_id = remote.history_buf().next_id();
+
+        // the next run is the same client, so its undo is still on the server
+        let mut remote = RemoteStore::with_history_path(url, &history_path).unwrap();
+        assert_eq!(remote.history().client_id(), client_id);
+        assert_eq!(remote.history_buf().next_id(), next_id);
+        remote.execute(Action::Undo).await.unwrap();
+        assert!(remote.read_node(a).await.is_err());
+    }
+
+    // the building blocks that read answer like an embedded store, the ones that write would
+    // skip the history, undo and broadcasts of the server and are refused
+    #[tokio::test]
+    async fn test_building_blocks() {
+        let mut remote = RemoteStore::new(spawn_server());
+        let mut embedded = MemoryDB::default();
+        let (graph_id, a, b) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let edge = Edge {
+            id: Uuid::from_u128(4),
+            from: a,
+            to: b,
+        };
+
+        for store in [&mut remote as &mut dyn Datastore, &mut embedded] {
+            for action in actions(graph_id, a, b, edge).into_iter().take(4) {
+                store.execute(action).await.unwrap();
+            }
+        }
+        assert_eq!(remote.read_state_id(graph_id).await.unwrap(), 3);
+        let hash = embedded.read_graph_hash(graph_id).await.unwrap();
+        assert_eq!(remote.read_graph_hash(graph_id).await.unwrap(), hash);
+        assert_eq!(
+            remote.read_edge_properties(edge).await.unwrap(),
+            props(json!({ "weight": 1 }))
+        );
+
+        assert!(matches!(
+            remote.set_state_id(graph_id, 5).await,
+            Err(Error::Unimplemented)
+        ));
+        assert!(matches!(
+            remote
+                .create_node_with_id(Uuid::from_u128(5), (graph_id, Properties::new()))
+                .await,
+            Err(Error::Unimplemented)
+        ));
+        assert!(matches!(
+            remote.delete_node(a, graph_id).await,
+            Err(Error::Unimplemented)
+        ));
+        assert_eq!(remote.read_state_id(graph_id).await.unwrap(), 3);
+        assert_eq!(remote.read_graph_hash(graph_id).await.unwrap(), hash);
+    }
+}
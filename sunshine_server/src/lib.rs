@@ -0,0 +1,379 @@
+use axum::extract::{FromRef, Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use uuid::Uuid;
+
+use sunshine_core::error::Error;
+use sunshine_core::msg::{
+    Action, ActionId, GraphId, NodeId, Properties, QueryKind, Reply, Subgraph,
+};
+use sunshine_core::store::Datastore;
+
+pub mod client;
+pub mod live;
+
+use live::{LiveEvent, LiveUpdate};
+
+pub type SharedStore = Arc<Mutex<Box<dyn Datastore>>>;
+
+// updates that aren't picked up in time are dropped for the lagging subscriber
+const LIVE_CAPACITY: usize = 1024;
+
+// the undo and redo buffers of each client, only touched while the store is locked
+type UndoStacks = Arc<std::sync::Mutex<HashMap<Uuid, (Vec<Action>, Vec<Action>)>>>;
+
+#[derive(Clone)]
+pub struct ServerState {
+    store: SharedStore,
+    updates: broadcast::Sender<LiveEvent>,
+    undo_stacks: UndoStacks,
+}
+
+impl FromRef<ServerState> for SharedStore {
+    fn from_ref(state: &ServerState) -> SharedStore {
+        state.store.clone()
+    }
+}
+
+// POST /action                   an Action as json, answers with the Reply
+// GET  /graphs                   the graphs with their properties
+// GET  /graphs/:graph_id/export  the nodes and edges of a graph as a Subgraph
+// GET  /graphs/:graph_id/live    websocket of the mutations applied to the graph, see live
+//
+// for the client Datastore:
+// POST /action/:client_id/:seq   an Action with its id, answered with the first reply if it
+//                                was already applied, undo and redo only see the client's own
+//
+// actions without an id share the undo and redo of the server
+pub fn router(store: Box<dyn Datastore>) -> Router {
+    Router::new()
+        .route("/action", post(execute))
+        .route("/action/:client_id/:seq", post(execute_with_id))
+        .route("/graphs", get(list_graphs))
+        .route("/graphs/:graph_id/export", get(export_graph))
+        .route("/graphs/:graph_id/live", get(live::subscribe))
+        .with_state(ServerState {
+            store: Arc::new(Mutex::new(store)),
+            updates: broadcast::channel(LIVE_CAPACITY).0,
+            undo_stacks: UndoStacks::default(),
+        })
+}
+
+pub async fn serve(addr: SocketAddr, store: Box<dyn Datastore>) -> hyper::Result<()> {
+    axum::Server::bind(&addr)
+        .serve(router(store).into_make_service())
+        .await
+}
+
+// errors are answered as { "error": message, "kind": RemoteError }, without a kind
+// for errors the client can't do anything about but report
+pub struct ApiError(pub Error);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            Error::GraphNotFound
+            | Error::NodeNotFound
+            | Error::EdgeNotFound
+            | Error::VersionNotFound
+            | Error::StateNotFound(_) => StatusCode::NOT_FOUND,
+            Error::UndoBufferEmpty
+            | Error::RedoBufferEmpty
+            | Error::MissingTemplateParameter(_)
+            | Error::VersionAlreadyExists(_)
+            | Error::ActionForgotten(..) => StatusCode::BAD_REQUEST,
+            Error::TransactionConflict | Error::NodeAlreadyExists(_) => StatusCode::CONFLICT,
+            Error::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Error::RemoteUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let body = json!({
+            "error": self.0.to_string(),
+            "kind": RemoteError::from_error(&self.0),
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+// the errors of the store a RemoteStore gives back as they are, so callers can match on
+// them like on an embedded store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RemoteError {
+    GraphNotFound,
+    NodeNotFound,
+    EdgeNotFound,
+    NodeAlreadyExists(Uuid),
+    UndoBufferEmpty,
+    RedoBufferEmpty,
+    MissingTemplateParameter(String),
+    VersionNotFound,
+    VersionAlreadyExists(String),
+    StateNotFound(u64),
+    UnresolvedConflicts(usize),
+    TransactionConflict,
+    Unimplemented,
+    RemoteUnavailable(String),
+    ActionForgotten(Uuid, u64),
+}
+
+impl RemoteError {
+    pub fn from_error(error: &Error) -> Option<RemoteError> {
+        let remote_error = match error {
+            Error::GraphNotFound => RemoteError::GraphNotFound,
+            Error::NodeNotFound => RemoteError::NodeNotFound,
+            Error::EdgeNotFound => RemoteError::EdgeNotFound,
+            Error::NodeAlreadyExists(node_id) => RemoteError::NodeAlreadyExists(*node_id),
+            Error::UndoBufferEmpty => RemoteError::UndoBufferEmpty,
+            Error::RedoBufferEmpty => RemoteError::RedoBufferEmpty,
+            Error::MissingTemplateParameter(name) => {
+                RemoteError::MissingTemplateParameter(name.clone())
+            }
+            Error::VersionNotFound => RemoteError::VersionNotFound,
+            Error::VersionAlreadyExists(name) => RemoteError::VersionAlreadyExists(name.clone()),
+            Error::StateNotFound(state_id) => RemoteError::StateNotFound(*state_id),
+            Error::UnresolvedConflicts(count) => RemoteError::UnresolvedConflicts(*count),
+            Error::TransactionConflict => RemoteError::TransactionConflict,
+            Error::Unimplemented => RemoteError::Unimplemented,
+            Error::RemoteUnavailable(message) => RemoteError::RemoteUnavailable(message.clone()),
+            Error::ActionForgotten(client_id, seq) => {
+                RemoteError::ActionForgotten(*client_id, *seq)
+            }
+            _ => return None,
+        };
+
+        Some(remote_error)
+    }
+}
+
+impl From<RemoteError> for Error {
+    fn from(remote_error: RemoteError) -> Error {
+        match remote_error {
+            RemoteError::GraphNotFound => Error::GraphNotFound,
+            RemoteError::NodeNotFound => Error::NodeNotFound,
+            RemoteError::EdgeNotFound => Error::EdgeNotFound,
+            RemoteError::NodeAlreadyExists(node_id) => Error::NodeAlreadyExists(node_id),
+            RemoteError::UndoBufferEmpty => Error::UndoBufferEmpty,
+            RemoteError::RedoBufferEmpty => Error::RedoBufferEmpty,
+            RemoteError::MissingTemplateParameter(name) => Error::MissingTemplateParameter(name),
+            RemoteError::VersionNotFound => Error::VersionNotFound,
+            RemoteError::VersionAlreadyExists(name) => Error::VersionAlreadyExists(name),
+            RemoteError::StateNotFound(state_id) => Error::StateNotFound(state_id),
+            RemoteError::UnresolvedConflicts(count) => Error::UnresolvedConflicts(count),
+            RemoteError::TransactionConflict => Error::TransactionConflict,
+            RemoteError::Unimplemented => Error::Unimplemented,
+            RemoteError::RemoteUnavailable(message) => Error::RemoteUnavailable(message),
+            RemoteError::ActionForgotten(client_id, seq) => Error::ActionForgotten(client_id, seq),
+        }
+    }
+}
+
+// executes the action with the undo and redo of its client and broadcasts the mutations
+// it applied, as recorded in the history so generated ids and the effect of undo and redo
+// are spelled out for the subscribers, and the graphs it deleted
+async fn execute_and_broadcast(
+    store: &mut dyn Datastore,
+    state: &ServerState,
+    action_id: ActionId,
+    action: Action,
+) -> sunshine_core::error::Result<Reply> {
+    let client_id = action_id.client_id;
+    let (undo, redo) = state
+        .undo_stacks
+        .lock()
+        .unwrap()
+        .remove(&client_id)
+        .unwrap_or_default();
+    *store.undo_buf() = undo;
+    *store.redo_buf() = redo;
+
+    let seen = store.history_buf().len();
+    let result = store.execute_with_id(action_id, action).await;
+
+    let stacks = (
+        std::mem::take(store.undo_buf()),
+        std::mem::take(store.redo_buf()),
+    );
+    state.undo_stacks.lock().unwrap().insert(client_id, stacks);
+    let reply = result?;
+
+    let applied: Vec<(ActionId, Action)> = store.history_buf().since(seen).to_vec();
+
+    for (action_id, action) in applied {
+        let event = match action {
+            Action::Mutate(graph_id, kind) => match store.read_state_id(graph_id).await {
+                Ok(state_id) => LiveEvent::Applied(LiveUpdate {
+                    graph_id,
+                    state_id,
+                    action_id,
+                    kind,
+                }),
+                // the action went through, only its subscribers miss it
+                Err(error) => {
+                    log::warn!(
+                        "not broadcasting {}/{}: {}",
+                        action_id.client_id,
+                        action_id.seq,
+                        error
+                    );
+                    continue;
+                }
+            },
+            Action::DeleteGraph(graph_id) => LiveEvent::Deleted(graph_id),
+            _ => continue,
+        };
+        // nobody listening isn't an error
+        let _ = state.updates.send(event);
+    }
+
+    Ok(reply)
+}
+
+async fn execute(
+    State(state): State<ServerState>,
+    Json(action): Json<Action>,
+) -> Result<Json<Reply>, ApiError> {
+    let mut store = state.store.lock().await;
+    let action_id = store.history_buf().next_id();
+    let reply = execute_and_broadcast(store.as_mut(), &state, action_id, action)
+        .await
+        .map_err(ApiError)?;
+    Ok(Json(reply))
+}
+
+async fn execute_with_id(
+    State(state): State<ServerState>,
+    Path((client_id, seq)): Path<(Uuid, u64)>,
+    Json(action): Json<Action>,
+) -> Result<Json<Reply>, ApiError> {
+    let action_id = ActionId { client_id, seq };
+    let mut store = state.store.lock().await;
+    let reply = execute_and_broadcast(store.as_mut(), &state, action_id, action)
+        .await
+        .map_err(ApiError)?;
+    Ok(Json(reply))
+}
+
+async fn list_graphs(
+    State(store): State<SharedStore>,
+) -> Result<Json<Vec<(NodeId, Properties)>>, ApiError> {
+    match store
+        .lock()
+        .await
+        .execute_read_only(QueryKind::ListGraphs)
+        .await
+        .map_err(ApiError)?
+    {
+        Reply::NodeList(graphs) => Ok(Json(graphs)),
+        _ => unreachable!(),
+    }
+}
+
+async fn export_graph(
+    State(store): State<SharedStore>,
+    Path(graph_id): Path<GraphId>,
+) -> Result<Json<Subgraph>, ApiError> {
+    let (_, snapshot) = store
+        .lock()
+        .await
+        .read_graph_snapshot(graph_id)
+        .await
+        .map_err(ApiError)?;
+    Ok(Json(snapshot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    pub(crate) use sunshine_core::test_utils::props;
+    use sunshine_indra::store::MemoryDB;
+
+    // serves a fresh store on a free localhost port
+    pub(crate) fn spawn_server() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router(Box::new(MemoryDB::default())).into_make_service());
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    async fn post(client: &reqwest::Client, url: &str, action: Action) -> reqwest::Response {
+        client
+            .post(format!("{}/action", url))
+            .json(&action)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_execute_list_and_export() {
+        let url = spawn_server();
+        let client = reqwest::Client::new();
+
+        let reply: Reply = post(
+            &client,
+            &url,
+            Action::CreateGraph(props(json!({ "name": "graph" }))),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        let graph_id = reply.as_id().unwrap();
+
+        // what a script would send without linking the crate
+        let node_id = client
+            .post(format!("{}/action", url))
+            .json(&json!({ "Mutate": [graph_id, { "CreateNode": { "name": "a" } }] }))
+            .send()
+            .await
+            .unwrap()
+            .json::<Reply>()
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        let graphs: Vec<(NodeId, Properties)> = client
+            .get(format!("{}/graphs", url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(graphs.len(), 1);
+        assert_eq!(graphs[0].1["name"], json!("graph"));
+
+        let export: Subgraph = client
+            .get(format!("{}/graphs/{}/export", url, graph_id))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(export.nodes.len(), 1);
+        assert_eq!(export.nodes[0].0, node_id);
+
+        let undone = post(&client, &url, Action::Undo).await;
+        assert!(undone.status().is_success());
+        post(&client, &url, Action::Redo).await;
+
+        let response = post(&client, &url, Action::Redo).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("redo"));
+    }
+}
@@ -0,0 +1,297 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use sunshine_core::msg::{Action, ActionId, GraphId, MutateKind};
+
+use crate::{execute_and_broadcast, ServerState};
+
+// a mutation applied to a graph, whoever sent it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveUpdate {
+    pub graph_id: GraphId,
+    pub state_id: u64, // of the graph once the mutation is applied
+    pub action_id: ActionId,
+    pub kind: MutateKind,
+}
+
+// sent by a subscriber, applied only if the graph is still at `state_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveRequest {
+    pub state_id: u64,
+    pub kind: MutateKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LiveMessage {
+    Applied(LiveUpdate),
+    // the graph moved on or the mutation failed, the subscriber should catch up and retry
+    Rejected { state_id: u64, error: String },
+    // the last message of the session, the graph is gone
+    Deleted(GraphId),
+}
+
+// what the server broadcasts, each session picks what concerns its graph
+#[derive(Debug, Clone)]
+pub(crate) enum LiveEvent {
+    Applied(LiveUpdate),
+    Deleted(GraphId),
+}
+
+pub(crate) async fn subscribe(
+    ws: WebSocketUpgrade,
+    State(state): State<ServerState>,
+    Path(graph_id): Path<GraphId>,
+) -> Response {
+    ws.on_upgrade(move |socket| session(socket, state, graph_id))
+}
+
+// the update of an accepted request reaches its sender through the broadcast like everyone else's,
+// a subscriber that lags behind the broadcast is disconnected and has to reload the graph,
+// each session is a client of its own, with its own ids and undo
+async fn session(mut socket: WebSocket, state: ServerState, graph_id: GraphId) {
+    let mut updates = state.updates.subscribe();
+    let client_id = Uuid::new_v4();
+    let mut seq = 0;
+
+    loop {
+        let (message, last) = tokio::select! {
+            update = updates.recv() => match update {
+                Ok(LiveEvent::Applied(update)) if update.graph_id == graph_id => {
+                    (LiveMessage::Applied(update), false)
+                }
+                Ok(LiveEvent::Deleted(deleted_id)) if deleted_id == graph_id => {
+                    (LiveMessage::Deleted(graph_id), true)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+            },
+            request = socket.recv() => match request {
+                Some(Ok(Message::Text(text))) => {
+                    seq += 1;
+                    let action_id = ActionId { client_id, seq };
+                    match apply(&state, graph_id, action_id, &text).await {
+                        Ok(()) => continue,
+                        Err(rejected) => (rejected, false),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        let text = serde_json::to_string(&message).unwrap();
+        if socket.send(Message::Text(text)).await.is_err() || last {
+            break;
+        }
+    }
+
+    state.undo_stacks.lock().unwrap().remove(&client_id);
+}
+
+async fn apply(
+    state: &ServerState,
+    graph_id: GraphId,
+    action_id: ActionId,
+    text: &str,
+) -> Result<(), LiveMessage> {
+    let mut store = state.store.lock().await;
+    let current_state_id =
+        store
+            .read_state_id(graph_id)
+            .await
+            .map_err(|error| LiveMessage::Rejected {
+                state_id: 0,
+                error: error.to_string(),
+            })?;
+
+    let rejected = |error: String| LiveMessage::Rejected {
+        state_id: current_state_id,
+        error,
+    };
+
+    let request: LiveRequest =
+        serde_json::from_str(text).map_err(|error| rejected(error.to_string()))?;
+
+    if request.state_id != current_state_id {
+        return Err(rejected(format!(
+            "the graph is at state {}, not {}",
+            current_state_id, request.state_id
+        )));
+    }
+
+    execute_and_broadcast(
+        store.as_mut(),
+        state,
+        action_id,
+        Action::Mutate(graph_id, request.kind),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|error| rejected(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{props, spawn_server};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use sunshine_core::error::Error;
+    use sunshine_core::msg::{QueryKind, Reply};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn connect(url: &str, graph_id: GraphId) -> Socket {
+        let url = format!("{}/graphs/{}/live", url.replace("http", "ws"), graph_id);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    async fn send(socket: &mut Socket, state_id: u64, kind: MutateKind) {
+        let request = serde_json::to_string(&LiveRequest { state_id, kind }).unwrap();
+        socket.send(WsMessage::Text(request)).await.unwrap();
+    }
+
+    async fn next(socket: &mut Socket) -> LiveMessage {
+        match socket.next().await.unwrap().unwrap() {
+            WsMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_live_updates() {
+        let url = spawn_server();
+        let client = reqwest::Client::new();
+
+        let graph_id = client
+            .post(format!("{}/action", url))
+            .json(&Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .send()
+            .await
+            .unwrap()
+            .json::<Reply>()
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+
+        let mut alice = connect(&url, graph_id).await;
+        let mut bob = connect(&url, graph_id).await;
+
+        send(
+            &mut alice,
+            0,
+            MutateKind::CreateNode(props(json!({ "name": "a" }))),
+        )
+        .await;
+        for socket in [&mut alice, &mut bob] {
+            match next(socket).await {
+                LiveMessage::Applied(update) => {
+                    assert_eq!(update.state_id, 1);
+                    assert!(matches!(update.kind, MutateKind::CreateNodeWithId(_)));
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+
+        // bob hadn't seen alice's change yet
+        send(
+            &mut bob,
+            0,
+            MutateKind::CreateNode(props(json!({ "name": "b" }))),
+        )
+        .await;
+        assert!(matches!(
+            next(&mut bob).await,
+            LiveMessage::Rejected { state_id: 1, .. }
+        ));
+
+        // mutations through the http api are broadcast as well, undo as what it did,
+        // the http api has an undo of its own, which takes its node and not alice's
+        let post = |action: Action| client.post(format!("{}/action", url)).json(&action).send();
+        let c = post(Action::Mutate(
+            graph_id,
+            MutateKind::CreateNode(props(json!({ "name": "c" }))),
+        ))
+        .await
+        .unwrap()
+        .json::<Reply>()
+        .await
+        .unwrap()
+        .as_id()
+        .unwrap();
+        post(Action::Undo).await.unwrap();
+        assert!(
+            matches!(next(&mut bob).await, LiveMessage::Applied(update) if update.state_id == 2)
+        );
+        match next(&mut bob).await {
+            LiveMessage::Applied(update) => {
+                assert_eq!(update.state_id, 3);
+                assert!(matches!(update.kind, MutateKind::DeleteNode(node_id) if node_id == c));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        // a deleted graph ends the session
+        post(Action::DeleteGraph(graph_id)).await.unwrap();
+        assert!(matches!(
+            next(&mut bob).await,
+            LiveMessage::Deleted(id) if id == graph_id
+        ));
+        assert!(!matches!(bob.next().await, Some(Ok(WsMessage::Text(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_to_node() {
+        let url = spawn_server();
+        let client = reqwest::Client::new();
+        let post = |action: Action| client.post(format!("{}/action", url)).json(&action).send();
+
+        let graph_id = post(Action::CreateGraph(props(json!({ "name": "graph" }))))
+            .await
+            .unwrap()
+            .json::<Reply>()
+            .await
+            .unwrap()
+            .as_id()
+            .unwrap();
+        let node_id = post(Action::Mutate(
+            graph_id,
+            MutateKind::CreateNode(props(json!({ "name": "a" }))),
+        ))
+        .await
+        .unwrap()
+        .json::<Reply>()
+        .await
+        .unwrap()
+        .as_id()
+        .unwrap();
+
+        // a node that isn't a graph root has no state id to check against
+        let mut socket = connect(&url, node_id).await;
+        send(
+            &mut socket,
+            0,
+            MutateKind::CreateNode(props(json!({ "name": "b" }))),
+        )
+        .await;
+        assert!(matches!(
+            next(&mut socket).await,
+            LiveMessage::Rejected { state_id: 0, error } if error == Error::GraphNotFound.to_string()
+        ));
+
+        // the store is still usable
+        assert!(post(Action::Query(QueryKind::ReadGraph(graph_id)))
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+    }
+}
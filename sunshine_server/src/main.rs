@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use sunshine_indra::store::{DbConfig, DB};
+
+const USAGE: &str = "usage: sunshine_server [db_path] [address]";
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    if let Err(error) = try_main().await {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn try_main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let db_path = args.next().unwrap_or_else(|| "indra_datastore".into());
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".into());
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|error| format!("invalid address {}: {}\n{}", addr, error, USAGE))?;
+
+    let store = DB::new(&DbConfig {
+        db_path: db_path.clone(),
+        ..Default::default()
+    })
+    .map_err(|error| format!("cannot open {}: {}", db_path, error))?;
+
+    log::info!("listening on {}", addr);
+    sunshine_server::serve(addr, Box::new(store)).await?;
+
+    Ok(())
+}